@@ -1,16 +1,43 @@
+use super::amount::{deserialize_decimal, Amount};
+use super::options::Options;
 use super::store::Store;
-use super::transaction::{Transaction, TransactionId};
+use super::transaction::{DisputeState, Transaction, TransactionId, TransactionType};
 use anyhow::Context;
 use rust_decimal::prelude::*;
 use rust_decimal_macros::dec;
 use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
 use thiserror::Error;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
 pub struct Client(u16);
 
+impl From<u16> for Client {
+    fn from(id: u16) -> Self {
+        Self(id)
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Who a rejection is down to, for routing its log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The client asked for something their account doesn't allow
+    ClientFault,
+    /// The partner sent a row that is malformed or makes no sense
+    PartnerError,
+    /// The engine itself failed or hit a limit
+    SystemError,
+}
+
 #[derive(Error, Debug)]
-pub(crate) enum AccountError {
+#[non_exhaustive]
+pub enum AccountError {
     #[error(
         "You cannot withdraw {requested}. It is less than {available} available in your account"
     )]
@@ -18,47 +45,212 @@ pub(crate) enum AccountError {
         requested: Decimal,
         available: Decimal,
     },
-    #[error("Action forbidden, account- (0) is locked")]
+    #[error("You cannot withdraw {requested}. Only {available} is available because {held} is held for dispute")]
+    InsufficientFundWhileHeld {
+        requested: Decimal,
+        available: Decimal,
+        held: Decimal,
+    },
+
+    #[error("Action forbidden, account {0} is locked")]
     AccountLocked(Client),
 
+    #[error("Action forbidden, account {0} is closed")]
+    AccountClosed(Client),
+
     #[error("Invalid input")]
     InvalidInput(#[from] anyhow::Error),
 
-    #[error("Erroneous dispute: Transaction id (0)")]
+    #[error("Erroneous dispute: Transaction id {0} does not exist")]
     ErroneousDispute(TransactionId),
 
-    #[error("Erroneous resolve: Transaction id (0)")]
+    #[error("Erroneous resolve: Transaction id {0} does not exist")]
     ErroneousResolve(TransactionId),
 
-    #[error("Erroneous charge back: Transaction id (0)")]
+    #[error("Erroneous charge back: Transaction id {0} does not exist")]
     ErroneousChargeback(TransactionId),
+
+    #[error("Transaction {transaction_id} does not belong to client {client}")]
+    ForeignTransaction {
+        transaction_id: TransactionId,
+        client: Client,
+    },
+
+    #[error("Illegal dispute transition: cannot {action} transaction {transaction_id} while its dispute state is {state:?}")]
+    IllegalDisputeTransition {
+        action: &'static str,
+        transaction_id: TransactionId,
+        state: DisputeState,
+    },
+
+    #[error("Transaction {0} cannot be corrected, it was refused or disputed, or the correction's client or type differs")]
+    UncorrectableTransaction(TransactionId),
+
+    #[error("Cannot release {requested} held for transaction {transaction_id}, the account only holds {held}")]
+    HeldUnderflow {
+        transaction_id: TransactionId,
+        requested: Decimal,
+        held: Decimal,
+    },
+
+    #[error("Cannot charge back transaction {transaction_id}: {disputed} is disputed but the account only holds {held}")]
+    ChargebackExceedsHeld {
+        transaction_id: TransactionId,
+        disputed: Decimal,
+        held: Decimal,
+    },
+
+    #[error("Transaction {0} is a fee, which cannot be disputed")]
+    UndisputableTransaction(TransactionId),
+
+    #[error("Transaction {0} is a withdrawal that was refused, so there is nothing to reverse")]
+    UnappliedWithdrawal(TransactionId),
+
+    #[error("Cannot dispute {requested} of transaction {transaction_id}, only {undisputed} of it is undisputed")]
+    DisputeExceedsAmount {
+        transaction_id: TransactionId,
+        requested: Decimal,
+        undisputed: Decimal,
+    },
+}
+
+impl AccountError {
+    /// The transaction the error is about, if it is about one
+    pub fn transaction_id(&self) -> Option<TransactionId> {
+        match self {
+            AccountError::ErroneousDispute(transaction_id)
+            | AccountError::ErroneousResolve(transaction_id)
+            | AccountError::ErroneousChargeback(transaction_id)
+            | AccountError::UncorrectableTransaction(transaction_id)
+            | AccountError::UndisputableTransaction(transaction_id)
+            | AccountError::UnappliedWithdrawal(transaction_id)
+            | AccountError::ForeignTransaction { transaction_id, .. }
+            | AccountError::IllegalDisputeTransition { transaction_id, .. }
+            | AccountError::DisputeExceedsAmount { transaction_id, .. }
+            | AccountError::ChargebackExceedsHeld { transaction_id, .. }
+            | AccountError::HeldUnderflow { transaction_id, .. } => Some(*transaction_id),
+            _ => None,
+        }
+    }
+
+    /// The client whose account the error is about, if the error names one
+    pub fn client(&self) -> Option<Client> {
+        match self {
+            AccountError::AccountLocked(client)
+            | AccountError::AccountClosed(client)
+            | AccountError::ForeignTransaction { client, .. } => Some(*client),
+            _ => None,
+        }
+    }
+
+    /// Who the rejection is down to
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            AccountError::InsufficientFund { .. }
+            | AccountError::InsufficientFundWhileHeld { .. }
+            | AccountError::AccountLocked(_)
+            | AccountError::AccountClosed(_) => ErrorCategory::ClientFault,
+            AccountError::ErroneousDispute(_)
+            | AccountError::ErroneousResolve(_)
+            | AccountError::ErroneousChargeback(_)
+            | AccountError::ForeignTransaction { .. }
+            | AccountError::IllegalDisputeTransition { .. }
+            | AccountError::UncorrectableTransaction(_)
+            | AccountError::UndisputableTransaction(_)
+            | AccountError::UnappliedWithdrawal(_)
+            | AccountError::DisputeExceedsAmount { .. }
+            | AccountError::ChargebackExceedsHeld { .. } => ErrorCategory::PartnerError,
+            AccountError::InvalidInput(_) | AccountError::HeldUnderflow { .. } => {
+                ErrorCategory::SystemError
+            }
+        }
+    }
 }
 
 type AccountResult<T> = anyhow::Result<T, AccountError>;
 
 /// Client Account
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub struct Account {
     client: Client,
     /// The total funds that are available for trading, staking, withdrawal, etc.
     /// This should be equal to the total - held amounts
-    #[serde(rename = "available", serialize_with = "round_serialize")]
+    #[serde(
+        rename = "available",
+        serialize_with = "round_serialize",
+        deserialize_with = "deserialize_decimal"
+    )]
     available_amount: Decimal,
 
     /// The total funds that are held for dispute. This should be equal to total - available amounts
-    #[serde(rename = "held", serialize_with = "round_serialize")]
+    #[serde(
+        rename = "held",
+        serialize_with = "round_serialize",
+        deserialize_with = "deserialize_decimal"
+    )]
     held_amount: Decimal,
 
     /// The total funds that are available or held. This should be equal to available + held
-    #[serde(rename = "total", serialize_with = "round_serialize")]
+    #[serde(
+        rename = "total",
+        serialize_with = "round_serialize",
+        deserialize_with = "deserialize_decimal"
+    )]
     total_amount: Decimal,
 
     /// Whether the account is locked. An account is locked if a charge back occurs
     #[serde(rename = "locked")]
     is_locked: bool,
+
+    /// The transaction whose charge back locked the account. Not known for an account that was
+    /// already locked in a seed
+    #[serde(skip)]
+    locked_by: Option<TransactionId>,
+
+    /// Whether a withdrawal drained the account and closed it, see
+    /// `Options::close_zero_accounts`. Unlike a lock, nothing is owed or held
+    #[serde(skip)]
+    is_closed: bool,
+}
+
+/// An account's state at one point in time, owned, so it can be kept or handed to another
+/// thread while the account carries on changing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountSnapshot {
+    pub client: Client,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl From<&Account> for AccountSnapshot {
+    fn from(account: &Account) -> Self {
+        Self {
+            client: account.client,
+            available: account.available_amount,
+            held: account.held_amount,
+            total: account.total_amount,
+            locked: account.is_locked,
+        }
+    }
+}
+
+/// What a dispute, resolve or charge back did to an account, e.g. for a dispute dashboard to
+/// show. See `Account::apply_dispute`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisputeOutcome {
+    pub held_delta: Decimal,
+    pub available_delta: Decimal,
+
+    /// Only a charge back, or a dispute or resolve of a withdrawal, changes the total
+    pub total_delta: Decimal,
+
+    /// Where the disputed transaction is in the dispute process afterwards
+    pub new_state: DisputeState,
 }
 
-fn round_serialize<S>(amount: &Decimal, s: S) -> Result<S::Ok, S::Error>
+pub(crate) fn round_serialize<S>(amount: &Decimal, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
@@ -75,27 +267,259 @@ impl Account {
             held_amount: dec!(0),
             total_amount: dec!(0),
             is_locked: false,
+            locked_by: None,
+            is_closed: false,
+        }
+    }
+
+    /// The account as a ledger recorded it, see `replay_ledger`
+    pub(crate) fn replayed(
+        client: Client,
+        available_amount: Decimal,
+        held_amount: Decimal,
+        total_amount: Decimal,
+        locked_by: Option<TransactionId>,
+    ) -> Self {
+        Self {
+            client,
+            available_amount,
+            held_amount,
+            total_amount,
+            is_locked: locked_by.is_some(),
+            locked_by,
+            is_closed: false,
         }
     }
 
-    pub(crate) fn find_or_create_by_client(client: Client, store: &mut Store) -> Account {
-        *store
+    /// Get the account's client.
+    pub fn client(&self) -> Client {
+        self.client
+    }
+
+    /// The same account under another client id
+    pub(crate) fn with_client(self, client: Client) -> Self {
+        Self { client, ..self }
+    }
+
+    /// Get the account's available amount.
+    pub fn available(&self) -> Decimal {
+        self.available_amount
+    }
+
+    /// Get the account's held amount.
+    pub fn held(&self) -> Decimal {
+        self.held_amount
+    }
+
+    /// Get the account's total amount.
+    pub fn total(&self) -> Decimal {
+        self.total_amount
+    }
+
+    /// Get whether the account is locked.
+    pub fn is_locked(&self) -> bool {
+        self.is_locked
+    }
+
+    /// Whether a withdrawal drained the account and closed it
+    pub fn is_closed(&self) -> bool {
+        self.is_closed
+    }
+
+    /// The transaction whose charge back locked the account, if known
+    pub fn locked_by(&self) -> Option<TransactionId> {
+        self.locked_by
+    }
+
+    /// Disputes the client's transaction `transaction_id`, holding all of it that isn't held yet,
+    /// and says what that did. The account must be the client's as `store` has it, see
+    /// `Store::account`. Applied directly, without the checks `Options` configure for a feed,
+    /// e.g. the redispute policy. On error the account and store are left as they were.
+    pub fn apply_dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        store: &mut Store,
+    ) -> AccountResult<DisputeOutcome> {
+        self.apply_with_outcome(transaction_id, store, |account, store| {
+            account.dispute(transaction_id, None, None, store)
+        })
+    }
+
+    /// Resolves the dispute of the client's transaction `transaction_id` and says what that did.
+    /// See `apply_dispute`
+    pub fn apply_resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        store: &mut Store,
+    ) -> AccountResult<DisputeOutcome> {
+        self.apply_with_outcome(transaction_id, store, |account, store| {
+            account.resolve(transaction_id, store)
+        })
+    }
+
+    /// Charges back the dispute of the client's transaction `transaction_id`, locking the
+    /// account, and says what that did. See `apply_dispute`
+    pub fn apply_chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        store: &mut Store,
+    ) -> AccountResult<DisputeOutcome> {
+        self.apply_with_outcome(transaction_id, store, |account, store| {
+            account.charge_back(transaction_id, store)
+        })
+    }
+
+    fn apply_with_outcome(
+        &mut self,
+        transaction_id: TransactionId,
+        store: &mut Store,
+        action: impl FnOnce(Self, &mut Store) -> AccountResult<Self>,
+    ) -> AccountResult<DisputeOutcome> {
+        let before = *self;
+        *self = action(before, store)?;
+
+        Ok(DisputeOutcome {
+            held_delta: self.held_amount - before.held_amount,
+            available_delta: self.available_amount - before.available_amount,
+            total_delta: self.total_amount - before.total_amount,
+            new_state: store
+                .transactions
+                .get(&transaction_id)
+                .map_or(DisputeState::Active, Transaction::get_dispute_state),
+        })
+    }
+
+    /// The client's account, or a new one holding `options.seed_balance`. A new account is only
+    /// stored once a transaction is applied to it, so a client whose transactions were all
+    /// rejected has no account.
+    pub(crate) fn find_or_new_by_client(
+        client: Client,
+        store: &Store,
+        options: &Options,
+    ) -> Account {
+        store
             .accounts
-            .entry(client)
-            .or_insert_with(|| Account::new(client))
+            .get(&client)
+            .copied()
+            .unwrap_or_else(|| Account {
+                available_amount: options.seed_balance,
+                total_amount: options.seed_balance,
+                ..Account::new(client)
+            })
     }
 
     pub(crate) fn update(self, store: &mut Store) -> AccountResult<Self> {
-        let account = Self::find_or_create_by_client(self.client, store);
-        if account.is_locked {
-            return Err(AccountError::AccountLocked(self.client));
+        match store.accounts.get(&self.client) {
+            Some(account) if account.is_locked => {
+                return Err(AccountError::AccountLocked(self.client))
+            }
+            Some(account) if account.is_closed => {
+                return Err(AccountError::AccountClosed(self.client))
+            }
+            _ => {}
         }
 
         store.accounts.insert(self.client, self);
         Ok(self)
     }
 
-    pub(crate) fn deposit(self, amount: Decimal, store: &mut Store) -> AccountResult<Self> {
+    /// Applies `tx` to this account and stores the result. This is the only way balances change:
+    /// deposits and withdrawals move available funds, and disputes, resolves and charge backs
+    /// move the referenced transaction's amount through the dispute state machine. On error the
+    /// account and store are left as they were.
+    pub(crate) fn apply_transaction(
+        &mut self,
+        tx: &Transaction,
+        store: &mut Store,
+    ) -> AccountResult<()> {
+        use TransactionType::*;
+
+        let amount = || tx.get_amount().with_context(|| "Unable to get amount");
+        let funds = || amount().and_then(|amount| Ok(Amount::new(amount)?));
+
+        *self = match tx.transaction_type() {
+            Deposit => self.deposit(funds()?, store)?,
+            Withdrawal => self.withdraw(funds()?, store)?,
+            PercentageFee => self.charge_fee(funds()?, store)?,
+            Dispute => self.dispute(
+                tx.transaction_id(),
+                tx.get_amount(),
+                tx.get_timestamp(),
+                store,
+            )?,
+            Resolve => self.resolve(tx.transaction_id(), store)?,
+            Chargeback => self.charge_back(tx.transaction_id(), store)?,
+        };
+        Ok(())
+    }
+
+    /// Rounds `available` and `held` to `scale` decimal places, with `total` their sum so it
+    /// still adds up, and stores the result. See `Options::working_scale`
+    pub(crate) fn round_to_scale(&mut self, scale: u32, store: &mut Store) {
+        self.available_amount = self.available_amount.round_dp(scale);
+        self.held_amount = self.held_amount.round_dp(scale);
+        self.total_amount = self.available_amount + self.held_amount;
+        store.accounts.insert(self.client, *self);
+    }
+
+    /// Closes the account if it holds nothing at all and isn't locked, so any later transaction
+    /// for the client is rejected, see `Options::close_zero_accounts`
+    pub(crate) fn close_if_drained(&mut self, store: &mut Store) {
+        let drained = self.available_amount.is_zero()
+            && self.held_amount.is_zero()
+            && self.total_amount.is_zero();
+        if drained && !self.is_locked {
+            self.is_closed = true;
+            store.accounts.insert(self.client, *self);
+        }
+    }
+
+    /// Moves the balance by the difference between `original` and `corrected`, a later row with
+    /// the same id. See `DuplicateTxPolicy::Last` for the constraints.
+    pub(crate) fn correct(
+        &mut self,
+        original: &Transaction,
+        corrected: &Transaction,
+        store: &mut Store,
+    ) -> AccountResult<()> {
+        use TransactionType::*;
+
+        if original.client_id() != corrected.client_id()
+            || original.transaction_type() != corrected.transaction_type()
+            || corrected.transaction_type() == PercentageFee
+            || original.get_dispute_state() != DisputeState::Active
+            || !original.get_applied()
+        {
+            return Err(AccountError::UncorrectableTransaction(
+                corrected.transaction_id(),
+            ));
+        }
+
+        let amount = |tx: &Transaction| tx.get_amount().with_context(|| "Unable to get amount");
+        let difference = amount(corrected)? - amount(original)?;
+        let change = match corrected.transaction_type() {
+            Withdrawal => -difference,
+            _ => difference,
+        };
+        if self.available_amount + change < Decimal::ZERO {
+            return Err(AccountError::InsufficientFund {
+                requested: -change,
+                available: self.available_amount,
+            });
+        }
+
+        *self = Self {
+            available_amount: self.available_amount + change,
+            total_amount: self.total_amount + change,
+            ..*self
+        }
+        .update(store)?;
+        Ok(())
+    }
+
+    fn deposit(self, amount: Amount, store: &mut Store) -> AccountResult<Self> {
+        let amount = amount.value();
+
         Self {
             available_amount: self.available_amount + amount,
             total_amount: self.total_amount + amount,
@@ -104,7 +528,38 @@ impl Account {
         .update(store)
     }
 
-    pub(crate) fn withdraw(self, amount: Decimal, store: &mut Store) -> AccountResult<Self> {
+    /// `percent` of the available funds, truncated to `places` decimal places. Nothing is
+    /// charged on negative available funds.
+    pub(crate) fn percentage_fee(&self, percent: Decimal, places: u32) -> Decimal {
+        (self.available_amount.max(Decimal::ZERO) * percent / dec!(100))
+            .round_dp_with_strategy(places, RoundingStrategy::ToZero)
+    }
+
+    /// A fee is only ever a percentage of the available funds, so it never needs checking
+    /// against them
+    fn charge_fee(self, fee: Amount, store: &mut Store) -> AccountResult<Self> {
+        let fee = fee.value();
+
+        Self {
+            available_amount: self.available_amount - fee,
+            total_amount: self.total_amount - fee,
+            ..self
+        }
+        .update(store)
+    }
+
+    /// Withdrawals only ever draw on available funds, never on funds held for dispute. When the
+    /// withdrawal would have been covered but for the held funds, the error says so.
+    fn withdraw(self, amount: Amount, store: &mut Store) -> AccountResult<Self> {
+        let amount = amount.value();
+
+        if self.available_amount < amount && self.total_amount >= amount {
+            return Err(AccountError::InsufficientFundWhileHeld {
+                requested: amount,
+                available: self.available_amount,
+                held: self.held_amount,
+            });
+        }
         if self.available_amount < amount {
             return Err(AccountError::InsufficientFund {
                 requested: amount,
@@ -120,70 +575,192 @@ impl Account {
         .update(store)
     }
 
-    pub(crate) fn dispute(
+    /// Holds `portion` of the transaction, or all of it when the dispute doesn't say how much.
+    /// A transaction already under dispute can have further portions held by disputes that say
+    /// how much, as long as no more than its amount is held in total.
+    ///
+    /// A disputed deposit's funds are held out of those available. A disputed withdrawal is a
+    /// claim that the funds should never have left, so its reversal is held on top of the
+    /// available funds, growing the total, until a resolve drops it or a charge back credits it.
+    /// A refused withdrawal moved nothing, so it can't be disputed.
+    fn dispute(
         self,
         transaction_id: TransactionId,
+        portion: Option<Decimal>,
+        disputed_at: Option<u64>,
         store: &mut Store,
     ) -> AccountResult<Self> {
-        let existing_transaction = Transaction::find_by_id(transaction_id, store);
-        match existing_transaction {
-            Some(tx) => {
-                let amount = tx.get_amount().with_context(|| "Amount does not exist")?;
-                tx.set_is_under_dispute(true);
-
-                Self {
-                    available_amount: self.available_amount - amount,
-                    held_amount: self.held_amount + amount,
-                    ..self
-                }
-                .update(store)
+        let mut tx =
+            self.find_own_transaction(transaction_id, store, AccountError::ErroneousDispute)?;
+        if tx.transaction_type() == TransactionType::PercentageFee {
+            return Err(AccountError::UndisputableTransaction(transaction_id));
+        }
+        if tx.transaction_type() == TransactionType::Withdrawal && !tx.get_applied() {
+            return Err(AccountError::UnappliedWithdrawal(transaction_id));
+        }
+        let amount = tx.get_amount().with_context(|| "Amount does not exist")?;
+        let (state, disputed_at, dispute_seq) = match (tx.get_dispute_state(), portion) {
+            (DisputeState::Disputed, Some(_)) => (
+                DisputeState::Disputed,
+                tx.get_disputed_at(),
+                tx.get_dispute_seq(),
+            ),
+            _ => (
+                Self::transition(&tx, "dispute", DisputeState::dispute)?,
+                disputed_at,
+                store.disputes_raised + 1,
+            ),
+        };
+
+        let undisputed = amount - tx.get_disputed_amount();
+        let portion = portion.unwrap_or(undisputed);
+        if portion > undisputed {
+            return Err(AccountError::DisputeExceedsAmount {
+                transaction_id,
+                requested: portion,
+                undisputed,
+            });
+        }
+
+        let account = if tx.transaction_type() == TransactionType::Withdrawal {
+            Self {
+                held_amount: self.held_amount + portion,
+                total_amount: self.total_amount + portion,
+                ..self
+            }
+        } else {
+            Self {
+                available_amount: self.available_amount - portion,
+                held_amount: self.held_amount + portion,
+                ..self
             }
-            _ => Err(AccountError::ErroneousDispute(transaction_id)),
         }
+        .update(store)?;
+
+        tx.set_dispute_state(state);
+        tx.set_disputed_at(disputed_at);
+        tx.set_dispute_seq(dispute_seq);
+        store.disputes_raised = store.disputes_raised.max(dispute_seq);
+        tx.set_disputed_amount(tx.get_disputed_amount() + portion);
+        tx.update(store);
+        Ok(account)
     }
 
-    pub(crate) fn resolve(
-        self,
-        transaction_id: TransactionId,
-        store: &mut Store,
-    ) -> AccountResult<Self> {
-        let transaction = Transaction::find_by_id(transaction_id, store);
-        match transaction {
-            Some(tx) if tx.get_is_under_dispute() => {
-                let amount = tx.get_amount().with_context(|| "Amount does not exist")?;
-                tx.set_is_under_dispute(false);
-
-                Self {
-                    available_amount: self.available_amount + amount,
-                    held_amount: self.held_amount - amount,
-                    ..self
-                }
-                .update(store)
+    /// Releases what the dispute held, back to where the account was before it: a deposit's
+    /// funds become available again, and a withdrawal's reversal is dropped
+    fn resolve(self, transaction_id: TransactionId, store: &mut Store) -> AccountResult<Self> {
+        let mut tx =
+            self.find_own_transaction(transaction_id, store, AccountError::ErroneousResolve)?;
+        let amount = tx.get_disputed_amount();
+        let state = Self::transition(&tx, "resolve", DisputeState::resolve)?;
+
+        // Held funds can't go negative. If the account holds less than the dispute, the store is
+        // inconsistent, e.g. seeded from a snapshot that doesn't match the transactions, and the
+        // resolve is refused rather than making up the difference
+        if amount > self.held_amount {
+            return Err(AccountError::HeldUnderflow {
+                transaction_id,
+                requested: amount,
+                held: self.held_amount,
+            });
+        }
+
+        let account = if tx.transaction_type() == TransactionType::Withdrawal {
+            Self {
+                held_amount: self.held_amount - amount,
+                total_amount: self.total_amount - amount,
+                ..self
+            }
+        } else {
+            Self {
+                available_amount: self.available_amount + amount,
+                held_amount: self.held_amount - amount,
+                ..self
             }
-            _ => Err(AccountError::ErroneousResolve(transaction_id)),
         }
+        .update(store)?;
+
+        tx.set_dispute_state(state);
+        tx.set_disputed_at(None);
+        tx.set_disputed_amount(Decimal::ZERO);
+        tx.update(store);
+        Ok(account)
     }
 
-    // Should charge back be allowed to negative balance?
-    pub(crate) fn charge_back(
-        self,
+    /// Reverses what the dispute held and locks the account: a deposit's held funds are taken
+    /// away, and a withdrawal's held reversal is credited to the client as available funds.
+    /// Only funds still held can be charged back; if the account holds less than the dispute,
+    /// the charge back is refused rather than taking held negative.
+    fn charge_back(self, transaction_id: TransactionId, store: &mut Store) -> AccountResult<Self> {
+        let mut tx =
+            self.find_own_transaction(transaction_id, store, AccountError::ErroneousChargeback)?;
+        let amount = tx.get_disputed_amount();
+        let state = Self::transition(&tx, "charge back", DisputeState::charge_back)?;
+        if amount > self.held_amount {
+            return Err(AccountError::ChargebackExceedsHeld {
+                transaction_id,
+                disputed: amount,
+                held: self.held_amount,
+            });
+        }
+
+        let account = if tx.transaction_type() == TransactionType::Withdrawal {
+            Self {
+                available_amount: self.available_amount + amount,
+                held_amount: self.held_amount - amount,
+                ..self
+            }
+        } else {
+            Self {
+                held_amount: self.held_amount - amount,
+                total_amount: self.total_amount - amount,
+                ..self
+            }
+        };
+        let account = Self {
+            is_locked: true,
+            locked_by: Some(transaction_id),
+            ..account
+        }
+        .update(store)?;
+
+        tx.set_dispute_state(state);
+        tx.update(store);
+        Ok(account)
+    }
+
+    /// The client's own transaction `transaction_id`, or the `missing` error if it doesn't exist.
+    /// A reference to another client's transaction is a partner error too, and is rejected
+    /// rather than touching either client's funds.
+    fn find_own_transaction(
+        &self,
         transaction_id: TransactionId,
         store: &mut Store,
-    ) -> AccountResult<Self> {
-        let existing_transaction = Transaction::find_by_id(transaction_id, store);
-
-        match existing_transaction {
-            Some(tx) if tx.get_is_under_dispute() => {
-                let amount = tx.get_amount().with_context(|| "Amount does not exist")?;
-                Self {
-                    is_locked: true,
-                    held_amount: self.held_amount - amount,
-                    total_amount: self.total_amount - amount,
-                    ..self
-                }
-                .update(store)
-            }
-            _ => Err(AccountError::ErroneousChargeback(transaction_id)),
+        missing: fn(TransactionId) -> AccountError,
+    ) -> AccountResult<Transaction> {
+        let tx = Transaction::find_by_id(transaction_id, store)
+            .cloned()
+            .ok_or_else(|| missing(transaction_id))?;
+        if tx.client_id() != self.client {
+            return Err(AccountError::ForeignTransaction {
+                transaction_id,
+                client: self.client,
+            });
         }
+        Ok(tx)
+    }
+
+    /// The transaction's next dispute state, or an error if `action` isn't allowed from its current one
+    fn transition(
+        tx: &Transaction,
+        action: &'static str,
+        next: fn(DisputeState) -> Option<DisputeState>,
+    ) -> AccountResult<DisputeState> {
+        let state = tx.get_dispute_state();
+        next(state).ok_or(AccountError::IllegalDisputeTransition {
+            action,
+            transaction_id: tx.transaction_id(),
+            state,
+        })
     }
 }
@@ -0,0 +1,171 @@
+//! Amount policy: every amount is a `Decimal` from the moment it is read until it is written.
+//! Binary floating point (`f32`/`f64`) is never used to parse, compute or format an amount, since
+//! it cannot represent most decimal fractions exactly. Amounts are therefore parsed from their
+//! source text rather than through a format's native number type, and conversions to or from
+//! floats are disallowed by `clippy.toml`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use serde::de::{self, Deserializer, Visitor};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum AmountError {
+    #[error("{0} is not a fraction of two integers")]
+    InvalidFraction(String),
+
+    #[error("{0} has no exact decimal value")]
+    NonTerminatingFraction(String),
+
+    #[error("{0} is negative")]
+    Negative(Decimal),
+}
+
+/// The funds a deposit or withdrawal moves, which are never negative. Balances stay plain
+/// `Decimal`s, since available funds can go negative after a dispute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Amount(Decimal);
+
+impl Amount {
+    pub fn new(value: Decimal) -> Result<Self, AmountError> {
+        if value.is_sign_negative() && !value.is_zero() {
+            return Err(AmountError::Negative(value));
+        }
+        Ok(Self(value))
+    }
+
+    pub fn value(self) -> Decimal {
+        self.0
+    }
+}
+
+/// The exact value of a fraction amount such as `3/4`, or `None` if `text` isn't a fraction.
+/// A fraction with no exact decimal value, such as `1/3`, is rejected rather than rounded, which
+/// rejects only its row, see `ParseError::is_row_rejection`.
+pub(crate) fn parse_fraction(text: &str) -> Result<Option<Decimal>, AmountError> {
+    let (numerator, denominator) = match text.split_once('/') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let invalid = || AmountError::InvalidFraction(text.to_string());
+
+    let numerator = Decimal::from(numerator.trim().parse::<i64>().map_err(|_| invalid())?);
+    let denominator = Decimal::from(denominator.trim().parse::<u64>().map_err(|_| invalid())?);
+    let value = numerator.checked_div(denominator).ok_or_else(invalid)?;
+
+    if value * denominator != numerator {
+        return Err(AmountError::NonTerminatingFraction(text.to_string()));
+    }
+    Ok(Some(value.normalize()))
+}
+
+/// `text` without the commas grouping its whole part in thousands, e.g. `1234.56` for
+/// `1,234.56`, or `None` if it has no commas or they don't group thousands
+pub(crate) fn ungroup_thousands(text: &str) -> Option<String> {
+    let unsigned = text.trim_start_matches(['+', '-']);
+    let whole = unsigned.split('.').next().unwrap_or_default();
+    let mut groups = whole.split(',');
+    let first = groups.next().unwrap_or_default();
+
+    let digits = |group: &str| group.bytes().all(|byte| byte.is_ascii_digit());
+    let grouped = (1..=3).contains(&first.len())
+        && digits(first)
+        && groups.all(|group| group.len() == 3 && digits(group));
+    if !whole.contains(',') || !grouped {
+        return None;
+    }
+    Some(text.replacen(',', "", whole.matches(',').count()))
+}
+
+/// `text` without the underscores grouping its digits, e.g. `1000.50` for `1_000.50`, or `None`
+/// if it has no underscores or one of them isn't between two digits
+pub(crate) fn ungroup_underscores(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let grouping = |(i, &byte): (usize, &u8)| {
+        byte != b'_'
+            || (i > 0
+                && bytes[i - 1].is_ascii_digit()
+                && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+    };
+    if !text.contains('_') || !bytes.iter().enumerate().all(grouping) {
+        return None;
+    }
+    Some(text.replace('_', ""))
+}
+
+/// Deserializes an optional amount from its text, never through `f64`
+pub(crate) fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_option(OptionalAmountVisitor)
+}
+
+/// Deserializes an amount from its text, never through `f64`
+pub(crate) fn deserialize_decimal<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_str(AmountVisitor)
+}
+
+struct OptionalAmountVisitor;
+
+impl<'de> Visitor<'de> for OptionalAmountVisitor {
+    type Value = Option<Decimal>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "an optional decimal amount")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(AmountVisitor).map(Some)
+    }
+}
+
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a decimal amount")
+    }
+
+    /// Some feeds mark positive amounts with a leading `+`, e.g. `+1.50`, which is dropped before
+    /// parsing. Only one sign is accepted, so `+-1.50` is still malformed.
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        parse_amount(value).ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+}
+
+/// The amount `text` holds, in plain or scientific notation, or `None` if it isn't one.
+/// `Decimal`'s own parser skips underscores wherever they are, reading `1__0` as `10`, so text
+/// with any is rejected here; `ungroup_underscores` drops the ones grouping digits.
+pub(crate) fn parse_amount(text: &str) -> Option<Decimal> {
+    if text.contains('_') {
+        return None;
+    }
+    let unsigned = text
+        .strip_prefix('+')
+        .filter(|rest| !rest.starts_with(['+', '-']))
+        .unwrap_or(text);
+
+    Decimal::from_str(unsigned)
+        .or_else(|_| Decimal::from_scientific(unsigned))
+        .ok()
+}
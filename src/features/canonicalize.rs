@@ -0,0 +1,62 @@
+use std::io::Write;
+
+use super::account::Client;
+use super::source::TransactionSource;
+use super::transaction::{Transaction, TransactionId, TransactionType};
+use csv::Writer;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+/// Decimal places every canonical amount is written with, at least
+const AMOUNT_SCALE: u32 = 4;
+
+#[derive(Serialize, Debug)]
+struct CanonicalRow<'a> {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: Client,
+    tx: Option<TransactionId>,
+    amount: Option<Decimal>,
+    timestamp: Option<u64>,
+    memo: Option<&'a str>,
+    idempotency_key: Option<&'a str>,
+}
+
+impl<'a> From<&'a Transaction> for CanonicalRow<'a> {
+    fn from(transaction: &'a Transaction) -> Self {
+        Self {
+            transaction_type: transaction.transaction_type(),
+            client: transaction.client_id(),
+            tx: transaction.get_transaction_id(),
+            amount: transaction.get_amount().map(canonical_amount),
+            timestamp: transaction.get_timestamp(),
+            memo: transaction.get_memo(),
+            idempotency_key: transaction.get_idempotency_key(),
+        }
+    }
+}
+
+/// Pads the amount to four decimal places. Amounts with more places are kept as they are rather
+/// than rounded, so canonicalizing never changes a value.
+fn canonical_amount(mut amount: Decimal) -> Decimal {
+    if amount.scale() < AMOUNT_SCALE {
+        amount.rescale(AMOUNT_SCALE);
+    }
+    amount
+}
+
+/// Re-emits every transaction from `source` as CSV in one canonical form, without applying any:
+/// always the `type,client,tx,amount,timestamp,memo,idempotency_key` columns, no padding around
+/// fields, lowercase
+/// types and amounts to four decimal places. Two feeds meaning the same thing come out the same,
+/// so they can be diffed. A malformed transaction stops it, as it would stop processing.
+pub fn canonicalize(mut source: impl TransactionSource, writer: impl Write) -> anyhow::Result<()> {
+    let mut wtr = Writer::from_writer(writer);
+
+    while let Some(transaction) = source.next() {
+        wtr.serialize(CanonicalRow::from(&transaction?))?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use std::io::{self, BufRead, BufReader, Read};
+
+use encoding_rs::WINDOWS_1252;
+use encoding_rs_io::{DecodeReaderBytes, DecodeReaderBytesBuilder};
+
+/// Transcodes a "latin1" feed, as some legacy partners send, to UTF-8 as it is read, so it can
+/// be handed to any source. Feeds labelled latin1 are windows-1252 in practice, which is
+/// ISO-8859-1 but for 0x80-0x9F holding printable characters such as `€` and curly quotes
+/// instead of control codes, so they are decoded as windows-1252, as browsers do for the label.
+pub struct Latin1Reader<R> {
+    inner: BufReader<DecodeReaderBytes<R, Vec<u8>>>,
+}
+
+impl<R: Read> Latin1Reader<R> {
+    pub fn new(inner: R) -> Self {
+        let decoder = DecodeReaderBytesBuilder::new()
+            .encoding(Some(WINDOWS_1252))
+            .build(inner);
+        Self {
+            inner: BufReader::new(decoder),
+        }
+    }
+}
+
+impl<R: Read> Read for Latin1Reader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(out)
+    }
+}
+
+impl<R: Read> BufRead for Latin1Reader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Write;
+use std::mem;
+use std::num::NonZeroU32;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::account::{AccountError, AccountSnapshot, Client, ErrorCategory};
+use super::metrics::Metrics;
+use super::normalize::{normalize_client_ids, ClientMapping};
+use super::options::{DuplicateTxPolicy, Options};
+use super::output::{write_accounts, OutputOptions};
+use super::processing::{Completion, Event};
+use super::source::TransactionSource;
+use super::store::Store;
+use super::transaction::{Transaction, TransactionError};
+
+/// A custom check run on every transaction before it applies, rejecting it with a reason.
+/// `Send`, so an engine can be shared between the server's connections.
+pub type Validator = Box<dyn Fn(&Transaction) -> Result<(), String> + Send>;
+
+/// Everything needed to process transactions, kept together so an embedder can apply them one
+/// at a time, or a feed at a time, and read the accounts and metrics in between.
+#[derive(Default)]
+pub struct Engine {
+    options: Options,
+    store: Store,
+    metrics: Metrics,
+    validators: Vec<Validator>,
+
+    /// Clients whose locked account rejection was already logged
+    warned_locked: BTreeSet<Client>,
+}
+
+impl fmt::Debug for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Engine")
+            .field("options", &self.options)
+            .field("store", &self.store)
+            .field("metrics", &self.metrics)
+            .field("validators", &self.validators.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Engine {
+    pub fn new(options: Options) -> Self {
+        Self::with_store(options, Store::new())
+    }
+
+    /// An engine carrying on from `store`, e.g. one loaded with `load_seed`
+    pub fn with_store(options: Options, store: Store) -> Self {
+        Self {
+            options,
+            store,
+            metrics: Metrics::new(),
+            validators: Vec::new(),
+            warned_locked: BTreeSet::new(),
+        }
+    }
+
+    /// Adds a check for bespoke rules, e.g. velocity limits. Validators run in the order they
+    /// were added, before each transaction applies; the first to return an error rejects it
+    /// with that reason and the accounts are left as they were.
+    pub fn with_validator(
+        mut self,
+        validator: impl Fn(&Transaction) -> Result<(), String> + Send + 'static,
+    ) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    /// Applies one transaction. A rejected transaction leaves the accounts as they were and is
+    /// counted in the metrics.
+    pub fn apply(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        self.apply_observed(transaction, &mut |_| {})
+    }
+
+    /// Applies every transaction from `source`, handing each one's `Event` to `observe`.
+    /// Rejected transactions are logged and skipped; a malformed one stops processing, as does a
+    /// repeated transaction id under `DuplicateTxPolicy::Reject`, a decreasing one under
+    /// `Options::require_monotonic_tx`, or a partner error promoted by `Options::promoted_errors`.
+    pub fn process(
+        &mut self,
+        mut source: impl TransactionSource,
+        mut observe: impl FnMut(&Event),
+    ) -> anyhow::Result<Completion> {
+        let deadline = self.options.timeout.map(|timeout| Instant::now() + timeout);
+        let pace = self.options.replay_speed.map(|rate| (Instant::now(), rate));
+        let mut rows_paced = 0;
+
+        loop {
+            let parse_started = Instant::now();
+            let next = source.next();
+            self.metrics.timing.parse += parse_started.elapsed();
+            if let (Some((started, rate)), Some(_)) = (pace, &next) {
+                let due = started + row_due(rows_paced, rate);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+                rows_paced += 1;
+            }
+            let apply_started = Instant::now();
+
+            let transaction = match next {
+                Some(_) if deadline.is_some_and(|deadline| apply_started >= deadline) => {
+                    return Ok(Completion::TimedOut)
+                }
+                Some(Err(e)) if e.is_row_rejection() => {
+                    warn!("{e}");
+                    self.metrics.record_unreadable(&e);
+                    continue;
+                }
+                Some(result) => result?,
+                None => break,
+            };
+
+            let transaction_type = transaction.transaction_type();
+            let result = self.apply_observed(transaction, &mut observe);
+            self.metrics.timing.apply += apply_started.elapsed();
+            match result {
+                Err(e)
+                    if e.category() == ErrorCategory::PartnerError
+                        && self.options.promoted_errors.contains(&transaction_type) =>
+                {
+                    return Err(e.into())
+                }
+                Err(e @ TransactionError::DuplicateTransaction(_))
+                    if self.options.duplicate_tx_policy == DuplicateTxPolicy::Reject =>
+                {
+                    return Err(e.into())
+                }
+                Err(e @ TransactionError::NonMonotonicTransaction { .. }) => return Err(e.into()),
+                Err(e @ TransactionError::DeniedClient(_)) => {
+                    info!(target: "payment_engine::deny_list", "{e}")
+                }
+                Err(e @ TransactionError::Replayed(_)) => info!("{e}"),
+                Err(TransactionError::AccountError(AccountError::AccountLocked(client)))
+                    if self.options.dedupe_locked_warnings
+                        && !self.warned_locked.insert(client) =>
+                {
+                    self.metrics.locked_warnings_suppressed += 1
+                }
+                Err(e) => warn!("{e}"),
+                Ok(()) => {}
+            }
+        }
+
+        Ok(Completion::Finished)
+    }
+
+    fn apply_observed(
+        &mut self,
+        transaction: Transaction,
+        observe: &mut impl FnMut(&Event),
+    ) -> Result<(), TransactionError> {
+        let transaction = match transaction.assign_oldest_dispute(&self.store, &self.options) {
+            Ok(transaction) => transaction.assign_aliased_owner(&self.store, &self.options),
+            Err(e) => {
+                self.metrics.record_rejection(&e);
+                return Err(e);
+            }
+        };
+        let client = transaction.client_id();
+
+        let before = self.store.accounts.get(&client).copied();
+        let result = self
+            .validators
+            .iter()
+            .try_for_each(|validator| validator(&transaction))
+            .map_err(|reason| TransactionError::Rejected {
+                transaction_id: transaction.transaction_id(),
+                reason,
+            })
+            .and_then(|()| transaction.clone().save(&mut self.store, &self.options));
+        let after = self.store.accounts.get(&client).copied();
+
+        let event = Event {
+            transaction: &transaction,
+            before,
+            after,
+            result: &result,
+        };
+        self.metrics.record(&event);
+        observe(&event);
+
+        result
+    }
+
+    /// Renumbers the clients densely, see `normalize_client_ids`
+    pub fn normalize_client_ids(&mut self, writer: impl Write) -> anyhow::Result<ClientMapping> {
+        let (store, mapping) = normalize_client_ids(mem::take(&mut self.store), writer)?;
+        self.store = store;
+        Ok(mapping)
+    }
+
+    pub fn write_accounts(
+        &self,
+        writer: impl Write,
+        options: &OutputOptions,
+    ) -> anyhow::Result<()> {
+        write_accounts(&self.store, writer, options)
+    }
+
+    /// The state of every account as of the transactions applied so far, e.g. to answer a query
+    /// while ingestion carries on. The engine can't change while it is borrowed, so the snapshot
+    /// is consistent; a store shared between threads, as `serve` does, is snapshotted under its
+    /// lock with `Store::snapshot`.
+    pub fn snapshot(&self) -> Vec<AccountSnapshot> {
+        self.store.snapshot()
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn into_store(self) -> Store {
+        self.store
+    }
+}
+
+/// How long after the first row the `row`th is due at `rate` rows a second, see
+/// `Options::replay_speed`. Rows are due on a fixed schedule, so time lost to parsing or
+/// applying one row is made up on the next rather than adding up.
+fn row_due(row: u64, rate: NonZeroU32) -> Duration {
+    let rate = rate.get();
+    let whole_seconds = row / u64::from(rate);
+    let rest = (row % u64::from(rate)) as u32;
+    Duration::from_secs(whole_seconds) + Duration::from_secs(1) * rest / rate
+}
@@ -0,0 +1,88 @@
+use std::io::{self, Write};
+
+use super::account::{Account, Client};
+use super::processing::Event;
+use super::transaction::TransactionId;
+use rust_decimal::Decimal;
+
+/// A chronological account of everything the feed did with one transaction id: the transaction
+/// itself and every dispute, resolve and charge back referring to it, whether each was applied
+/// or rejected, and the client's balances either side of it.
+pub struct Explanation {
+    transaction_id: TransactionId,
+    row: usize,
+    lines: Vec<String>,
+}
+
+impl Explanation {
+    pub fn new(transaction_id: TransactionId) -> Self {
+        Self {
+            transaction_id,
+            row: 0,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Notes the event if it concerns the explained transaction. Meant to be handed every event
+    /// of a run, in order.
+    pub fn record(&mut self, event: &Event) {
+        self.row += 1;
+
+        let transaction = event.transaction;
+        if transaction.transaction_id() != self.transaction_id {
+            return;
+        }
+
+        let client = transaction.client_id();
+        let amount = match transaction.get_amount() {
+            Some(amount) => format!(" of {amount}"),
+            None => String::new(),
+        };
+        let outcome = match event.result {
+            Ok(()) => "applied".to_string(),
+            Err(e) => format!("rejected - {e}"),
+        };
+        self.lines.push(format!(
+            "row {}: {}{amount} by client {client}: {outcome}",
+            self.row,
+            transaction.transaction_type(),
+        ));
+        self.lines.push(format!(
+            "  {}",
+            describe_change(client, event.before, event.after)
+        ));
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        if self.lines.is_empty() {
+            return writeln!(
+                writer,
+                "transaction {} does not appear in the feed",
+                self.transaction_id
+            );
+        }
+        for line in &self.lines {
+            writeln!(writer, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The client's balances before and after, with a client that had no account counting as empty
+fn describe_change(client: Client, before: Option<Account>, after: Option<Account>) -> String {
+    let before = before.unwrap_or_else(|| Account::new(client));
+    let after = after.unwrap_or_else(|| Account::new(client));
+
+    format!(
+        "available {}, held {}, total {}, locked {} -> {}",
+        change(before.available(), after.available()),
+        change(before.held(), after.held()),
+        change(before.total(), after.total()),
+        before.is_locked(),
+        after.is_locked(),
+    )
+}
+
+fn change(before: Decimal, after: Decimal) -> String {
+    format!("{} -> {}", before.round_dp(4), after.round_dp(4))
+}
@@ -0,0 +1,177 @@
+use std::io::{Read, Write};
+
+use super::account::{Account, Client};
+use super::amount::deserialize_decimal;
+use super::normalize::ClientMapping;
+use super::processing::{Event, ProcessingError};
+use super::store::Store;
+use super::transaction::{DisputeState, Transaction, TransactionId, TransactionType};
+use csv::{Writer, WriterBuilder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// Amounts are written at full precision rather than rounded like the accounts, as the ledger is
+/// also the journal a run's state is rebuilt from, see `replay_ledger`
+#[derive(Serialize, Deserialize, Debug)]
+struct LedgerRow {
+    client: Client,
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    delta: Decimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    available: Decimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    held: Decimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    total: Decimal,
+}
+
+/// A running ledger of every applied transaction: what it moved and the client's balances after
+/// it, written as CSV as the transactions are applied. `delta` is the change in the client's
+/// total funds, so disputes and resolves of deposits, which only move funds between available
+/// and held, have a delta of zero, while those of withdrawals add and drop the held reversal.
+pub struct Ledger<W: Write> {
+    writer: Writer<W>,
+    error: Option<csv::Error>,
+
+    /// Rows kept back until `finish`, see `hold_rows`
+    held_rows: Option<Vec<LedgerRow>>,
+}
+
+impl<W: Write> Ledger<W> {
+    pub fn new(writer: W, header: bool) -> Self {
+        Self {
+            writer: WriterBuilder::new().has_headers(header).from_writer(writer),
+            error: None,
+            held_rows: None,
+        }
+    }
+
+    /// Keeps every row in memory until `finish` rather than writing it as it is recorded, so the
+    /// clients can still be renumbered once the run is over, see `renumber`
+    pub fn hold_rows(self) -> Self {
+        Self {
+            held_rows: Some(Vec::new()),
+            ..self
+        }
+    }
+
+    /// Gives the held rows' clients their new ids, see `normalize_client_ids`. Rows already
+    /// written are left as they are, so this is only of use to a ledger holding its rows.
+    pub fn renumber(&mut self, mapping: &ClientMapping) {
+        for row in self.held_rows.iter_mut().flatten() {
+            row.client = mapping.get(&row.client).copied().unwrap_or(row.client);
+        }
+    }
+
+    /// Writes a row if the event's transaction was applied. Meant to be handed every event of a
+    /// run, in order. Once a write fails nothing more is written; `finish` reports the failure.
+    pub fn record(&mut self, event: &Event) {
+        if self.error.is_some() || event.result.is_err() {
+            return;
+        }
+
+        let after = event
+            .after
+            .unwrap_or_else(|| Account::new(event.transaction.client_id()));
+        let total_before = event
+            .before
+            .map_or(Decimal::ZERO, |account| account.total());
+        let row = LedgerRow {
+            client: event.transaction.client_id(),
+            tx: event.transaction.transaction_id(),
+            transaction_type: event.transaction.transaction_type(),
+            delta: after.total() - total_before,
+            available: after.available(),
+            held: after.held(),
+            total: after.total(),
+        };
+
+        match self.held_rows.as_mut() {
+            Some(rows) => rows.push(row),
+            None => {
+                if let Err(e) = self.writer.serialize(row) {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// Writes any held rows and flushes the ledger, failing if any row could not be written
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e.into());
+        }
+        for row in self.held_rows.take().into_iter().flatten() {
+            self.writer.serialize(row)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Rebuilds the store a run ended with from its ledger, which journals every transaction it
+/// applied, without re-validating anything. Faster than re-running the raw feed, and further
+/// feeds can be processed on top, disputes included. Amounts come back at the full precision
+/// they were journaled with; memos and dispute timestamps aren't in the ledger and come back
+/// empty.
+pub fn replay_ledger(reader: impl Read) -> Result<Store, ProcessingError> {
+    use TransactionType::*;
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let mut store = Store::new();
+
+    for result in rdr.deserialize() {
+        let row: LedgerRow = result.map_err(ProcessingError::MalformedLedger)?;
+        let account = store.accounts.get(&row.client).copied();
+        let held_before = account.map_or(Decimal::ZERO, |account| account.held());
+
+        match row.transaction_type {
+            Deposit | Withdrawal | PercentageFee => {
+                // A repeated id is a correction, whose delta is the difference in amount
+                let moved = match row.transaction_type {
+                    Withdrawal | PercentageFee => -row.delta,
+                    _ => row.delta,
+                };
+                let amount = store
+                    .transactions
+                    .get(&row.tx)
+                    .and_then(Transaction::get_amount)
+                    .unwrap_or_default()
+                    + moved;
+                Transaction::replayed(row.transaction_type, row.client, row.tx, amount)
+                    .update(&mut store);
+            }
+            Dispute | Resolve | Chargeback => {
+                let tx = Transaction::find_by_id(row.tx, &mut store)
+                    .ok_or(ProcessingError::UnknownLedgerTransaction(row.tx))?;
+                let (state, disputed_amount) = match row.transaction_type {
+                    Dispute => (
+                        DisputeState::Disputed,
+                        tx.get_disputed_amount() + row.held - held_before,
+                    ),
+                    Resolve => (DisputeState::Resolved, Decimal::ZERO),
+                    _ => (DisputeState::ChargedBack, tx.get_disputed_amount()),
+                };
+                tx.set_dispute_state(state);
+                tx.set_disputed_amount(disputed_amount);
+            }
+        }
+
+        let locked_by = match row.transaction_type {
+            Chargeback => Some(row.tx),
+            _ => account.and_then(|account| account.locked_by()),
+        };
+        store.accounts.insert(
+            row.client,
+            Account::replayed(row.client, row.available, row.held, row.total, locked_by),
+        );
+    }
+
+    Ok(store)
+}
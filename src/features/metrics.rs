@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use super::account::{Account, AccountError};
+use super::processing::{Event, ProcessingError};
+use super::source::ParseError;
+use super::store::Store;
+use super::transaction::{TransactionError, TransactionId};
+use rust_decimal::Decimal;
+
+/// Counts of what happened to the transactions of a run
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Transactions read from the feed
+    pub rows_read: u64,
+
+    /// Transactions applied to an account
+    pub rows_applied: u64,
+
+    /// Transactions rejected, by reason
+    pub rows_rejected: BTreeMap<&'static str, u64>,
+
+    /// The first `REJECTION_SAMPLES` transactions rejected for each reason, as examples for ops
+    /// to look into. Bounded, so a feed rejected wholesale can't grow it
+    pub rejection_samples: BTreeMap<&'static str, Vec<RejectionSample>>,
+
+    /// The change in total funds the transactions applied made: deposits minus withdrawals minus
+    /// deposits' charge backs, plus the reversals held or credited for disputed withdrawals
+    pub net_deposits: Decimal,
+
+    /// Rejections for a locked account that were not logged, see
+    /// `Options::dedupe_locked_warnings`
+    pub locked_warnings_suppressed: u64,
+
+    /// Where the time of the run went
+    pub timing: Timing,
+}
+
+/// A rejected row kept as an example: its tx, or for a row without one, its position in the feed
+/// counting from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionSample {
+    Tx(TransactionId),
+    Row(u64),
+}
+
+impl fmt::Display for RejectionSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectionSample::Tx(tx) => write!(f, "tx {tx}"),
+            RejectionSample::Row(row) => write!(f, "row {row}"),
+        }
+    }
+}
+
+/// Time spent in each phase of a run, to tell whether parsing or applying dominates on a feed.
+/// Parsing and applying alternate row by row, so each is the sum over all rows.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timing {
+    /// Reading and parsing transactions from the feed
+    pub parse: Duration,
+
+    /// Applying transactions to the accounts, including observing them
+    pub apply: Duration,
+
+    /// Writing the output. Left for whoever writes it to fill in
+    pub write: Duration,
+}
+
+impl Timing {
+    /// Writes one line per phase, e.g. `parse: 1.2ms`
+    pub fn write_report(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "parse: {:?}", self.parse)?;
+        writeln!(writer, "apply: {:?}", self.apply)?;
+        writeln!(writer, "write: {:?}", self.write)
+    }
+}
+
+impl Metrics {
+    /// How many rejected transaction ids are kept per reason, see `rejection_samples`
+    pub const REJECTION_SAMPLES: usize = 5;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the event. Meant to be handed every event of a run
+    pub fn record(&mut self, event: &Event) {
+        self.rows_read += 1;
+        match event.result {
+            Ok(()) => {
+                self.rows_applied += 1;
+                self.net_deposits += total(event.after) - total(event.before);
+            }
+            Err(e) => {
+                let sample = match event.transaction.get_transaction_id() {
+                    Some(tx) => RejectionSample::Tx(tx),
+                    None => RejectionSample::Row(self.rows_read),
+                };
+                self.reject(rejection_reason(e), sample);
+            }
+        }
+    }
+
+    /// Counts a transaction rejected before it could be observed, having no id to observe it by
+    pub(crate) fn record_rejection(&mut self, error: &TransactionError) {
+        self.rows_read += 1;
+        self.reject(
+            rejection_reason(error),
+            RejectionSample::Row(self.rows_read),
+        );
+    }
+
+    /// Counts a row that was rejected before it could be read as a transaction, see
+    /// `ParseError::is_row_rejection`
+    pub(crate) fn record_unreadable(&mut self, error: &ParseError) {
+        self.rows_read += 1;
+        let reason = match error {
+            ParseError::UnparsableAmount(_) | ParseError::Amount(_) => "unparsable_amount",
+            _ => "unreadable",
+        };
+        self.reject(reason, RejectionSample::Row(self.rows_read));
+    }
+
+    /// Counts a rejection, keeping `sample` if the reason has fewer than `REJECTION_SAMPLES`
+    fn reject(&mut self, reason: &'static str, sample: RejectionSample) {
+        *self.rows_rejected.entry(reason).or_default() += 1;
+        let samples = self.rejection_samples.entry(reason).or_default();
+        if samples.len() < Self::REJECTION_SAMPLES {
+            samples.push(sample);
+        }
+    }
+
+    /// Fails if the net deposits differ from the total the feed declared by more than `epsilon`,
+    /// which points at dropped or duplicated rows
+    pub fn check_control_total(
+        &self,
+        declared: Decimal,
+        epsilon: Decimal,
+    ) -> Result<(), ProcessingError> {
+        if (self.net_deposits - declared).abs() > epsilon {
+            return Err(ProcessingError::ControlTotalMismatch {
+                declared,
+                actual: self.net_deposits,
+                epsilon,
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes the counts, and the funds held and available across the store's accounts, in the
+    /// Prometheus text exposition format. The sampled rejections follow as comments, one line per
+    /// reason, as a label per tx would grow the series with every feed.
+    pub fn write_prometheus(&self, store: &Store, mut writer: impl Write) -> io::Result<()> {
+        let (available, held) = store.accounts.values().fold(
+            (Decimal::ZERO, Decimal::ZERO),
+            |(available, held), account| (available + account.available(), held + account.held()),
+        );
+
+        write_metric(
+            &mut writer,
+            "payment_engine_rows_read_total",
+            "counter",
+            "Transactions read from the feed",
+        )?;
+        writeln!(writer, "payment_engine_rows_read_total {}", self.rows_read)?;
+
+        write_metric(
+            &mut writer,
+            "payment_engine_rows_applied_total",
+            "counter",
+            "Transactions applied to an account",
+        )?;
+        writeln!(
+            writer,
+            "payment_engine_rows_applied_total {}",
+            self.rows_applied
+        )?;
+
+        write_metric(
+            &mut writer,
+            "payment_engine_rows_rejected_total",
+            "counter",
+            "Transactions rejected, by reason",
+        )?;
+        for (reason, count) in &self.rows_rejected {
+            writeln!(
+                writer,
+                "payment_engine_rows_rejected_total{{reason=\"{reason}\"}} {count}"
+            )?;
+        }
+
+        write_metric(
+            &mut writer,
+            "payment_engine_funds_available",
+            "gauge",
+            "Funds available across all accounts",
+        )?;
+        writeln!(writer, "payment_engine_funds_available {available}")?;
+
+        write_metric(
+            &mut writer,
+            "payment_engine_locked_warnings_suppressed_total",
+            "counter",
+            "Rejections for a locked account that were not logged",
+        )?;
+        writeln!(
+            writer,
+            "payment_engine_locked_warnings_suppressed_total {}",
+            self.locked_warnings_suppressed
+        )?;
+
+        write_metric(
+            &mut writer,
+            "payment_engine_funds_held",
+            "gauge",
+            "Funds held for dispute across all accounts",
+        )?;
+        writeln!(writer, "payment_engine_funds_held {held}")?;
+
+        for (reason, samples) in &self.rejection_samples {
+            let samples = samples.iter().map(ToString::to_string).collect::<Vec<_>>();
+            writeln!(writer, "# rejected {reason}: {}", samples.join(", "))?;
+        }
+
+        writer.flush()
+    }
+}
+
+/// Only deposits, withdrawals and charge backs change an account's total
+fn total(account: Option<Account>) -> Decimal {
+    account.map_or(Decimal::ZERO, |account| account.total())
+}
+
+fn write_metric(writer: &mut impl Write, name: &str, kind: &str, help: &str) -> io::Result<()> {
+    writeln!(writer, "# HELP {name} {help}")?;
+    writeln!(writer, "# TYPE {name} {kind}")
+}
+
+/// A stable label for why a transaction was rejected
+fn rejection_reason(error: &TransactionError) -> &'static str {
+    match error {
+        TransactionError::AccountError(e) => match e {
+            AccountError::InsufficientFund { .. }
+            | AccountError::InsufficientFundWhileHeld { .. } => "insufficient_funds",
+            AccountError::AccountLocked(_) => "account_locked",
+            AccountError::AccountClosed(_) => "account_closed",
+            AccountError::InvalidInput(_) => "invalid_input",
+            AccountError::ErroneousDispute(_)
+            | AccountError::ErroneousResolve(_)
+            | AccountError::ErroneousChargeback(_) => "unknown_transaction",
+            AccountError::ForeignTransaction { .. } => "foreign_transaction",
+            AccountError::UncorrectableTransaction(_) => "uncorrectable_transaction",
+            AccountError::UndisputableTransaction(_) => "undisputable_transaction",
+            AccountError::UnappliedWithdrawal(_) => "unapplied_withdrawal",
+            AccountError::IllegalDisputeTransition { .. } => "illegal_dispute_transition",
+            AccountError::DisputeExceedsAmount { .. } => "dispute_exceeds_amount",
+            AccountError::ChargebackExceedsHeld { .. } => "chargeback_exceeds_held",
+            AccountError::HeldUnderflow { .. } => "held_underflow",
+        },
+        TransactionError::InvalidAmount(_)
+        | TransactionError::NegativeDeposit(_)
+        | TransactionError::NegativeWithdrawal(_) => "invalid_amount",
+        TransactionError::MissingAmount { .. } => "missing_amount",
+        TransactionError::AmountExceedsLimit { .. } => "amount_exceeds_limit",
+        TransactionError::InvalidPercentage(_) => "invalid_percentage",
+        TransactionError::ClientLimitReached { .. } => "client_limit_reached",
+        TransactionError::DuplicateTransaction(_) => "duplicate_transaction",
+        TransactionError::DeniedClient(_) => "denied_client",
+        TransactionError::NonMonotonicTransaction { .. } => "non_monotonic_transaction",
+        TransactionError::Rejected { .. } => "rejected_by_validator",
+        TransactionError::MissingTransactionId { .. } => "missing_transaction_id",
+        TransactionError::NoOpenDispute(_) => "no_open_dispute",
+        TransactionError::Replayed(_) => "replayed",
+        TransactionError::Unknown(_) => "unknown",
+    }
+}
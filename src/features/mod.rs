@@ -1,9 +1,50 @@
 mod account;
+mod amount;
+mod canonicalize;
+mod encoding;
+mod engine;
+mod explain;
+mod ledger;
+mod metrics;
+mod normalize;
+mod options;
+mod output;
+mod processing;
+mod range;
+mod report;
+mod server;
+mod source;
 mod store;
+mod trace;
 mod transaction;
 
 pub use self::{
-    account::Account,
-    store::Store,
-    transaction::{Transaction, TransactionId},
+    account::{Account, AccountError, AccountSnapshot, Client, DisputeOutcome, ErrorCategory},
+    amount::{Amount, AmountError},
+    canonicalize::canonicalize,
+    encoding::Latin1Reader,
+    engine::{Engine, Validator},
+    explain::Explanation,
+    ledger::{replay_ledger, Ledger},
+    metrics::{Metrics, RejectionSample, Timing},
+    normalize::{normalize_client_ids, ClientMapping},
+    options::{DuplicateTxPolicy, IngestRounding, Options, RedisputePolicy},
+    output::{
+        account_schema, check_round_trip, open_append, write_accounts, write_split_accounts,
+        Column, OutputError, OutputFormat, OutputOptions, SortOrder,
+    },
+    processing::{
+        load_client_aliases, load_deny_list, load_seed, process_transactions,
+        process_transactions_with, Completion, Event, ProcessingError,
+    },
+    range::{read_byte_range, ByteRange, ByteRangeError},
+    report::{
+        write_baseline_diff, write_held_aging, write_locked_report, write_open_disputes,
+        write_transaction_dump,
+    },
+    server::serve,
+    source::{CsvSource, JsonLinesSource, ParseError, TransactionSource},
+    store::{Store, StoreError},
+    trace::ClientTrace,
+    transaction::{DisputeState, Transaction, TransactionError, TransactionId, TransactionType},
 };
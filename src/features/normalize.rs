@@ -0,0 +1,67 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use super::account::Client;
+use super::store::Store;
+use super::transaction::Transaction;
+use csv::Writer;
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+struct MappingRow {
+    old: Client,
+    new: Client,
+}
+
+/// The new id of every renumbered client, by its original id
+pub type ClientMapping = BTreeMap<Client, Client>;
+
+/// Renumbers clients densely as 1..=N in the order of their original ids, so sparse ids from an
+/// anonymized dataset come out compact. Accounts and the transactions they own are rewritten
+/// together, so balances and disputes are unaffected. Every client of a stored transaction is
+/// renumbered, including those whose every row was refused and who have no account, so no two
+/// clients end up sharing an id. The `old,new` mapping goes to `writer`, and is returned for
+/// anything else written with the original ids, see `Ledger::renumber`.
+pub fn normalize_client_ids(
+    mut store: Store,
+    writer: impl Write,
+) -> anyhow::Result<(Store, ClientMapping)> {
+    let clients = store
+        .accounts
+        .keys()
+        .copied()
+        .chain(store.transactions.values().map(Transaction::client_id))
+        .collect::<BTreeSet<_>>();
+    let mapping = clients
+        .into_iter()
+        .zip(1..)
+        .map(|(old, new)| (old, Client::from(new)))
+        .collect::<ClientMapping>();
+
+    store.accounts = store
+        .accounts
+        .into_values()
+        .map(|account| {
+            let client = mapping[&account.client()];
+            (client, account.with_client(client))
+        })
+        .collect();
+
+    for transaction in store.transactions.values_mut() {
+        transaction.set_client(mapping[&transaction.client_id()]);
+    }
+
+    store.memos = store
+        .memos
+        .into_iter()
+        .map(|(client, memo)| (mapping.get(&client).copied().unwrap_or(client), memo))
+        .collect();
+
+    let mut wtr = Writer::from_writer(writer);
+    for (&old, &new) in &mapping {
+        wtr.serialize(MappingRow { old, new })?;
+    }
+    wtr.flush()?;
+
+    Ok((store, mapping))
+}
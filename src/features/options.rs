@@ -0,0 +1,190 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use super::account::Client;
+use super::transaction::TransactionType;
+use rust_decimal::{Decimal, RoundingStrategy};
+
+/// Knobs that change how transactions are interpreted while processing a feed
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Treat a withdrawal with a negative amount as a deposit of the absolute amount.
+    /// Some partners encode refunds this way. When off, such withdrawals are rejected.
+    pub negative_withdrawal_as_deposit: bool,
+
+    /// The most accounts the store will hold. Once reached, deposits, withdrawals and fees for
+    /// clients without an account are rejected so a hostile feed can't exhaust memory; existing
+    /// clients carry on.
+    pub max_clients: Option<usize>,
+
+    /// Funds every new account starts with, available, instead of none. A testing aid for
+    /// simulations, never meant for production feeds.
+    pub seed_balance: Decimal,
+
+    /// The largest amount a deposit or withdrawal may have. Larger ones are rejected as partner
+    /// errors, so a fat-fingered or malicious amount can't distort balances. Disputes are unaffected.
+    pub max_amount: Option<Decimal>,
+
+    /// How long processing may run. Once it has passed, the rest of the feed is left
+    /// unprocessed so a pathological feed can't hold up a deployment indefinitely.
+    pub timeout: Option<Duration>,
+
+    /// Apply at most this many rows a second, sleeping as needed, to replay a feed at a
+    /// controlled rate, e.g. to load-test a downstream system fed from the service. Only the pace
+    /// changes, not what is applied. Time spent waiting counts towards the `timeout`.
+    pub replay_speed: Option<NonZeroU32>,
+
+    /// What to do with a deposit or withdrawal reusing the id of one already applied
+    pub duplicate_tx_policy: DuplicateTxPolicy,
+
+    /// Stop processing at a deposit, withdrawal or fee whose id isn't greater than every such id
+    /// before it. For feeds whose ids are meant to increase, where a decrease means corruption.
+    /// A repeated id is left to `duplicate_tx_policy`.
+    pub require_monotonic_tx: bool,
+
+    /// Whether a transaction can be disputed again once its dispute was resolved
+    pub redispute_policy: RedisputePolicy,
+
+    /// Let a resolve or charge back leave out its tx, applying to the client's oldest open
+    /// dispute. One naming a tx always applies to that transaction, open disputes before it or
+    /// not. When off, a row without a tx is rejected.
+    pub fifo_disputes: bool,
+
+    /// Ignore a transaction delivered again, e.g. resent over the network, rather than applying
+    /// it twice. Deliveries are told apart by their `idempotency_key` column, or if they have
+    /// none, by all of their fields, so a dispute repeated verbatim holds its funds only once.
+    /// A dispute raised again after a resolve belongs to a new dispute, so it still applies.
+    pub idempotent: bool,
+
+    /// Close an account once a withdrawal leaves it with nothing available, held or in total,
+    /// unless it is locked. Later transactions for the client are rejected as the account is
+    /// closed, which is told apart from a lock in rejections and metrics.
+    pub close_zero_accounts: bool,
+
+    /// Round `available` and `held` to this many decimal places after every transaction that
+    /// changes them, with `total` their sum, for regimes that round at each operation. By default
+    /// balances keep full precision and only the output is rounded, once. Rounding at each step
+    /// drops what lies below the scale every time, so balances can drift from the exact sum of
+    /// the transactions, e.g. two deposits of 0.335 at a scale of 2 leave 0.68 rather than 0.67,
+    /// and `Store::check_balanced` no longer holds. Transaction amounts are never rounded.
+    pub working_scale: Option<u32>,
+
+    /// Clients whose transactions are all skipped, e.g. for sanctions, so they never have an
+    /// account
+    pub deny_list: BTreeSet<Client>,
+
+    /// Round the amount of every transaction to this many decimal places as it is read, e.g.
+    /// the output precision, so balances never carry digits the output can't show and displayed
+    /// balances add up exactly to displayed totals across accounts. By default amounts keep
+    /// their full precision and only the output is rounded. How the dropped digits are handled
+    /// is up to `ingest_rounding`. Amounts are rounded before anything else looks at them,
+    /// limits and idempotency keys included; a fee is rounded once charged.
+    pub ingest_precision: Option<u32>,
+
+    /// How amounts are brought to `ingest_precision`
+    pub ingest_rounding: IngestRounding,
+
+    /// Decimal places a percentage fee is truncated to, so it is no finer than the balances are
+    /// written or kept at. Unset, the output's default of four places. A working scale, when
+    /// set, takes precedence.
+    pub fee_precision: Option<u32>,
+
+    /// Client ids a partner re-keyed clients to mid-feed, each mapped to the id it stands for.
+    /// A dispute, resolve or charge back of a transaction under an id aliasing its client, in
+    /// either direction, applies to the transaction's own client, whose account holds its funds.
+    /// Other transactions stay with the id they give.
+    pub client_aliases: BTreeMap<Client, Client>,
+
+    /// Transaction types whose partner errors, e.g. a dispute of an unknown transaction, stop
+    /// processing instead of being logged and skipped, for strict feeds. Other rejections of
+    /// those types, such as insufficient funds, are still only logged.
+    pub promoted_errors: BTreeSet<TransactionType>,
+
+    /// Log only the first rejection for a locked account, per client. Frozen accounts that keep
+    /// receiving transactions would otherwise flood the logs; the rest are still counted.
+    pub dedupe_locked_warnings: bool,
+}
+
+impl Options {
+    /// The output's default precision, which fees are truncated to unless configured otherwise
+    const DEFAULT_FEE_PRECISION: u32 = 4;
+
+    /// Decimal places a percentage fee is truncated to, see `fee_precision`
+    pub(crate) fn fee_precision(&self) -> u32 {
+        self.working_scale
+            .or(self.fee_precision)
+            .unwrap_or(Self::DEFAULT_FEE_PRECISION)
+    }
+
+    /// The amount brought to `ingest_precision`, if one is set
+    pub(crate) fn ingest(&self, amount: Decimal) -> Decimal {
+        match (self.ingest_precision, self.ingest_rounding) {
+            (Some(places), IngestRounding::Round) => amount.round_dp(places),
+            (Some(places), IngestRounding::Truncate) => {
+                amount.round_dp_with_strategy(places, RoundingStrategy::ToZero)
+            }
+            (None, _) => amount,
+        }
+    }
+
+    /// Whether the two ids name the same client, directly or through `client_aliases`
+    pub(crate) fn is_same_client(&self, a: Client, b: Client) -> bool {
+        self.original_client(a) == self.original_client(b)
+    }
+
+    /// The id a client had before any re-keying. Chains of aliases are followed, but no further
+    /// than there are aliases, so a cycle in them can't loop forever.
+    fn original_client(&self, mut client: Client) -> Client {
+        for _ in 0..self.client_aliases.len() {
+            match self.client_aliases.get(&client) {
+                Some(&original) => client = original,
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// Whether a resolved transaction can be disputed again. Some partners treat a resolve as final,
+/// so a second dispute of the same transaction is a partner error for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RedisputePolicy {
+    /// A new dispute holds the funds again
+    #[default]
+    Allow,
+
+    /// A new dispute is rejected as an illegal dispute transition
+    Reject,
+}
+
+/// How an amount with more decimal places than `Options::ingest_precision` loses them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IngestRounding {
+    /// To the nearest, ties to even, as the output rounds, so an amount reads in as it would
+    /// have been written
+    #[default]
+    Round,
+
+    /// Towards zero, so a client is never credited or debited more than the amount says
+    Truncate,
+}
+
+/// What to do with a deposit or withdrawal whose id was already applied. Partners sending
+/// corrections expect the last row to win; for everyone else a repeated id means the feed is
+/// broken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateTxPolicy {
+    /// Keep the first row and reject the repeats
+    First,
+
+    /// Treat a repeat as a correction of the first row: its amount replaces the first's and the
+    /// balance moves by the difference. Only a transaction that was applied and never disputed
+    /// can be corrected, and only by a row of the same client and type; a correction that would
+    /// take available funds negative is rejected like a withdrawal.
+    Last,
+
+    /// Stop processing the feed at the first repeat
+    #[default]
+    Reject,
+}
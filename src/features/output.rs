@@ -0,0 +1,526 @@
+use std::cmp::Reverse;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::iter;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::account::{Account, Client};
+use super::store::Store;
+use csv::{ReaderBuilder, WriterBuilder};
+use rust_decimal::Decimal;
+use serde::ser::{self, SerializeMap};
+use serde::{Serialize, Serializer};
+use serde_json::json;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum OutputError {
+    #[error("Unknown column {0:?}, expected one of client, available, held, total, locked, memo")]
+    UnknownColumn(String),
+
+    #[error("Column {0:?} is selected more than once")]
+    DuplicateColumn(String),
+
+    #[error("At least one column must be selected")]
+    NoColumns,
+
+    #[error("Client {client}'s {column} was written as {written:?}, which reads back as a different value")]
+    RoundTripMismatch {
+        client: Client,
+        column: &'static str,
+        written: String,
+    },
+
+    #[error("{written} accounts were written, but {read} read back")]
+    RoundTripCount { written: usize, read: usize },
+}
+
+/// How the final account states are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// A header naming the columns followed by one row per account
+    #[default]
+    Csv,
+
+    /// One standalone JSON object per account, each terminated by a newline, so consumers can
+    /// process accounts as they arrive. Amounts are JSON numbers.
+    JsonLines,
+}
+
+/// The order accounts are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    /// By client id
+    #[default]
+    Client,
+
+    /// Most funds held first, by absolute amount, then by client id, for dispute triage
+    HeldDesc,
+}
+
+/// A field of an account that can be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+
+    /// The memo of the client's latest applied transaction. Not written by default
+    Memo,
+}
+
+impl Column {
+    /// The columns written by default, in order
+    pub const DEFAULT: [Column; 5] = [
+        Column::Client,
+        Column::Available,
+        Column::Held,
+        Column::Total,
+        Column::Locked,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Total => "total",
+            Column::Locked => "locked",
+            Column::Memo => "memo",
+        }
+    }
+
+    /// Parses a comma separated list of column names, e.g. `client,total,locked`
+    pub fn parse_list(list: &str) -> Result<Vec<Column>, OutputError> {
+        let mut columns = Vec::new();
+
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let column = name.parse()?;
+            if columns.contains(&column) {
+                return Err(OutputError::DuplicateColumn(name.to_string()));
+            }
+            columns.push(column);
+        }
+
+        if columns.is_empty() {
+            return Err(OutputError::NoColumns);
+        }
+        Ok(columns)
+    }
+
+    fn schema(self) -> serde_json::Value {
+        let amount = |description: &str| {
+            json!({
+                "type": "number",
+                "description": format!("{description}. A decimal, rounded to four decimal places unless configured otherwise"),
+            })
+        };
+
+        match self {
+            Column::Client => json!({
+                "type": "integer",
+                "minimum": 0,
+                "maximum": u16::MAX,
+                "description": "The client id",
+            }),
+            Column::Available => amount("Funds available for withdrawal"),
+            Column::Held => amount("Funds held for dispute"),
+            Column::Total => amount("Available plus held funds"),
+            Column::Locked => json!({
+                "type": "boolean",
+                "description": "Whether a charge back locked the account",
+            }),
+            Column::Memo => json!({
+                "type": ["string", "null"],
+                "description": "The memo of the client's latest applied transaction, if it had one",
+            }),
+        }
+    }
+
+    /// The column's value as written to CSV, with amounts rounded as `options` say
+    fn text(self, account: &Account, store: &Store, options: &OutputOptions) -> String {
+        match self {
+            Column::Client => account.client().to_string(),
+            Column::Available => options.round(self, account.available()).to_string(),
+            Column::Held => options.round(self, options.held(account)).to_string(),
+            Column::Total => options.round(self, account.total()).to_string(),
+            Column::Locked => account.is_locked().to_string(),
+            Column::Memo => store
+                .memos
+                .get(&account.client())
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether `field`, as read back from CSV, is the column's value rounded as `options` say
+    fn reads_back(
+        self,
+        field: &str,
+        account: &Account,
+        store: &Store,
+        options: &OutputOptions,
+    ) -> bool {
+        let amount =
+            |amount: Decimal| Decimal::from_str(field).ok() == Some(options.round(self, amount));
+
+        match self {
+            Column::Client => field.parse::<u16>().ok().map(Client::from) == Some(account.client()),
+            Column::Available => amount(account.available()),
+            Column::Held => amount(options.held(account)),
+            Column::Total => amount(account.total()),
+            Column::Locked => field.parse::<bool>().ok() == Some(account.is_locked()),
+            Column::Memo => {
+                field
+                    == store
+                        .memos
+                        .get(&account.client())
+                        .map_or("", String::as_str)
+            }
+        }
+    }
+}
+
+/// A JSON Schema describing one object of the JSON Lines account output with `columns`, so
+/// consumers can validate it or generate a parser. Amounts are decimal JSON numbers rounded to
+/// the configured precision, which a schema can't state beyond the description.
+pub fn account_schema(columns: &[Column]) -> serde_json::Value {
+    let properties = columns
+        .iter()
+        .map(|column| (column.name().to_string(), column.schema()))
+        .collect::<serde_json::Map<_, _>>();
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Account",
+        "description": "One line of the payment engine's JSON Lines account output",
+        "type": "object",
+        "properties": properties,
+        "required": columns.iter().map(|column| column.name()).collect::<Vec<_>>(),
+        "additionalProperties": false,
+    })
+}
+
+impl FromStr for Column {
+    type Err = OutputError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Column::DEFAULT
+            .into_iter()
+            .chain([Column::Memo])
+            .find(|column| column.name() == name)
+            .ok_or_else(|| OutputError::UnknownColumn(name.to_string()))
+    }
+}
+
+/// Knobs that change how the final account states are written
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+
+    /// The columns to write, in order
+    pub columns: Vec<Column>,
+
+    /// Whether CSV output starts with a header naming the columns
+    pub header: bool,
+
+    /// Decimal places `available` and `total` are rounded to
+    pub balance_precision: u32,
+
+    /// Decimal places `held` is rounded to. Some regulators want disputed funds reported at
+    /// full precision while balances are rounded for display
+    pub held_precision: u32,
+
+    /// Bytes of output gathered before each write to the writer
+    pub write_buffer: usize,
+
+    /// The order accounts are written in
+    pub sort: SortOrder,
+
+    /// Write held as zero for a locked account, whose funds are settled by the charge back that
+    /// locked it. Only the output changes; the account keeps what it holds, and its total still
+    /// includes it.
+    pub zero_held_when_locked: bool,
+
+    /// When set, CSV output starts with a comment line `# generated_at=<rfc3339>, rows=<n>`
+    /// giving this time, in seconds since the Unix epoch, and the number of accounts written,
+    /// for traceability. Readers with `#` as their comment character skip it.
+    pub generated_at: Option<u64>,
+
+    /// Write nothing at all, not even the CSV header, when there are no accounts, e.g. for a run
+    /// whose every transaction was rejected, for pipelines that take a zero-byte file to mean
+    /// there is nothing to load
+    pub empty_when_no_accounts: bool,
+}
+
+impl OutputOptions {
+    /// The most decimal places an amount can have, i.e. full precision
+    pub const MAX_PRECISION: u32 = 28;
+
+    /// The default `write_buffer`. Larger than the CSV library's own default, so a big output
+    /// takes fewer writes
+    pub const DEFAULT_WRITE_BUFFER: usize = 64 * 1024;
+
+    /// The funds the account holds, as written
+    pub(crate) fn held(&self, account: &Account) -> Decimal {
+        if self.zero_held_when_locked && account.is_locked() {
+            Decimal::ZERO
+        } else {
+            account.held()
+        }
+    }
+
+    /// The amount of `column` rounded to that column's precision
+    pub(crate) fn round(&self, column: Column, amount: Decimal) -> Decimal {
+        match column {
+            Column::Held => amount.round_dp(self.held_precision),
+            _ => amount.round_dp(self.balance_precision),
+        }
+    }
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::default(),
+            columns: Column::DEFAULT.to_vec(),
+            header: true,
+            balance_precision: 4,
+            held_precision: 4,
+            write_buffer: Self::DEFAULT_WRITE_BUFFER,
+            sort: SortOrder::default(),
+            zero_held_when_locked: false,
+            generated_at: None,
+            empty_when_no_accounts: false,
+        }
+    }
+}
+
+/// Opens `path` for writing after whatever it already holds, creating it if needed. Also returns
+/// whether the file already had content, in which case it already has a header.
+pub fn open_append(path: impl AsRef<Path>) -> io::Result<(File, bool)> {
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+    let has_content = file.metadata()?.len() > 0;
+    Ok((file, has_content))
+}
+
+/// Writes every account in the store. Rows are streamed to `writer` as they are formatted,
+/// through a buffer of `options.write_buffer` bytes, and never collected first, so writing tens
+/// of millions of accounts takes no more memory than the buffer, and `writer` needs no buffering
+/// of its own. Any order but by client id takes a reference per account to sort.
+pub fn write_accounts(
+    store: &Store,
+    writer: impl Write,
+    options: &OutputOptions,
+) -> anyhow::Result<()> {
+    match options.sort {
+        SortOrder::Client => write_rows(store.accounts.values(), store, writer, options),
+        SortOrder::HeldDesc => {
+            // By held as written, and stable, so accounts holding the same amount stay in
+            // client id order
+            let mut accounts = store.accounts.values().collect::<Vec<_>>();
+            accounts.sort_by_key(|account| {
+                Reverse(options.round(Column::Held, options.held(account)).abs())
+            });
+            write_rows(accounts.into_iter(), store, writer, options)
+        }
+    }
+}
+
+/// Writes the accounts as CSV with `options`' columns and precision, reads them back and checks
+/// every field reads as the value it was written from, rounded to the configured precision. A
+/// self-test of the output formatting, failing on the first field that doesn't round-trip.
+pub fn check_round_trip(store: &Store, options: &OutputOptions) -> anyhow::Result<()> {
+    let options = OutputOptions {
+        format: OutputFormat::Csv,
+        header: true,
+        sort: SortOrder::Client,
+        generated_at: None,
+        ..options.clone()
+    };
+    let mut written = Vec::new();
+    write_accounts(store, &mut written, &options)?;
+
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(written.as_slice());
+    let mut read = 0;
+    for (record, account) in rdr.records().zip(store.accounts.values()) {
+        let record = record?;
+        for (&column, field) in options.columns.iter().zip(record.iter()) {
+            if !column.reads_back(field, account, store, &options) {
+                return Err(OutputError::RoundTripMismatch {
+                    client: account.client(),
+                    column: column.name(),
+                    written: field.to_string(),
+                }
+                .into());
+            }
+        }
+        read += 1;
+    }
+
+    if read != store.accounts.len() {
+        return Err(OutputError::RoundTripCount {
+            written: store.accounts.len(),
+            read,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Writes each account to its own file in `dir`, named by client id, e.g. `17.csv`, or
+/// `17.jsonl` for JSON Lines, so sharded or per-tenant pipelines can load them separately. Each
+/// file is written as `write_accounts` would write a store holding only that account. `dir` is
+/// created if needed and existing files of the same name are replaced.
+pub fn write_split_accounts(
+    store: &Store,
+    dir: impl AsRef<Path>,
+    options: &OutputOptions,
+) -> anyhow::Result<()> {
+    let dir = dir.as_ref();
+    let extension = match options.format {
+        OutputFormat::Csv => "csv",
+        OutputFormat::JsonLines => "jsonl",
+    };
+    fs::create_dir_all(dir)?;
+
+    for account in store.accounts.values() {
+        let path = dir.join(format!("{}.{extension}", account.client()));
+        write_rows(iter::once(account), store, File::create(path)?, options)?;
+    }
+
+    Ok(())
+}
+
+fn write_rows<'a>(
+    accounts: impl ExactSizeIterator<Item = &'a Account>,
+    store: &Store,
+    mut writer: impl Write,
+    options: &OutputOptions,
+) -> anyhow::Result<()> {
+    match options.format {
+        OutputFormat::Csv if accounts.len() == 0 && options.empty_when_no_accounts => {}
+        OutputFormat::Csv => {
+            if let Some(generated_at) = options.generated_at {
+                writeln!(
+                    writer,
+                    "# generated_at={}, rows={}",
+                    rfc3339(generated_at),
+                    accounts.len()
+                )?;
+            }
+            let mut wtr = WriterBuilder::new()
+                .buffer_capacity(options.write_buffer)
+                .from_writer(writer);
+
+            if options.header {
+                wtr.write_record(options.columns.iter().map(|column| column.name()))?;
+            }
+            for account in accounts {
+                wtr.write_record(
+                    options
+                        .columns
+                        .iter()
+                        .map(|column| column.text(account, store, options)),
+                )?;
+            }
+            wtr.flush()?;
+        }
+        OutputFormat::JsonLines => {
+            let mut writer = BufWriter::with_capacity(options.write_buffer, writer);
+            for account in accounts {
+                let row = JsonRow {
+                    account,
+                    memo: store.memos.get(&account.client()),
+                    options,
+                };
+                serde_json::to_writer(&mut writer, &row)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `seconds` since the Unix epoch as an RFC 3339 UTC timestamp, e.g. `2022-06-01T12:30:00Z`
+fn rfc3339(seconds: u64) -> String {
+    const DAY: u64 = 24 * 60 * 60;
+    let (days, time) = (seconds / DAY, seconds % DAY);
+
+    // The civil date of a day count, after Howard Hinnant's `civil_from_days`, counting in
+    // 400 year eras of 146097 days from 0000-03-01, so leap days fall at the end of a year
+    let days = days + 719_468;
+    let (era, day_of_era) = (days / 146_097, days % 146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// An account as a JSON object holding the selected columns, in order
+struct JsonRow<'a> {
+    account: &'a Account,
+    memo: Option<&'a String>,
+    options: &'a OutputOptions,
+}
+
+impl Serialize for JsonRow<'_> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let amount =
+            |column: Column, amount: Decimal| json_number(self.options.round(column, amount));
+
+        let mut map = s.serialize_map(Some(self.options.columns.len()))?;
+        for &column in &self.options.columns {
+            match column {
+                Column::Client => map.serialize_entry("client", &self.account.client())?,
+                Column::Available => {
+                    map.serialize_entry("available", &amount(column, self.account.available())?)?
+                }
+                Column::Held => {
+                    map.serialize_entry("held", &amount(column, self.options.held(self.account))?)?
+                }
+                Column::Total => {
+                    map.serialize_entry("total", &amount(column, self.account.total())?)?
+                }
+                Column::Locked => map.serialize_entry("locked", &self.account.is_locked())?,
+                Column::Memo => map.serialize_entry("memo", &self.memo)?,
+            }
+        }
+        map.end()
+    }
+}
+
+/// The amount as a JSON number. The number is built from the decimal's digits, never from an
+/// `f64`, so it carries exactly the amount.
+pub(crate) fn json_number<E: ser::Error>(amount: Decimal) -> Result<serde_json::Number, E> {
+    serde_json::Number::from_str(&amount.to_string()).map_err(E::custom)
+}
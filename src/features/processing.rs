@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Read};
+
+use super::account::{Account, Client};
+use super::engine::Engine;
+use super::options::Options;
+use super::source::TransactionSource;
+use super::store::Store;
+use super::transaction::{Transaction, TransactionError, TransactionId};
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+/// Errors that fail a whole run, as opposed to rejecting a single transaction
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ProcessingError {
+    #[error("Malformed seed account - {0}")]
+    MalformedSeed(#[from] csv::Error),
+
+    #[error("Seed contains more than one row for client {0}")]
+    DuplicateSeedAccount(Client),
+
+    #[error("Malformed ledger row - {0}")]
+    MalformedLedger(csv::Error),
+
+    #[error("Ledger row for transaction {0} comes before the transaction itself")]
+    UnknownLedgerTransaction(TransactionId),
+
+    #[error("Malformed deny list - line {line}: {text:?} is not a client id")]
+    MalformedDenyList { line: usize, text: String },
+
+    #[error("Unable to read deny list - {0}")]
+    UnreadableDenyList(#[from] io::Error),
+
+    #[error("Malformed client aliases - line {line}: {text:?} is not an alias,client pair")]
+    MalformedClientAliases { line: usize, text: String },
+
+    #[error("Unable to read client aliases - {0}")]
+    UnreadableClientAliases(io::Error),
+
+    #[error("The accounts of {0} clients differ from the baseline")]
+    BaselineMismatch(usize),
+
+    #[error("Net deposits of {actual} differ from the control total of {declared} by more than {epsilon}")]
+    ControlTotalMismatch {
+        declared: Decimal,
+        actual: Decimal,
+        epsilon: Decimal,
+    },
+}
+
+/// Builds a store from a previous run's account output, so a feed can be processed on top of it.
+/// A client appearing more than once means the snapshot is corrupt, so it is rejected rather
+/// than keeping either row.
+pub fn load_seed(reader: impl Read) -> Result<Store, ProcessingError> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+
+    let mut store = Store::new();
+
+    for result in rdr.deserialize() {
+        let account: Account = result?;
+        if store.accounts.insert(account.client(), account).is_some() {
+            return Err(ProcessingError::DuplicateSeedAccount(account.client()));
+        }
+    }
+
+    Ok(store)
+}
+
+/// Reads a deny list of one client id per line. Blank lines and lines starting with `#` are
+/// skipped, so the list can carry comments, e.g. the sanction each id is listed under.
+pub fn load_deny_list(reader: impl BufRead) -> Result<BTreeSet<Client>, ProcessingError> {
+    let mut clients = BTreeSet::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let text = line.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let client = text
+            .parse::<u16>()
+            .map_err(|_| ProcessingError::MalformedDenyList {
+                line: index + 1,
+                text: text.to_string(),
+            })?;
+        clients.insert(client.into());
+    }
+
+    Ok(clients)
+}
+
+/// Reads client aliases as one `alias,client` pair per line, the alias being the id a client
+/// was re-keyed to. Blank lines and lines starting with `#` are skipped, like a deny list.
+pub fn load_client_aliases(
+    reader: impl BufRead,
+) -> Result<BTreeMap<Client, Client>, ProcessingError> {
+    let mut aliases = BTreeMap::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(ProcessingError::UnreadableClientAliases)?;
+        let text = line.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let malformed = || ProcessingError::MalformedClientAliases {
+            line: index + 1,
+            text: text.to_string(),
+        };
+        let (alias, client) = text.split_once(',').ok_or_else(malformed)?;
+        let alias = alias.trim().parse::<u16>().map_err(|_| malformed())?;
+        let client = client.trim().parse::<u16>().map_err(|_| malformed())?;
+        aliases.insert(alias.into(), client.into());
+    }
+
+    Ok(aliases)
+}
+
+/// One transaction from the feed and what it did to its client's account
+pub struct Event<'a> {
+    pub transaction: &'a Transaction,
+
+    /// The client's account before the transaction, if it had one
+    pub before: Option<Account>,
+
+    /// The client's account after the transaction, if it has one
+    pub after: Option<Account>,
+
+    /// Whether the transaction was applied, or why it was rejected
+    pub result: &'a Result<(), TransactionError>,
+}
+
+/// How far through the feed processing got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// Every transaction in the feed was processed
+    Finished,
+
+    /// Processing stopped once `Options::timeout` had passed, leaving the rest of the feed
+    TimedOut,
+}
+
+pub fn process_transactions(
+    source: impl TransactionSource,
+    store: Store,
+    options: &Options,
+) -> anyhow::Result<Store> {
+    let (store, _) = process_transactions_with(source, store, options, |_| {})?;
+    Ok(store)
+}
+
+/// Like `process_transactions`, but hands every transaction's `Event` to `observe` as it happens.
+/// The store is returned even if processing timed out, so whatever was processed can be written.
+pub fn process_transactions_with(
+    source: impl TransactionSource,
+    store: Store,
+    options: &Options,
+    observe: impl FnMut(&Event),
+) -> anyhow::Result<(Store, Completion)> {
+    let mut engine = Engine::with_store(options.clone(), store);
+    let completion = engine.process(source, observe)?;
+    Ok((engine.into_store(), completion))
+}
@@ -0,0 +1,73 @@
+use std::io::{self, BufRead, Cursor, Read, Seek, SeekFrom};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ByteRangeError {
+    #[error("expected a byte range as START:END, got {0:?}")]
+    Malformed(String),
+
+    #[error("the byte range {start}:{end} ends before it starts")]
+    Backwards { start: u64, end: u64 },
+}
+
+/// A slice of a feed by byte offset, so several processes can each take a part of one large
+/// file. A record belongs to the range its first byte falls in, so adjacent ranges `0:N` and
+/// `N:M` share no record and miss none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl FromStr for ByteRange {
+    type Err = ByteRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || ByteRangeError::Malformed(s.to_owned());
+        let (start, end) = s.split_once(':').ok_or_else(malformed)?;
+        let start = start.trim().parse().map_err(|_| malformed())?;
+        let end = end.trim().parse().map_err(|_| malformed())?;
+        if end < start {
+            return Err(ByteRangeError::Backwards { start, end });
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// The records of `reader` whose first byte falls in `range`. Record boundaries are line
+/// breaks, so a range shouldn't be cut inside a quoted field spanning lines. With
+/// `keep_header`, the feed's first line is read ahead of a range starting past it, as a CSV
+/// source needs its header in every shard.
+pub fn read_byte_range<R: BufRead + Seek>(
+    mut reader: R,
+    range: ByteRange,
+    keep_header: bool,
+) -> io::Result<impl BufRead> {
+    let start = record_boundary(&mut reader, range.start)?;
+    let end = record_boundary(&mut reader, range.end)?.max(start);
+
+    let mut header = Vec::new();
+    if keep_header && start > 0 {
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_until(b'\n', &mut header)?;
+        if !header.ends_with(b"\n") {
+            header.push(b'\n');
+        }
+    }
+
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(Cursor::new(header).chain(reader.take(end - start)))
+}
+
+/// The offset of the first record starting at or after `offset`, or the end of the feed
+fn record_boundary<R: BufRead + Seek>(reader: &mut R, offset: u64) -> io::Result<u64> {
+    if offset == 0 {
+        return Ok(0);
+    }
+    reader.seek(SeekFrom::Start(offset - 1))?;
+    let skipped = reader.read_until(b'\n', &mut Vec::new())?;
+    Ok(offset - 1 + skipped as u64)
+}
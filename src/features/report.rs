@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use super::account::{round_serialize, Account, Client};
+use super::output::{Column, OutputOptions};
+use super::store::Store;
+use super::transaction::{DisputeState, TransactionId, TransactionType};
+use csv::Writer;
+use rust_decimal::prelude::*;
+use serde::Serialize;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// How long an amount has been held for dispute
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AgeBucket {
+    #[serde(rename = "under_1d")]
+    UnderADay,
+    #[serde(rename = "1d_to_7d")]
+    UnderAWeek,
+    #[serde(rename = "7d_to_30d")]
+    UnderAMonth,
+    #[serde(rename = "over_30d")]
+    OverAMonth,
+    /// The dispute did not carry a timestamp
+    Unknown,
+}
+
+impl AgeBucket {
+    fn from_age(age_seconds: Option<u64>) -> Self {
+        match age_seconds {
+            None => Self::Unknown,
+            Some(age) if age < DAY => Self::UnderADay,
+            Some(age) if age < 7 * DAY => Self::UnderAWeek,
+            Some(age) if age < 30 * DAY => Self::UnderAMonth,
+            Some(_) => Self::OverAMonth,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct HeldAgingRow {
+    client: Client,
+    tx: TransactionId,
+    #[serde(serialize_with = "round_serialize")]
+    held: Decimal,
+    age_seconds: Option<u64>,
+    age_bucket: AgeBucket,
+}
+
+#[derive(Serialize, Debug)]
+struct LockedRow {
+    client: Client,
+    tx: Option<TransactionId>,
+    #[serde(serialize_with = "round_serialize")]
+    total: Decimal,
+}
+
+#[derive(Serialize, Debug)]
+struct BaselineDiffRow {
+    client: Client,
+    field: &'static str,
+    baseline: String,
+    actual: String,
+}
+
+#[derive(Serialize, Debug)]
+struct TransactionDumpRow {
+    client: Client,
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    /// Exactly as stored, unlike the rounded balances
+    amount: Option<Decimal>,
+    applied: bool,
+    under_dispute: bool,
+    dispute_state: DisputeState,
+    disputed_amount: Decimal,
+}
+
+#[derive(Serialize, Debug)]
+struct OpenDisputesRow {
+    client: Client,
+    #[serde(serialize_with = "round_serialize")]
+    held: Decimal,
+    /// Space separated, as CSV has no lists
+    open_disputes: String,
+}
+
+/// Writes every account still holding funds at the end of the feed, with the transactions whose
+/// disputes were never resolved or charged back, so ops can chase them.
+pub fn write_open_disputes(store: &Store, writer: impl Write) -> anyhow::Result<()> {
+    let mut open_disputes = BTreeMap::<Client, Vec<String>>::new();
+    for tx in store.transactions.values() {
+        if tx.get_is_under_dispute() {
+            open_disputes
+                .entry(tx.client_id())
+                .or_default()
+                .push(tx.transaction_id().to_string());
+        }
+    }
+
+    let mut wtr = Writer::from_writer(writer);
+    for account in store.accounts.values() {
+        if account.held().is_zero() {
+            continue;
+        }
+        wtr.serialize(OpenDisputesRow {
+            client: account.client(),
+            held: account.held(),
+            open_disputes: open_disputes
+                .get(&account.client())
+                .map(|txs| txs.join(" "))
+                .unwrap_or_default(),
+        })?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Writes, for compliance follow-up, every locked account with the transaction whose charge
+/// back locked it. `tx` is empty for an account that was already locked in a seed.
+pub fn write_locked_report(store: &Store, writer: impl Write) -> anyhow::Result<()> {
+    let mut wtr = Writer::from_writer(writer);
+
+    for account in store
+        .accounts
+        .values()
+        .filter(|account| account.is_locked())
+    {
+        wtr.serialize(LockedRow {
+            client: account.client(),
+            tx: account.locked_by(),
+            total: account.total(),
+        })?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Writes, for debugging, every transaction the store keeps for disputes to refer to, in tx order,
+/// with where it is in the dispute process. Only deposits, withdrawals and fees are kept, as
+/// nothing refers back to the others, and none of them are pruned while the feed is processed.
+pub fn write_transaction_dump(store: &Store, writer: impl Write) -> anyhow::Result<()> {
+    let mut wtr = Writer::from_writer(writer);
+
+    for tx in store.transactions.values() {
+        wtr.serialize(TransactionDumpRow {
+            client: tx.client_id(),
+            tx: tx.transaction_id(),
+            transaction_type: tx.transaction_type(),
+            amount: tx.get_amount(),
+            applied: tx.get_applied(),
+            under_dispute: tx.get_is_under_dispute(),
+            dispute_state: tx.get_dispute_state(),
+            disputed_amount: tx.get_disputed_amount(),
+        })?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Writes, for every transaction currently under dispute, how long its funds have been held
+/// as of `now` (seconds since the Unix epoch).
+pub fn write_held_aging(store: &Store, now: u64, writer: impl Write) -> anyhow::Result<()> {
+    let mut wtr = Writer::from_writer(writer);
+
+    for tx in store.transactions.values() {
+        if !tx.get_is_under_dispute() {
+            continue;
+        }
+        let age_seconds = tx
+            .get_disputed_at()
+            .map(|disputed_at| now.saturating_sub(disputed_at));
+
+        wtr.serialize(HeldAgingRow {
+            client: tx.client_id(),
+            tx: tx.transaction_id(),
+            held: tx.get_disputed_amount(),
+            age_seconds,
+            age_bucket: AgeBucket::from_age(age_seconds),
+        })?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Writes, for every client whose account differs from `baseline`, e.g. the accounts an earlier
+/// version of the engine wrote for the same feed, a row per differing field with both values.
+/// Balances are compared as `options` writes them, at each column's precision, and a client with
+/// an account on only one side differs in `account`. Returns the number of clients that differ.
+pub fn write_baseline_diff(
+    store: &Store,
+    baseline: &Store,
+    writer: impl Write,
+    options: &OutputOptions,
+) -> anyhow::Result<usize> {
+    let mut wtr = Writer::from_writer(writer);
+    let clients = store
+        .accounts
+        .keys()
+        .chain(baseline.accounts.keys())
+        .collect::<BTreeSet<_>>();
+
+    let mut differing = 0;
+    for &client in clients {
+        let fields = |account: Option<&Account>| match account {
+            Some(account) => [
+                ("account", "present".to_string()),
+                (
+                    "available",
+                    options
+                        .round(Column::Available, account.available())
+                        .to_string(),
+                ),
+                (
+                    "held",
+                    options
+                        .round(Column::Held, options.held(account))
+                        .to_string(),
+                ),
+                (
+                    "total",
+                    options.round(Column::Total, account.total()).to_string(),
+                ),
+                ("locked", account.is_locked().to_string()),
+            ],
+            None => [
+                ("account", "missing".to_string()),
+                ("available", String::new()),
+                ("held", String::new()),
+                ("total", String::new()),
+                ("locked", String::new()),
+            ],
+        };
+        let expected = baseline.accounts.get(&client);
+        let actual = store.accounts.get(&client);
+
+        let mut differs = false;
+        for ((field, baseline), (_, actual)) in fields(expected).into_iter().zip(fields(actual)) {
+            if !same_value(&baseline, &actual) {
+                wtr.serialize(BaselineDiffRow {
+                    client,
+                    field,
+                    baseline,
+                    actual,
+                })?;
+                differs = true;
+            }
+        }
+        differing += usize::from(differs);
+    }
+    wtr.flush()?;
+
+    Ok(differing)
+}
+
+/// Whether two written values are the same, amounts by value so `1.5` matches `1.5000`
+fn same_value(baseline: &str, actual: &str) -> bool {
+    match (Decimal::from_str(baseline), Decimal::from_str(actual)) {
+        (Ok(baseline), Ok(actual)) => baseline == actual,
+        _ => baseline == actual,
+    }
+}
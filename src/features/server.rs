@@ -0,0 +1,98 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::engine::Engine;
+use super::options::Options;
+use super::output::OutputOptions;
+use super::source::{CsvSource, ParseError, TransactionSource};
+use super::store::Store;
+use super::transaction::Transaction;
+use anyhow::anyhow;
+
+/// The columns of every transaction row sent to the server
+const HEADER: &str = "type, client, tx, amount";
+
+/// Asks the server for the current accounts
+const SNAPSHOT: &str = "SNAPSHOT";
+
+/// Applies transactions from every connection to `listener` to one shared engine, until
+/// accepting a connection fails.
+///
+/// A connection sends one CSV transaction row per line, e.g. `deposit, 1, 1, 1.0`, without a
+/// header (one is skipped if sent). A `SNAPSHOT` line is answered with the current accounts as
+/// CSV, followed by an empty line marking the end of the response. Rejected and malformed rows
+/// are logged and skipped, as when processing a file. A row that would stop processing a file,
+/// e.g. a repeated tx under `DuplicateTxPolicy::Reject`, is answered with an `ERROR` line giving
+/// the reason, and the connection carries on. The engine is only locked to apply a row or take
+/// a snapshot, never while reading from or writing to a connection, so a slow client holds up
+/// no other.
+pub fn serve(listener: TcpListener, store: Store, options: Options) -> anyhow::Result<()> {
+    let engine = Arc::new(Mutex::new(Engine::with_store(options, store)));
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let engine = Arc::clone(&engine);
+
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &engine) {
+                warn!("{e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, engine: &Mutex<Engine>) -> anyhow::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let lock = || engine.lock().map_err(|_| anyhow!("Engine is poisoned"));
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("type") {
+            continue;
+        }
+
+        if line == SNAPSHOT {
+            let mut accounts = Vec::new();
+            lock()?.write_accounts(&mut accounts, &OutputOptions::default())?;
+            writer.write_all(&accounts)?;
+            writer.write_all(b"\n")?;
+            continue;
+        }
+
+        let transaction = match parse_row(line) {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                warn!("{e}");
+                continue;
+            }
+        };
+        let processed = lock()?.process(Received(Some(transaction)), |_| {});
+        if let Err(e) = processed {
+            warn!("{e}");
+            writeln!(writer, "ERROR {e}")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_row(row: &str) -> Result<Transaction, ParseError> {
+    let csv = format!("{HEADER}\n{row}");
+    CsvSource::new(csv.as_bytes())
+        .next()
+        .unwrap_or_else(|| Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()))
+}
+
+/// A row received over a connection, handed to the engine as a feed of its own
+struct Received(Option<Transaction>);
+
+impl TransactionSource for Received {
+    fn next(&mut self) -> Option<Result<Transaction, ParseError>> {
+        self.0.take().map(Ok)
+    }
+}
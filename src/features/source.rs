@@ -0,0 +1,540 @@
+use std::borrow::Cow;
+use std::io::{self, BufRead, Lines, Read};
+use std::str::{self, FromStr};
+
+use super::amount::{
+    parse_amount, parse_fraction, ungroup_thousands, ungroup_underscores, AmountError,
+};
+use super::transaction::{Transaction, TransactionType};
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ParseError {
+    #[error("Unable to read input - {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Malformed CSV record - {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("Malformed JSON record - {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Malformed amount - {0}")]
+    Amount(#[from] AmountError),
+
+    #[error("Unparsable amount {0:?}, skipping the row")]
+    UnparsableAmount(String),
+
+    #[error("Header is missing the {0} column(s)")]
+    MissingColumns(String),
+
+    #[error("Malformed {column} {value:?}")]
+    MalformedField { column: &'static str, value: String },
+}
+
+impl ParseError {
+    /// Whether only the row is rejected, rather than the rest of the feed being unreadable. A
+    /// fraction with no exact value is the row's amount being unreadable, like any other.
+    pub fn is_row_rejection(&self) -> bool {
+        matches!(
+            self,
+            ParseError::UnparsableAmount(_)
+                | ParseError::Amount(
+                    AmountError::InvalidFraction(_) | AmountError::NonTerminatingFraction(_)
+                )
+        )
+    }
+}
+
+/// A feed of transactions, independent of the format they arrive in.
+/// The processing loop only pulls from this, so supporting a new feed is a matter of
+/// implementing it for that feed.
+pub trait TransactionSource {
+    /// The next transaction in the feed, or `None` once the feed is exhausted
+    fn next(&mut self) -> Option<Result<Transaction, ParseError>>;
+}
+
+/// Transactions from CSV with a `type, client, tx, amount` header. Optional columns may be left
+/// off the end of a row, and columns the engine doesn't know are ignored. A quoted field, such as
+/// a memo, may span lines, even after the padding this format allows around fields. A row
+/// repeating the header is skipped with a warning, so feeds concatenated by mistake still process.
+pub struct CsvSource<R> {
+    reader: csv::Reader<Unpadded<R>>,
+    headers: Option<csv::StringRecord>,
+    record: csv::StringRecord,
+    amounts: AmountFormat,
+    allocation_light: bool,
+    bytes: csv::ByteRecord,
+    columns: Option<Columns>,
+}
+
+impl<R: BufRead> CsvSource<R> {
+    /// The columns every feed's header must name
+    const REQUIRED_COLUMNS: [&'static str; 4] = ["type", "client", "tx", "amount"];
+
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: csv_reader(Unpadded::new(reader), csv::Trim::All),
+            headers: None,
+            record: csv::StringRecord::new(),
+            amounts: AmountFormat::default(),
+            allocation_light: false,
+            bytes: csv::ByteRecord::new(),
+            columns: None,
+        }
+    }
+
+    /// Also reads amounts written as fractions, e.g. `3/4`
+    pub fn amount_fractions(mut self, enabled: bool) -> Self {
+        self.amounts.fractions = enabled;
+        self
+    }
+
+    /// Drops any of `symbols` from the start of amounts before reading them, e.g. `$` from `$1.50`
+    pub fn strip_symbols(mut self, symbols: &str) -> Self {
+        self.amounts.strip_symbols = symbols.to_string();
+        self
+    }
+
+    /// Also reads amounts grouped with comma thousands separators, e.g. `"1,234.56"`, which must
+    /// be quoted as the comma delimits fields. See `AmountFormat::thousands_separators`
+    pub fn thousands_separators(mut self, enabled: bool) -> Self {
+        self.amounts.thousands_separators = enabled;
+        self
+    }
+
+    /// Also reads amounts grouping digits with underscores, e.g. `1_000.50`. See
+    /// `AmountFormat::strip_underscores`
+    pub fn strip_underscores(mut self, enabled: bool) -> Self {
+        self.amounts.strip_underscores = enabled;
+        self
+    }
+
+    /// Reads every row into the same buffer and takes the columns straight from it, rather than
+    /// deserializing each row, so a row without a memo or idempotency key is read without
+    /// allocating. Meant for memory-constrained ingestion; the transactions read are the same.
+    /// The CSV reader's own trimming copies every record, so fields are trimmed as they are taken
+    /// instead. Set before reading, as the reader is rebuilt.
+    pub fn allocation_light(mut self, enabled: bool) -> Self {
+        let trim = if enabled {
+            csv::Trim::None
+        } else {
+            csv::Trim::All
+        };
+        self.reader = csv_reader(self.reader.into_inner(), trim);
+        self.allocation_light = enabled;
+        self
+    }
+
+    /// Reads only the header and checks it names every column a transaction needs, without
+    /// reading any row, as a quick schema gate before a feed is processed. Columns the engine
+    /// doesn't know are allowed, as they are when processing.
+    pub fn check_header(&mut self) -> Result<(), ParseError> {
+        let headers = match &self.headers {
+            Some(headers) => headers,
+            None => self.headers.insert(self.reader.headers()?.clone()),
+        };
+        let missing = Self::REQUIRED_COLUMNS
+            .into_iter()
+            .filter(|&column| !headers.iter().any(|header| header.trim() == column))
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            return Err(ParseError::MissingColumns(missing.join(", ")));
+        }
+        Ok(())
+    }
+
+    fn next_transaction(&mut self) -> Result<Option<Transaction>, ParseError> {
+        if self.allocation_light {
+            return self.next_transaction_in_place();
+        }
+        let headers = match &self.headers {
+            Some(headers) => headers,
+            None => self.headers.insert(self.reader.headers()?.clone()),
+        };
+        loop {
+            if !self.reader.read_record(&mut self.record)? {
+                return Ok(None);
+            }
+            if !is_header(self.record.as_byte_record(), headers) {
+                break;
+            }
+            warn_repeated_header(self.record.position());
+        }
+
+        // A short row would otherwise fail on the first ignored column it is missing
+        for _ in self.record.len()..headers.len() {
+            self.record.push_field("");
+        }
+
+        if let Some(index) = headers.iter().position(|header| header == "amount") {
+            let field = &self.record[index];
+            if let Cow::Owned(value) = self.amounts.text(field)? {
+                self.record = self
+                    .record
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| if i == index { value.as_str() } else { field })
+                    .collect();
+            }
+        }
+        Ok(Some(self.record.deserialize(Some(headers))?))
+    }
+
+    /// See `allocation_light`
+    fn next_transaction_in_place(&mut self) -> Result<Option<Transaction>, ParseError> {
+        let headers = match &self.headers {
+            Some(headers) => headers,
+            None => self.headers.insert(self.reader.headers()?.clone()),
+        };
+        let columns = *self.columns.get_or_insert_with(|| Columns::of(headers));
+        loop {
+            if !self.reader.read_byte_record(&mut self.bytes)? {
+                return Ok(None);
+            }
+            if !is_header(&self.bytes, headers) {
+                break;
+            }
+            warn_repeated_header(self.bytes.position());
+        }
+
+        let row = &self.bytes;
+        let name = field(row, columns.transaction_type, "type")?;
+        let transaction_type =
+            TransactionType::from_name(name).ok_or_else(|| malformed("type", name.as_bytes()))?;
+        let client = parsed::<u16>(row, columns.client, "client")?
+            .ok_or_else(|| malformed("client", b""))?;
+
+        let amount = match self.amounts.text(field(row, columns.amount, "amount")?)? {
+            text if text.is_empty() => None,
+            text => Some(
+                parse_amount(&text)
+                    .ok_or_else(|| ParseError::UnparsableAmount(text.to_string()))?,
+            ),
+        };
+        let text = |column, name| -> Result<Option<String>, ParseError> {
+            let text = field(row, column, name)?;
+            Ok((!text.is_empty()).then(|| text.to_string()))
+        };
+
+        Ok(Some(Transaction::from_row(
+            transaction_type,
+            client.into(),
+            parsed::<u64>(row, columns.transaction_id, "tx")?.map(Into::into),
+            amount,
+            parsed(row, columns.timestamp, "timestamp")?,
+            text(columns.memo, "memo")?,
+            text(columns.idempotency_key, "idempotency_key")?,
+        )))
+    }
+}
+
+fn csv_reader<R: BufRead>(reader: Unpadded<R>, trim: csv::Trim) -> csv::Reader<Unpadded<R>> {
+    csv::ReaderBuilder::new()
+        .has_headers(true)
+        .delimiter(b',')
+        .trim(trim)
+        .flexible(true)
+        .from_reader(reader)
+}
+
+/// Whether `record` repeats the header, ignoring case and padding
+fn is_header(record: &csv::ByteRecord, headers: &csv::StringRecord) -> bool {
+    record.len() == headers.len()
+        && record
+            .iter()
+            .zip(headers.as_byte_record())
+            .all(|(field, header)| field.trim_ascii().eq_ignore_ascii_case(header.trim_ascii()))
+}
+
+fn warn_repeated_header(position: Option<&csv::Position>) {
+    warn!(
+        "Skipping the header repeated on line {}, as in feeds concatenated by mistake",
+        position.map_or(0, csv::Position::line)
+    );
+}
+
+/// Where each column the engine reads sits in the header, for `CsvSource::allocation_light`
+#[derive(Debug, Clone, Copy)]
+struct Columns {
+    transaction_type: Option<usize>,
+    client: Option<usize>,
+    transaction_id: Option<usize>,
+    amount: Option<usize>,
+    timestamp: Option<usize>,
+    memo: Option<usize>,
+    idempotency_key: Option<usize>,
+}
+
+impl Columns {
+    fn of(headers: &csv::StringRecord) -> Self {
+        let find = |name: &str| headers.iter().position(|header| header.trim() == name);
+        Self {
+            transaction_type: find("type"),
+            client: find("client"),
+            transaction_id: find("tx"),
+            amount: find("amount"),
+            timestamp: find("timestamp"),
+            memo: find("memo"),
+            idempotency_key: find("idempotency_key"),
+        }
+    }
+}
+
+/// The trimmed text of `row` in `column`, empty when the header has no such column or the row is
+/// short
+fn field<'r>(
+    row: &'r csv::ByteRecord,
+    column: Option<usize>,
+    name: &'static str,
+) -> Result<&'r str, ParseError> {
+    let bytes = column.and_then(|index| row.get(index)).unwrap_or_default();
+    str::from_utf8(bytes)
+        .map(str::trim)
+        .map_err(|_| malformed(name, bytes))
+}
+
+/// The value of `row` in `column`, or `None` if it is empty
+fn parsed<T: FromStr>(
+    row: &csv::ByteRecord,
+    column: Option<usize>,
+    name: &'static str,
+) -> Result<Option<T>, ParseError> {
+    match field(row, column, name)? {
+        "" => Ok(None),
+        text => text
+            .parse()
+            .map(Some)
+            .map_err(|_| malformed(name, text.as_bytes())),
+    }
+}
+
+fn malformed(column: &'static str, value: &[u8]) -> ParseError {
+    ParseError::MalformedField {
+        column,
+        value: String::from_utf8_lossy(value).into_owned(),
+    }
+}
+
+impl<R: BufRead> TransactionSource for CsvSource<R> {
+    fn next(&mut self) -> Option<Result<Transaction, ParseError>> {
+        self.next_transaction().transpose()
+    }
+}
+
+/// Drops the spaces and tabs padding fields, before the CSV parser sees them. The parser only
+/// recognises a quote at the very start of a field, so `1.0, "memo"` would otherwise read the
+/// quotes literally, and a quoted line break in it would end the record.
+struct Unpadded<R> {
+    inner: R,
+    field: Field,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+/// Where `Unpadded` is in the field being read
+#[derive(Debug, Default)]
+struct Field {
+    state: FieldState,
+    /// Spaces seen in an unquoted field, kept only if more of the field follows
+    padding: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum FieldState {
+    #[default]
+    Start,
+    Unquoted,
+    Quoted,
+    AfterQuote,
+}
+
+impl<R: BufRead> Unpadded<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            field: Field::default(),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Field {
+    /// Appends `byte` to `buf` unless it pads the field
+    fn push(&mut self, byte: u8, buf: &mut Vec<u8>) {
+        use FieldState::*;
+
+        let next = match (self.state, byte) {
+            (Quoted, b'"') => AfterQuote,
+            (Quoted, _) => Quoted,
+            (_, b',' | b'\n' | b'\r') => {
+                self.padding = 0;
+                Start
+            }
+            (Start | AfterQuote, b' ' | b'\t') => return,
+            (Unquoted, b' ' | b'\t') => {
+                self.padding += 1;
+                return;
+            }
+            (Start | AfterQuote, b'"') => Quoted,
+            (Start | Unquoted | AfterQuote, _) => {
+                buf.extend((0..self.padding).map(|_| b' '));
+                self.padding = 0;
+                Unquoted
+            }
+        };
+        self.state = next;
+        buf.push(byte);
+    }
+}
+
+impl<R: BufRead> Read for Unpadded<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+
+            let bytes = self.inner.fill_buf()?;
+            if bytes.is_empty() {
+                return Ok(0);
+            }
+            for &byte in bytes {
+                self.field.push(byte, &mut self.buf);
+            }
+            let len = bytes.len();
+            self.inner.consume(len);
+        }
+
+        let read = (&self.buf[self.pos..]).read(out)?;
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+/// Transactions from newline-delimited JSON, one object per line, e.g.
+/// `{"type": "deposit", "client": 1, "tx": 1, "amount": 1.0}`. Blank lines are skipped.
+/// Numeric amounts are read from their source text, so they are as exact as string amounts.
+pub struct JsonLinesSource<R> {
+    lines: Lines<R>,
+    amounts: AmountFormat,
+}
+
+impl<R: BufRead> JsonLinesSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            amounts: AmountFormat::default(),
+        }
+    }
+
+    /// Also reads string amounts written as fractions, e.g. `"3/4"`
+    pub fn amount_fractions(mut self, enabled: bool) -> Self {
+        self.amounts.fractions = enabled;
+        self
+    }
+
+    /// Drops any of `symbols` from the start of string amounts before reading them, e.g. `$`
+    /// from `"$1.50"`
+    pub fn strip_symbols(mut self, symbols: &str) -> Self {
+        self.amounts.strip_symbols = symbols.to_string();
+        self
+    }
+
+    /// Also reads string amounts grouped with comma thousands separators, e.g. `"1,234.56"`
+    pub fn thousands_separators(mut self, enabled: bool) -> Self {
+        self.amounts.thousands_separators = enabled;
+        self
+    }
+
+    /// Also reads string amounts grouping digits with underscores, e.g. `"1_000.50"`
+    pub fn strip_underscores(mut self, enabled: bool) -> Self {
+        self.amounts.strip_underscores = enabled;
+        self
+    }
+}
+
+impl<R: BufRead> TransactionSource for JsonLinesSource<R> {
+    fn next(&mut self) -> Option<Result<Transaction, ParseError>> {
+        for line in self.lines.by_ref() {
+            match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => return Some(self.parse_transaction(&line)),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        None
+    }
+}
+
+impl<R> JsonLinesSource<R> {
+    fn parse_transaction(&self, line: &str) -> Result<Transaction, ParseError> {
+        let mut record: Value = serde_json::from_str(line)?;
+        if let Some(amount) = record.get_mut("amount") {
+            match amount {
+                Value::Number(number) => *amount = Value::String(number.to_string()),
+                Value::String(text) => {
+                    if let Cow::Owned(value) = self.amounts.text(text)? {
+                        *text = value;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(serde_json::from_value(record)?)
+    }
+}
+
+/// How amounts are written in a feed, beyond plain decimals
+#[derive(Debug, Default)]
+struct AmountFormat {
+    /// Amounts may be fractions, e.g. `3/4`
+    fractions: bool,
+
+    /// Characters dropped from the start of amounts, e.g. `$`
+    strip_symbols: String,
+
+    /// Amounts may group digits with commas, e.g. `1,234.56`. A comma is only ever read as a
+    /// thousands separator, never as a decimal comma, and only between groups of three digits,
+    /// so a decimal comma such as `1,5` is still unparsable rather than read as `15`.
+    thousands_separators: bool,
+
+    /// Amounts may group digits with underscores, as human-edited feeds sometimes do, e.g.
+    /// `1_000.50`. Only an underscore between two digits is dropped, so `1__000` or `_1` is
+    /// still unparsable, as is any underscore when this is off.
+    strip_underscores: bool,
+}
+
+impl AmountFormat {
+    /// The amount `field` holds as decimal text, borrowed if nothing changed. An amount that
+    /// still can't be read only rejects its row, see `ParseError::is_row_rejection`.
+    fn text<'a>(&self, field: &'a str) -> Result<Cow<'a, str>, ParseError> {
+        let mut text = Cow::Borrowed(field.trim_start_matches(|c| self.strip_symbols.contains(c)));
+        if self.thousands_separators {
+            if let Some(ungrouped) = ungroup_thousands(&text) {
+                text = Cow::Owned(ungrouped);
+            }
+        }
+        if self.strip_underscores {
+            if let Some(ungrouped) = ungroup_underscores(&text) {
+                text = Cow::Owned(ungrouped);
+            }
+        }
+        if self.fractions {
+            if let Some(value) = parse_fraction(&text)? {
+                return Ok(Cow::Owned(value.to_string()));
+            }
+        }
+        if !text.is_empty() && parse_amount(&text).is_none() {
+            return Err(ParseError::UnparsableAmount(field.to_string()));
+        }
+
+        match text {
+            Cow::Borrowed(text) if text.len() == field.len() => Ok(Cow::Borrowed(field)),
+            text => Ok(Cow::Owned(text.into_owned())),
+        }
+    }
+}
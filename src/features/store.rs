@@ -1,22 +1,159 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use super::{
-    account::{Account, Client},
-    transaction::{Transaction, TransactionId},
+    account::{Account, AccountSnapshot, Client},
+    transaction::{DisputeState, IdempotencyKey, Transaction, TransactionId, TransactionType},
 };
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum StoreError {
+    #[error("Cannot merge stores - client {0} has a different account in each")]
+    ConflictingAccount(Client),
+
+    #[error("Cannot merge stores - transaction {0} is in both")]
+    ConflictingTransaction(TransactionId),
+
+    #[error("Unbalanced - accounts total {accounts}, but the transactions applied add up to {transactions}")]
+    Unbalanced {
+        accounts: Decimal,
+        transactions: Decimal,
+    },
+}
 
 /// This keeps track of users' account aggregation, deposits and withdrawals
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Store {
+    /// Ordered by client, which is the order `write_accounts` writes them in without sorting.
+    /// Swapping it for a hashed map would need the output sorted first to stay deterministic.
     pub(crate) accounts: BTreeMap<Client, Account>,
     pub transactions: BTreeMap<TransactionId, Transaction>,
+
+    /// The memo of each client's latest applied transaction, if it had one
+    pub(crate) memos: BTreeMap<Client, String>,
+
+    /// How many disputes were raised, to order them
+    pub(crate) disputes_raised: u64,
+
+    /// The transactions applied, see `Options::idempotent`
+    pub(crate) applied_keys: BTreeSet<IdempotencyKey>,
 }
 
 impl Store {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             accounts: BTreeMap::new(),
             transactions: BTreeMap::new(),
+            memos: BTreeMap::new(),
+            disputes_raised: 0,
+            applied_keys: BTreeSet::new(),
+        }
+    }
+
+    /// The clients that have an account, in id order
+    ///
+    /// ```
+    /// use payment_engine::{process_transactions, Client, CsvSource, Options, Store};
+    ///
+    /// let feed = "type, client, tx, amount
+    /// deposit, 2, 1, 1.0
+    /// deposit, 1, 2, 2.0";
+    /// let store = process_transactions(
+    ///     CsvSource::new(feed.as_bytes()),
+    ///     Store::new(),
+    ///     &Options::default(),
+    /// )?;
+    ///
+    /// assert_eq!(store.clients().collect::<Vec<_>>(), [Client::from(1), Client::from(2)]);
+    /// assert!(store.contains_client(Client::from(2)));
+    /// assert!(!store.contains_client(Client::from(3)));
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn clients(&self) -> impl Iterator<Item = Client> + '_ {
+        self.accounts.keys().copied()
+    }
+
+    /// The state of every account, in client id order
+    pub fn snapshot(&self) -> Vec<AccountSnapshot> {
+        self.accounts.values().map(AccountSnapshot::from).collect()
+    }
+
+    /// The client's account, if it has one
+    pub fn account(&self, client: Client) -> Option<Account> {
+        self.accounts.get(&client).copied()
+    }
+
+    /// Whether the client has an account
+    pub fn contains_client(&self, client: Client) -> bool {
+        self.accounts.contains_key(&client)
+    }
+
+    /// Checks that the accounts add up to what the transactions applied to them moved: deposits
+    /// minus withdrawals minus deposits' charge backs, plus the reversals of disputed or charged
+    /// back withdrawals. A failure means the engine or the store is broken,
+    /// not the feed. Only meaningful for a store built from transactions alone, not from a seed,
+    /// whose opening balances have no transactions behind them.
+    pub fn check_balanced(&self) -> Result<(), StoreError> {
+        let accounts = self.accounts.values().map(Account::total).sum::<Decimal>();
+        let transactions = self
+            .transactions
+            .values()
+            .filter(|tx| tx.get_applied())
+            .map(|tx| {
+                let amount = tx.get_amount().unwrap_or_default();
+                let moved = match tx.transaction_type() {
+                    TransactionType::Withdrawal | TransactionType::PercentageFee => -amount,
+                    _ => amount,
+                };
+                match (tx.transaction_type(), tx.get_dispute_state()) {
+                    (
+                        TransactionType::Withdrawal,
+                        DisputeState::Disputed | DisputeState::ChargedBack,
+                    ) => moved + tx.get_disputed_amount(),
+                    (_, DisputeState::ChargedBack) => moved - tx.get_disputed_amount(),
+                    _ => moved,
+                }
+            })
+            .sum::<Decimal>();
+
+        if accounts != transactions {
+            return Err(StoreError::Unbalanced {
+                accounts,
+                transactions,
+            });
+        }
+        Ok(())
+    }
+
+    /// Combines the accounts, transactions and memos of `other`, e.g. the store of another shard,
+    /// into this one. Shards split by client never overlap; a client whose account differs
+    /// between the two, or a transaction id in both, is an error, and then neither store is
+    /// merged into.
+    pub fn merge(&mut self, other: Store) -> Result<(), StoreError> {
+        for (client, account) in &other.accounts {
+            if self
+                .accounts
+                .get(client)
+                .is_some_and(|existing| existing != account)
+            {
+                return Err(StoreError::ConflictingAccount(*client));
+            }
+        }
+        if let Some(&transaction_id) = other
+            .transactions
+            .keys()
+            .find(|transaction_id| self.transactions.contains_key(transaction_id))
+        {
+            return Err(StoreError::ConflictingTransaction(transaction_id));
         }
+
+        self.accounts.extend(other.accounts);
+        self.transactions.extend(other.transactions);
+        self.memos.extend(other.memos);
+        self.disputes_raised = self.disputes_raised.max(other.disputes_raised);
+        self.applied_keys.extend(other.applied_keys);
+        Ok(())
     }
 }
@@ -0,0 +1,88 @@
+use std::io::Write;
+
+use super::account::{Account, Client};
+use super::output::json_number;
+use super::processing::Event;
+use super::transaction::{TransactionId, TransactionType};
+use serde::Serialize;
+use serde_json::Number;
+
+#[derive(Serialize, Debug)]
+struct TraceEvent {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    tx: TransactionId,
+    amount: Option<Number>,
+    applied: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    available: Number,
+    held: Number,
+    total: Number,
+    locked: bool,
+}
+
+/// Every row of one client's feed as JSON Lines, applied or not, with the client's balances
+/// after it, for tools that step through an account's history. Balances are rounded to the
+/// accounts' balance precision; amounts are as given.
+pub struct ClientTrace<W: Write> {
+    client: Client,
+    precision: u32,
+    writer: W,
+    error: Option<serde_json::Error>,
+}
+
+impl<W: Write> ClientTrace<W> {
+    /// Traces `client`'s rows with balances rounded to `precision` places, see
+    /// `OutputOptions::balance_precision`
+    pub fn new(client: Client, precision: u32, writer: W) -> Self {
+        Self {
+            client,
+            precision,
+            writer,
+            error: None,
+        }
+    }
+
+    /// Writes a line if the event's transaction is the traced client's. Meant to be handed every
+    /// event of a run, in order. Once a write fails nothing more is written; `finish` reports
+    /// the failure.
+    pub fn record(&mut self, event: &Event) {
+        let transaction = event.transaction;
+        if self.error.is_some() || transaction.client_id() != self.client {
+            return;
+        }
+
+        if let Err(e) = self.write(event) {
+            self.error = Some(e);
+        }
+    }
+
+    fn write(&mut self, event: &Event) -> serde_json::Result<()> {
+        let transaction = event.transaction;
+        let after = event.after.unwrap_or_else(|| Account::new(self.client));
+        let line = TraceEvent {
+            transaction_type: transaction.transaction_type(),
+            tx: transaction.transaction_id(),
+            amount: transaction.get_amount().map(json_number).transpose()?,
+            applied: event.result.is_ok(),
+            error: event.result.as_ref().err().map(ToString::to_string),
+            available: json_number(after.available().round_dp(self.precision))?,
+            held: json_number(after.held().round_dp(self.precision))?,
+            total: json_number(after.total().round_dp(self.precision))?,
+            locked: after.is_locked(),
+        };
+
+        serde_json::to_writer(&mut self.writer, &line)?;
+        self.writer.write_all(b"\n").map_err(serde_json::Error::io)
+    }
+
+    /// Flushes the trace, failing if any line could not be written
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e.into());
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
@@ -1,14 +1,17 @@
-use super::account::{Account, AccountError, Client};
+use super::account::{Account, AccountError, Client, ErrorCategory};
+use super::amount::{deserialize_amount, Amount};
+use super::options::{DuplicateTxPolicy, Options, RedisputePolicy};
 use super::store::Store;
-use anyhow::Context;
 use rust_decimal::prelude::*;
 use rust_decimal_macros::dec;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::fmt;
 use thiserror::Error;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
-pub(crate) enum TransactionType {
+#[non_exhaustive]
+pub enum TransactionType {
     ///  A deposit is a credit to the client's asset account, meaning it should increase the available and total funds of the client account
     Deposit,
 
@@ -43,26 +46,307 @@ pub(crate) enum TransactionType {
     /// specify an amount. Like a resolve, if the tx specified doesn't exist, or the tx isn't under dispute,
     /// you can ignore chargeback and assume this is an error on our partner's side.
     Chargeback,
+
+    /// A fee of a percentage of the client's available funds, given in the amount column from 0
+    /// to 100. The fee is computed exactly and truncated to four decimal places, and nothing is
+    /// charged on negative available funds. Once applied it is kept as a debit of the fee it
+    /// charged. A fee can't be disputed.
+    #[serde(rename = "percentage_fee")]
+    PercentageFee,
+}
+
+impl TransactionType {
+    const NAMES: [&'static str; 6] = [
+        "deposit",
+        "withdrawal",
+        "dispute",
+        "resolve",
+        "chargeback",
+        "percentage_fee",
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::PercentageFee => "percentage_fee",
+        }
+    }
+
+    /// The type named `name`, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        use TransactionType::*;
+
+        [
+            Deposit,
+            Withdrawal,
+            Dispute,
+            Resolve,
+            Chargeback,
+            PercentageFee,
+        ]
+        .into_iter()
+        .find(|transaction_type| transaction_type.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Types are matched ignoring case, since some partners capitalize them (`Deposit`, `WITHDRAWAL`)
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        Self::from_name(&name).ok_or_else(|| de::Error::unknown_variant(&name, &Self::NAMES))
+    }
+}
+
+/// Where a transaction is in the dispute process: `Active → Disputed → {Resolved | ChargedBack}`,
+/// with a resolved transaction able to be disputed again. A transaction is in at most one dispute
+/// at a time and a charge back is final; any other step is a partner error and is rejected.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    /// The transaction has never been disputed
+    #[default]
+    Active,
+
+    /// The transaction's funds are held until the dispute is resolved or charged back
+    Disputed,
+
+    /// The dispute was withdrawn and the funds released. The transaction can be disputed again
+    Resolved,
+
+    /// The transaction was reversed. Nothing further can happen to it
+    ChargedBack,
+}
+
+impl DisputeState {
+    /// The state after a dispute is raised, if one can be raised from this state
+    pub(crate) fn dispute(self) -> Option<Self> {
+        use DisputeState::*;
+
+        match self {
+            Active | Resolved => Some(Disputed),
+            Disputed | ChargedBack => None,
+        }
+    }
+
+    /// The state after the dispute is resolved, if the transaction is under dispute
+    pub(crate) fn resolve(self) -> Option<Self> {
+        use DisputeState::*;
+
+        match self {
+            Disputed => Some(Resolved),
+            Active | Resolved | ChargedBack => None,
+        }
+    }
+
+    /// The state after the dispute is charged back, if the transaction is under dispute
+    pub(crate) fn charge_back(self) -> Option<Self> {
+        use DisputeState::*;
+
+        match self {
+            Disputed => Some(ChargedBack),
+            Active | Resolved | ChargedBack => None,
+        }
+    }
 }
 
+/// Why a transaction was rejected. More variants will be added as the engine grows, so matches
+/// outside this crate need a wildcard arm; the accessors cover the common questions without one.
+///
+/// ```
+/// use payment_engine::TransactionError;
+///
+/// fn describe(error: &TransactionError) -> String {
+///     match error {
+///         TransactionError::NegativeDeposit(amount) => format!("negative deposit of {amount}"),
+///         TransactionError::NegativeWithdrawal(amount) => format!("negative withdrawal of {amount}"),
+///         _ => error.to_string(),
+///     }
+/// }
+/// ```
+///
+/// Without the wildcard arm, the match does not compile:
+///
+/// ```compile_fail
+/// use payment_engine::TransactionError;
+///
+/// fn describe(error: &TransactionError) -> &'static str {
+///     match error {
+///         TransactionError::AccountError(_) => "account",
+///         TransactionError::InvalidAmount(_) => "invalid amount",
+///         TransactionError::NegativeDeposit(_) => "negative deposit",
+///         TransactionError::NegativeWithdrawal(_) => "negative withdrawal",
+///         TransactionError::ClientLimitReached { .. } => "client limit",
+///         TransactionError::Unknown(_) => "unknown",
+///     }
+/// }
+/// ```
 #[derive(Error, Debug)]
-pub(crate) enum TransactionError {
+#[non_exhaustive]
+pub enum TransactionError {
     #[error("Invalid transaction - {0}")]
     AccountError(#[from] AccountError),
 
     #[error("Invalid input - {0}")]
     InvalidAmount(Decimal),
 
+    #[error("Invalid input - negative amount {0} on deposit")]
+    NegativeDeposit(Decimal),
+
+    #[error("Invalid input - negative amount {0} on withdrawal")]
+    NegativeWithdrawal(Decimal),
+
+    #[error("Invalid input - fee of {0}% is outside 0% to 100%")]
+    InvalidPercentage(Decimal),
+
+    #[error("Invalid input - {transaction_type} {transaction_id} has no amount")]
+    MissingAmount {
+        transaction_type: TransactionType,
+        transaction_id: TransactionId,
+    },
+
+    #[error("Invalid input - amount {amount} on {transaction_type} {transaction_id} exceeds the limit of {limit}")]
+    AmountExceedsLimit {
+        transaction_type: TransactionType,
+        transaction_id: TransactionId,
+        amount: Decimal,
+        limit: Decimal,
+    },
+
+    #[error("Client limit of {limit} reached, rejecting transaction for new client {client}")]
+    ClientLimitReached { client: Client, limit: usize },
+
+    #[error("Client {0} is on the deny list")]
+    DeniedClient(Client),
+
+    #[error("Transaction {transaction_id} rejected by a validator - {reason}")]
+    Rejected {
+        transaction_id: TransactionId,
+        reason: String,
+    },
+
+    #[error(
+        "Transaction id {transaction_id} is not greater than the earlier transaction id {previous}"
+    )]
+    NonMonotonicTransaction {
+        transaction_id: TransactionId,
+        previous: TransactionId,
+    },
+
+    #[error("Duplicate transaction id {0}")]
+    DuplicateTransaction(TransactionId),
+
+    #[error("Invalid input - {transaction_type} for client {client} has no tx")]
+    MissingTransactionId {
+        transaction_type: TransactionType,
+        client: Client,
+    },
+
+    #[error("Client {0} has no open dispute to settle")]
+    NoOpenDispute(Client),
+
+    #[error("Transaction {0} was already applied, ignoring the redelivery")]
+    Replayed(TransactionId),
+
     #[error("Unknown")]
     Unknown(#[from] anyhow::Error),
 }
 
+impl TransactionError {
+    /// The account error behind the rejection, if the account refused the transaction
+    pub fn account_error(&self) -> Option<&AccountError> {
+        match self {
+            TransactionError::AccountError(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// The amount that was rejected, if the transaction was rejected for its amount
+    pub fn amount(&self) -> Option<Decimal> {
+        match self {
+            TransactionError::InvalidAmount(amount)
+            | TransactionError::NegativeDeposit(amount)
+            | TransactionError::NegativeWithdrawal(amount)
+            | TransactionError::InvalidPercentage(amount)
+            | TransactionError::AmountExceedsLimit { amount, .. } => Some(*amount),
+            _ => None,
+        }
+    }
+
+    /// Who the rejection is down to
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            TransactionError::AccountError(e) => e.category(),
+            TransactionError::AmountExceedsLimit { .. }
+            | TransactionError::DeniedClient(_)
+            | TransactionError::Rejected { .. } => ErrorCategory::ClientFault,
+            TransactionError::InvalidAmount(_)
+            | TransactionError::NegativeDeposit(_)
+            | TransactionError::NegativeWithdrawal(_)
+            | TransactionError::InvalidPercentage(_)
+            | TransactionError::MissingAmount { .. }
+            | TransactionError::NonMonotonicTransaction { .. }
+            | TransactionError::DuplicateTransaction(_)
+            | TransactionError::MissingTransactionId { .. }
+            | TransactionError::NoOpenDispute(_)
+            | TransactionError::Replayed(_) => ErrorCategory::PartnerError,
+            TransactionError::ClientLimitReached { .. } | TransactionError::Unknown(_) => {
+                ErrorCategory::SystemError
+            }
+        }
+    }
+}
+
 type TransactionResult<T> = anyhow::Result<T, TransactionError>;
 
+/// Identifies one delivery of a transaction, see `Options::idempotent`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum IdempotencyKey {
+    /// The key the partner gave the transaction
+    Given(String),
+
+    /// The transaction's fields, for one given no key
+    Derived {
+        transaction_type: TransactionType,
+        client: Client,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+
+        /// For a dispute, resolve or charge back, which dispute of the transaction it belongs
+        /// to, so a dispute raised again after a resolve isn't taken for a redelivery
+        dispute: Option<u64>,
+    },
+}
+
+/// 64 bits wide, as a cumulative feed runs past the 4.3 billion ids 32 bits allow
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
-pub struct TransactionId(u32);
+pub struct TransactionId(u64);
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+impl From<u64> for TransactionId {
+    fn from(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     #[serde(rename = "type")]
     transaction_type: TransactionType,
@@ -71,18 +355,101 @@ pub struct Transaction {
     #[serde(rename = "client")]
     client: Client,
 
-    /// Globally Unique but not guaranteed to be ordered
-    #[serde(rename = "tx")]
-    transaction_id: TransactionId,
+    /// Globally Unique but not guaranteed to be ordered. Only a resolve or charge back may leave
+    /// it out, see `Options::fifo_disputes`
+    #[serde(rename = "tx", default)]
+    transaction_id: Option<TransactionId>,
 
-    /// Four decimal places
+    /// Exactly as read, at its original scale. Only output is rounded, never the stored amount,
+    /// so a dispute holds exactly what was deposited however the balances are written.
+    #[serde(default, deserialize_with = "deserialize_amount")]
     amount: Option<Decimal>,
 
+    /// Seconds since the Unix epoch at which the partner recorded the transaction. Optional
+    #[serde(default)]
+    timestamp: Option<u64>,
+
+    /// Free text from the partner, kept for reconciliation. Optional
+    #[serde(default)]
+    memo: Option<String>,
+
+    /// Identifies the delivery of the transaction, so a redelivery can be ignored, see
+    /// `Options::idempotent`. Optional
+    #[serde(default)]
+    idempotency_key: Option<String>,
+
+    #[serde(skip)]
+    dispute_state: DisputeState,
+
+    /// Timestamp of the dispute currently holding this transaction's funds, if it carried one
     #[serde(skip)]
-    is_under_dispute: bool,
+    disputed_at: Option<u64>,
+
+    /// How much of the amount the current dispute holds
+    #[serde(skip)]
+    disputed_amount: Decimal,
+
+    /// When the current dispute was raised relative to the store's other disputes, to find the
+    /// oldest one still open
+    #[serde(skip)]
+    dispute_seq: u64,
+
+    /// Whether the transaction moved any funds. A refused withdrawal is still stored
+    #[serde(skip)]
+    applied: bool,
 }
 
 impl Transaction {
+    /// An applied deposit or withdrawal as a ledger recorded it, see `replay_ledger`
+    pub(crate) fn replayed(
+        transaction_type: TransactionType,
+        client: Client,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Self {
+        Self {
+            transaction_type,
+            client,
+            transaction_id: Some(transaction_id),
+            amount: Some(amount),
+            timestamp: None,
+            memo: None,
+            idempotency_key: None,
+            dispute_state: DisputeState::Active,
+            disputed_at: None,
+            disputed_amount: Decimal::ZERO,
+            dispute_seq: 0,
+            applied: true,
+        }
+    }
+
+    /// A transaction as read from the columns of a row, without going through `Deserialize`,
+    /// see `CsvSource::allocation_light`
+    pub(crate) fn from_row(
+        transaction_type: TransactionType,
+        client: Client,
+        transaction_id: Option<TransactionId>,
+        amount: Option<Decimal>,
+        timestamp: Option<u64>,
+        memo: Option<String>,
+        idempotency_key: Option<String>,
+    ) -> Self {
+        Self {
+            transaction_type,
+            client,
+            transaction_id,
+            amount,
+            timestamp,
+            memo,
+            idempotency_key,
+            dispute_state: DisputeState::Active,
+            disputed_at: None,
+            disputed_amount: Decimal::ZERO,
+            dispute_seq: 0,
+            applied: false,
+        }
+    }
+
     pub fn find_by_id(
         transaction_id: TransactionId,
         store: &mut Store,
@@ -90,43 +457,369 @@ impl Transaction {
         store.transactions.get_mut(&transaction_id)
     }
 
-    pub(crate) fn save(self, store: &mut Store) -> TransactionResult<()> {
+    /// Gives a resolve or charge back without a tx the id of its client's oldest open dispute,
+    /// when `Options::fifo_disputes` allows leaving it out. Any other transaction without a tx is
+    /// rejected. Found by scanning the stored transactions, as only feeds leaving ids out pay
+    /// for it.
+    pub(crate) fn assign_oldest_dispute(
+        self,
+        store: &Store,
+        options: &Options,
+    ) -> TransactionResult<Self> {
         use TransactionType::*;
 
-        if let Some(amount) = self.amount {
-            if amount < dec!(0) {
-                return Err(TransactionError::InvalidAmount(amount));
+        if self.transaction_id.is_some() {
+            return Ok(self);
+        }
+        match self.transaction_type {
+            Resolve | Chargeback if options.fifo_disputes => {
+                let oldest = store
+                    .transactions
+                    .values()
+                    .filter(|tx| tx.client == self.client && tx.get_is_under_dispute())
+                    .min_by_key(|tx| tx.dispute_seq)
+                    .ok_or(TransactionError::NoOpenDispute(self.client))?;
+                Ok(Self {
+                    transaction_id: oldest.transaction_id,
+                    ..self
+                })
+            }
+            transaction_type => Err(TransactionError::MissingTransactionId {
+                transaction_type,
+                client: self.client,
+            }),
+        }
+    }
+
+    /// Hands a dispute, resolve or charge back to the client owning the transaction it refers
+    /// to, when the two ids are aliases of one client under `Options::client_aliases`
+    pub(crate) fn assign_aliased_owner(self, store: &Store, options: &Options) -> Self {
+        use TransactionType::*;
+
+        let owner = match self.transaction_type {
+            Dispute | Resolve | Chargeback => store
+                .transactions
+                .get(&self.transaction_id())
+                .map(|tx| tx.client),
+            Deposit | Withdrawal | PercentageFee => None,
+        };
+        match owner {
+            Some(owner) if owner != self.client && options.is_same_client(owner, self.client) => {
+                Self {
+                    client: owner,
+                    ..self
+                }
             }
+            _ => self,
+        }
+    }
+
+    /// Applies the transaction to its client's account. Once applied, its memo becomes the
+    /// client's latest memo; it isn't kept on the stored transaction. A deposit or withdrawal
+    /// repeating a stored id is handled by `Options::duplicate_tx_policy`.
+    pub(crate) fn save(mut self, store: &mut Store, options: &Options) -> TransactionResult<()> {
+        use TransactionType::*;
+
+        if options.deny_list.contains(&self.client) {
+            return Err(TransactionError::DeniedClient(self.client));
         }
 
-        if let Deposit | Withdrawal = self.transaction_type {
-            store.transactions.insert(self.transaction_id, self);
+        // Brought to the ingest precision first, so the checks and the idempotency key see the
+        // amount that is applied. A fee's amount is a percent, its fee is rounded once charged
+        if self.transaction_type != PercentageFee {
+            self.amount = self.amount.map(|amount| options.ingest(amount));
         }
 
-        self.update_account(store)?;
+        let key = options.idempotent.then(|| self.idempotency_key(store));
+        if key
+            .as_ref()
+            .is_some_and(|key| store.applied_keys.contains(key))
+        {
+            return Err(TransactionError::Replayed(self.transaction_id()));
+        }
+
+        let memo = self.memo.take();
+        let transaction = self.apply_options(options);
+        transaction.validate()?;
+        transaction.check_amount_limit(options)?;
+        transaction.check_client_limit(store, options)?;
+        transaction.check_redispute(store, options)?;
+        let transaction = transaction.charge_percentage(store, options);
+        let transaction = match transaction.transaction_type {
+            PercentageFee => Self {
+                amount: transaction.amount.map(|amount| options.ingest(amount)),
+                ..transaction
+            },
+            _ => transaction,
+        };
+
+        match transaction.find_original(store) {
+            Some(original) if options.duplicate_tx_policy == DuplicateTxPolicy::Last => {
+                let mut account =
+                    Account::find_or_new_by_client(transaction.client, store, options);
+                account.correct(&original, &transaction, store)?;
+                if let Some(scale) = options.working_scale {
+                    account.round_to_scale(scale, store);
+                }
+                Self {
+                    applied: true,
+                    ..transaction.clone()
+                }
+                .update(store);
+            }
+            Some(_) => {
+                return Err(TransactionError::DuplicateTransaction(
+                    transaction.transaction_id(),
+                ))
+            }
+            None => {
+                // Only a new id can break the ordering; a repeat is the duplicate policy's
+                transaction.check_monotonic(store, options)?;
+                let result = transaction.update_account(store, options);
+                if let Deposit | Withdrawal | PercentageFee = transaction.transaction_type {
+                    let applied = result.is_ok();
+                    store.transactions.insert(
+                        transaction.transaction_id(),
+                        Self {
+                            applied,
+                            ..transaction.clone()
+                        },
+                    );
+                }
+                result?;
+            }
+        }
+
+        match memo {
+            Some(memo) => store.memos.insert(transaction.client, memo),
+            None => store.memos.remove(&transaction.client),
+        };
+        store.applied_keys.extend(key);
 
         Ok(())
     }
 
-    fn update_account(self, store: &mut Store) -> TransactionResult<()> {
+    /// What identifies this delivery of the transaction: the key the partner gave it, or else
+    /// everything the row says and, for a dispute, resolve or charge back, the dispute it belongs
+    /// to. A correction, differing in amount, is a new transaction, and so is a dispute raised
+    /// again once the last was resolved, while two identical partial disputes of one open
+    /// dispute need keys to both apply.
+    fn idempotency_key(&self, store: &Store) -> IdempotencyKey {
+        match &self.idempotency_key {
+            Some(key) => IdempotencyKey::Given(key.clone()),
+            None => IdempotencyKey::Derived {
+                transaction_type: self.transaction_type,
+                client: self.client,
+                transaction_id: self.transaction_id(),
+                amount: self.amount,
+                dispute: self.dispute_of(store),
+            },
+        }
+    }
+
+    /// The sequence number of the dispute of the referenced transaction this row belongs to:
+    /// the open one, or for a dispute of a transaction not under dispute, the one it would open
+    fn dispute_of(&self, store: &Store) -> Option<u64> {
         use TransactionType::*;
 
-        let existing_account = Account::find_or_create_by_client(self.client, store);
-        let amount = self.amount.with_context(|| "Unable to get amount");
+        let tx = match self.transaction_type {
+            Dispute | Resolve | Chargeback => store.transactions.get(&self.transaction_id())?,
+            Deposit | Withdrawal | PercentageFee => return None,
+        };
+        match self.transaction_type {
+            Dispute if !tx.get_is_under_dispute() => Some(store.disputes_raised + 1),
+            _ => Some(tx.dispute_seq),
+        }
+    }
+
+    /// The applied deposit or withdrawal this one repeats the id of, if any. Deposits and
+    /// withdrawals share one id space, so a withdrawal reusing a deposit's id is a repeat too
+    /// rather than a new transaction replacing the deposit in the store.
+    fn find_original(&self, store: &Store) -> Option<Transaction> {
+        use TransactionType::*;
 
         match self.transaction_type {
-            Deposit => existing_account.deposit(amount?, store)?,
-            Withdrawal => existing_account.withdraw(amount?, store)?,
-            Dispute => existing_account.dispute(self.transaction_id, store)?,
-            Resolve => existing_account.resolve(self.transaction_id, store)?,
-            Chargeback => existing_account.charge_back(self.transaction_id, store)?,
+            Deposit | Withdrawal | PercentageFee => {
+                store.transactions.get(&self.transaction_id()).cloned()
+            }
+            Dispute | Resolve | Chargeback => None,
+        }
+    }
+
+    fn check_amount_limit(&self, options: &Options) -> TransactionResult<()> {
+        use TransactionType::*;
+
+        match (self.transaction_type, self.amount, options.max_amount) {
+            (Deposit | Withdrawal, Some(amount), Some(limit)) if amount > limit => {
+                Err(TransactionError::AmountExceedsLimit {
+                    transaction_type: self.transaction_type,
+                    transaction_id: self.transaction_id(),
+                    amount,
+                    limit,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects disputing the client's own resolved transaction when the options forbid it
+    fn check_redispute(&self, store: &Store, options: &Options) -> TransactionResult<()> {
+        let disputed = match self.transaction_type {
+            TransactionType::Dispute => store.transactions.get(&self.transaction_id()),
+            _ => None,
+        };
+
+        match disputed {
+            Some(tx)
+                if options.redispute_policy == RedisputePolicy::Reject
+                    && tx.client == self.client
+                    && tx.dispute_state == DisputeState::Resolved =>
+            {
+                Err(AccountError::IllegalDisputeTransition {
+                    action: "dispute",
+                    transaction_id: self.transaction_id(),
+                    state: tx.dispute_state,
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_monotonic(&self, store: &Store, options: &Options) -> TransactionResult<()> {
+        use TransactionType::*;
+
+        let previous = match self.transaction_type {
+            Deposit | Withdrawal | PercentageFee if options.require_monotonic_tx => {
+                store.transactions.keys().next_back()
+            }
+            _ => None,
         };
+        match previous {
+            Some(&previous) if self.transaction_id() <= previous => {
+                Err(TransactionError::NonMonotonicTransaction {
+                    transaction_id: self.transaction_id(),
+                    previous,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects a deposit, withdrawal or fee opening an account past `Options::max_clients`. A
+    /// dispute, resolve or charge back never opens one, as the transaction it refers to must be
+    /// the client's own, so it is left to fail on that, e.g. as an unknown transaction.
+    fn check_client_limit(&self, store: &Store, options: &Options) -> TransactionResult<()> {
+        use TransactionType::*;
+
+        let opens_account = matches!(self.transaction_type, Deposit | Withdrawal | PercentageFee)
+            && !store.accounts.contains_key(&self.client);
+        match options.max_clients {
+            Some(limit) if opens_account && store.accounts.len() >= limit => {
+                Err(TransactionError::ClientLimitReached {
+                    client: self.client,
+                    limit,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replaces a percentage fee's percent with the fee it charges the client's account as it is
+    /// now, so from here on it is handled as the debit it is
+    fn charge_percentage(self, store: &Store, options: &Options) -> Self {
+        match (self.transaction_type, self.amount) {
+            (TransactionType::PercentageFee, Some(percent)) => {
+                let account = Account::find_or_new_by_client(self.client, store, options);
+                Self {
+                    amount: Some(account.percentage_fee(percent, options.fee_precision())),
+                    ..self
+                }
+            }
+            _ => self,
+        }
+    }
+
+    /// Reads a negative withdrawal as a deposit (double negative) when the options allow it
+    fn apply_options(self, options: &Options) -> Self {
+        match (self.transaction_type, self.amount) {
+            (TransactionType::Withdrawal, Some(amount))
+                if amount < dec!(0) && options.negative_withdrawal_as_deposit =>
+            {
+                Self {
+                    transaction_type: TransactionType::Deposit,
+                    amount: Some(-amount),
+                    ..self
+                }
+            }
+            _ => self,
+        }
+    }
+
+    /// Checks everything about the transaction that doesn't depend on the accounts: a deposit,
+    /// withdrawal or fee needs an amount, no amount may be negative, and a fee's percent can't be
+    /// over 100. Ids are range checked when the transaction is read.
+    pub fn validate(&self) -> TransactionResult<()> {
+        use TransactionType::*;
+
+        match (self.transaction_type, self.amount) {
+            (Deposit | Withdrawal, _) => self.funds().map(drop),
+            (PercentageFee, _) => match self.funds()?.value() {
+                percent if percent > dec!(100) => Err(TransactionError::InvalidPercentage(percent)),
+                _ => Ok(()),
+            },
+            (_, Some(amount)) if amount < dec!(0) => Err(TransactionError::InvalidAmount(amount)),
+            (_, _) => Ok(()),
+        }
+    }
+
+    /// The funds a deposit, withdrawal or fee moves, or why it has none
+    pub(crate) fn funds(&self) -> TransactionResult<Amount> {
+        let amount = self.amount.ok_or(TransactionError::MissingAmount {
+            transaction_type: self.transaction_type,
+            transaction_id: self.transaction_id(),
+        })?;
+
+        Amount::new(amount).map_err(|_| match self.transaction_type {
+            TransactionType::Withdrawal => TransactionError::NegativeWithdrawal(amount),
+            TransactionType::PercentageFee => TransactionError::InvalidPercentage(amount),
+            _ => TransactionError::NegativeDeposit(amount),
+        })
+    }
+
+    fn update_account(&self, store: &mut Store, options: &Options) -> TransactionResult<()> {
+        let mut account = Account::find_or_new_by_client(self.client, store, options);
+        account.apply_transaction(self, store)?;
+        if let Some(scale) = options.working_scale {
+            let held = account.held();
+            account.round_to_scale(scale, store);
+            // A dispute records what it holds, so the funds rounding gave or took are the
+            // dispute's too, and a resolve or charge back releases exactly what is held for it
+            if self.transaction_type == TransactionType::Dispute {
+                if let Some(tx) = Self::find_by_id(self.transaction_id(), store) {
+                    tx.disputed_amount += account.held() - held;
+                }
+            }
+        }
+        if options.close_zero_accounts && self.transaction_type == TransactionType::Withdrawal {
+            account.close_if_drained(store);
+        }
         Ok(())
     }
 
+    /// Replaces the stored copy of this transaction
+    pub(crate) fn update(self, store: &mut Store) {
+        store.transactions.insert(self.transaction_id(), self);
+    }
+
     /// Get the transaction's is under dispute.
     pub fn get_is_under_dispute(&self) -> bool {
-        self.is_under_dispute
+        self.dispute_state == DisputeState::Disputed
+    }
+
+    /// Get the transaction's dispute state.
+    pub fn get_dispute_state(&self) -> DisputeState {
+        self.dispute_state
     }
 
     /// Get the transaction's amount.
@@ -134,8 +827,86 @@ impl Transaction {
         self.amount
     }
 
-    /// Set the transaction's is under dispute.
-    pub fn set_is_under_dispute(&mut self, is_under_dispute: bool) {
-        self.is_under_dispute = is_under_dispute;
+    /// Set the transaction's dispute state.
+    pub(crate) fn set_dispute_state(&mut self, dispute_state: DisputeState) {
+        self.dispute_state = dispute_state;
+    }
+
+    /// Get the time the transaction's current dispute was raised.
+    pub fn get_disputed_at(&self) -> Option<u64> {
+        self.disputed_at
+    }
+
+    /// Set the time the transaction's current dispute was raised.
+    pub fn set_disputed_at(&mut self, disputed_at: Option<u64>) {
+        self.disputed_at = disputed_at;
+    }
+
+    /// Get how much of the transaction's amount its current dispute holds.
+    pub fn get_disputed_amount(&self) -> Decimal {
+        self.disputed_amount
+    }
+
+    /// Set how much of the transaction's amount its current dispute holds.
+    pub(crate) fn set_disputed_amount(&mut self, disputed_amount: Decimal) {
+        self.disputed_amount = disputed_amount;
+    }
+
+    /// Get whether the transaction was applied to its client's account.
+    pub fn get_applied(&self) -> bool {
+        self.applied
+    }
+
+    /// Get the time the partner recorded the transaction.
+    pub fn get_timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// Get the transaction's memo.
+    pub fn get_memo(&self) -> Option<&str> {
+        self.memo.as_deref()
+    }
+
+    /// Get the key the partner gave the transaction, see `Options::idempotent`.
+    pub fn get_idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    /// Get the transaction's type.
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    /// Set the client the transaction belongs to.
+    pub(crate) fn set_client(&mut self, client: Client) {
+        self.client = client;
+    }
+
+    /// Get the client the transaction belongs to.
+    pub fn client_id(&self) -> Client {
+        self.client
+    }
+
+    /// Get the transaction's id, or for a dispute, resolve or charge back, the id of the
+    /// transaction it refers to. A resolve or charge back that left it out for the oldest open
+    /// dispute has one once the engine assigned it; until then this is 0, see
+    /// `get_transaction_id`.
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id.unwrap_or(TransactionId(0))
+    }
+
+    /// Get the transaction's id as read, `None` if the feed left it out
+    pub fn get_transaction_id(&self) -> Option<TransactionId> {
+        self.transaction_id
+    }
+
+    /// Get when the transaction's current dispute was raised relative to the store's others
+    pub(crate) fn get_dispute_seq(&self) -> u64 {
+        self.dispute_seq
+    }
+
+    /// Set when the transaction's current dispute was raised relative to the store's others
+    pub(crate) fn set_dispute_seq(&mut self, dispute_seq: u64) {
+        self.dispute_seq = dispute_seq;
     }
 }
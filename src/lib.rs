@@ -0,0 +1,4060 @@
+//! A toy payment engine: processes a feed of transactions into the state of each client's
+//! account. The `payment-engine` binary is a command line front end to it.
+
+#[macro_use]
+extern crate log;
+
+mod features;
+pub use features::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::collections::BTreeMap;
+    use std::io::{self, BufRead, Write};
+    use std::num::NonZeroU32;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use test_case::test_case;
+
+    fn generate_accounts_from_transactions(
+        reader: impl BufRead,
+        writer: impl Write,
+        options: &Options,
+    ) -> anyhow::Result<()> {
+        let store = process_transactions(CsvSource::new(reader), Store::new(), options)?;
+        write_accounts(&store, writer, &OutputOptions::default())
+    }
+
+    #[test_case(
+"type, client, tx, amount 
+deposit, 1, 1, 1.0 
+withdrawal, 1, 4, 1.5",
+
+"client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+";
+
+"cannot withdraw more than available"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0 
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0 
+withdrawal, 1, 4, 1.5 
+withdrawal, 2, 5, 3.0",
+
+"client,available,held,total,locked
+1,1.5,0.0000,1.5,false
+2,2.0,0.0000,2.0,false
+";
+
+"handles deposits and withdrawals properly"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.56787645323 
+deposit, 2, 2, 2.2345652
+deposit, 1, 3, 2.34354 
+withdrawal, 1, 4, 1.522454 
+withdrawal, 2, 5, 3.0014355",
+
+"client,available,held,total,locked
+1,2.3890,0.0000,2.3890,false
+2,2.2346,0.0000,2.2346,false
+";
+
+"handles at least 4 decimal places properly"
+)]
+    #[test_case(
+"type, client, tx, amount 
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0 
+dispute, 1, 1,
+deposit, 1, 3, 1.5 
+withdrawal, 2, 5, 3.0", 
+
+"client,available,held,total,locked
+1,1.5,1.0,2.5,false
+2,2.0,0.0000,2.0,false
+";
+
+"handles client 1 dispute properly"
+)]
+    #[test_case(
+"type, client, tx, amount 
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0 
+dispute, 1, 1,
+resolve, 1, 1,
+withdrawal, 2, 5, 3.0", 
+
+"client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+2,2.0,0.0000,2.0,false
+";
+
+"can successfully resolve dispute"
+)]
+    #[test_case(
+"type, client, tx, amount 
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0 
+dispute, 1, 1,
+chargeback, 1, 1,
+withdrawal, 2, 5, 3.0", 
+
+"client,available,held,total,locked
+1,0.0000,0.0000,0.0000,true
+2,2.0,0.0000,2.0,false
+";
+
+"locks account 1 when client 1 charges back"
+)]
+    #[test_case(
+"type, client, tx, amount 
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0 
+dispute, 1, 1,
+chargeback, 1, 1,
+withdrawal, 2, 5, 1.5
+dispute, 2, 5,
+chargeback, 2, 5,", 
+
+// Charging back a withdrawal credits its reversal
+"client,available,held,total,locked
+1,0.0000,0.0000,0.0000,true
+2,2.0,0.0000,2.0,true
+";
+
+"locks accounts 1 and 2 when clients 1 and 2 initiate chargebacks"
+)]
+    #[test_case(
+"type, client, tx, amount 
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0 
+dispute, 1, 1
+chargeback, 1, 1,
+deposit, 1, 3, 5.6587878
+deposit, 1, 4, 11.05
+withdrawal, 1, 5, 3.0", 
+
+"client,available,held,total,locked
+1,0.0000,0.0000,0.0000,true
+2,2.0,0.0000,2.0,false
+";
+
+"cannot carry out a transaction after account is locked"
+)]
+    #[test_case(
+"type, client, tx, amount 
+deposit, 1, 1, 1.0
+deposit, 1, 2, -0.0001", 
+
+"client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+";
+
+"does not accept negative amount"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 12345678901234.5678
+deposit, 1, 2, 0.0001",
+
+"client,available,held,total,locked
+1,12345678901234.5679,0.0000,12345678901234.5679,false
+";
+
+"keeps amounts beyond f64 precision exact"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+dispute, 1, 1,
+dispute, 1, 1,",
+
+"client,available,held,total,locked
+1,2.0,1.0,3.0,false
+";
+
+"rejects a dispute while the transaction is already under dispute"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+resolve, 1, 1,",
+
+"client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+";
+
+"rejects a resolve of an undisputed transaction"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+chargeback, 1, 1,",
+
+"client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+";
+
+"rejects a chargeback of an undisputed transaction"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+dispute, 1, 1,
+resolve, 1, 1,
+resolve, 1, 1,",
+
+"client,available,held,total,locked
+1,3.0,0.0000,3.0,false
+";
+
+"rejects a second resolve of the same dispute"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1,
+resolve, 1, 1,
+chargeback, 1, 1,",
+
+"client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+";
+
+"rejects a chargeback of a resolved dispute"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+dispute, 1, 1,
+chargeback, 1, 1,
+chargeback, 1, 1,
+dispute, 1, 1,",
+
+"client,available,held,total,locked
+1,2.0,0.0000,2.0,true
+";
+
+"rejects any dispute action on a charged back transaction"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+dispute, 2, 1,
+dispute, 1, 2,
+resolve, 2, 1,
+chargeback, 2, 1,",
+
+"client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+2,2.0,0.0000,2.0,false
+";
+
+"ignores disputes of another client's transaction"
+)]
+    #[test_case(
+"type, client, tx, amount
+dispute, 7, 99,",
+
+"client,available,held,total,locked
+";
+
+"creates no account for a client whose only row is a rejected dispute"
+)]
+    fn transactions_to_accounts(input_transaction: &str, output_account: &str) {
+        assert_accounts(input_transaction, &Options::default(), output_account);
+    }
+
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, -0.5
+withdrawal, 1, 3, -0.25",
+Options::default(),
+"client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+";
+
+"rejects negative deposits and negative withdrawals by default"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 3, 3, 3.0
+withdrawal, 4, 4, 1.0
+deposit, 1, 5, 1.5
+dispute, 3, 3,",
+Options {
+    max_clients: Some(2),
+    ..Options::default()
+},
+"client,available,held,total,locked
+1,2.5,0.0000,2.5,false
+2,2.0,0.0000,2.0,false
+";
+
+"rejects transactions for new clients beyond the client limit"
+)]
+    fn transactions_to_accounts_with_options(
+        input_transaction: &str,
+        options: Options,
+        output_account: &str,
+    ) {
+        assert_accounts(input_transaction, &options, output_account);
+    }
+
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, -0.5
+withdrawal, 1, 3, -0.25
+dispute, 1, 3,",
+Options {
+    negative_withdrawal_as_deposit: true,
+    ..Options::default()
+},
+"client,available,held,total,locked
+1,1,0.25,1.25,false
+";
+
+"treats a negative withdrawal as a deposit when enabled"
+)]
+    #[test_case(
+"type, client, tx, amount
+deposit, 1, 1, 5
+withdrawal, 2, 2, 4
+dispute, 2, 2,
+dispute, 3, 3,
+deposit, 4, 4, 1
+dispute, 4, 4,",
+Options {
+    seed_balance: dec!(10),
+    ..Options::default()
+},
+"client,available,held,total,locked
+1,15,0,15,false
+2,6,4,10,false
+4,10,1,11,false
+";
+
+"starts new accounts with the seed balance"
+)]
+    fn transactions_to_account_values_with_options(
+        input_transaction: &str,
+        options: Options,
+        output_account: &str,
+    ) {
+        assert_account_values(input_transaction, &options, output_account);
+    }
+
+    #[test_case("", "deposit, 1, 2, 2.0", (dec!(3.0), dec!(0), dec!(3.0), false); "deposit")]
+    #[test_case("", "withdrawal, 1, 2, 0.25", (dec!(0.75), dec!(0), dec!(0.75), false); "withdrawal")]
+    #[test_case("", "dispute, 1, 1,", (dec!(0), dec!(1.0), dec!(1.0), false); "dispute")]
+    #[test_case("dispute, 1, 1,", "resolve, 1, 1,", (dec!(1.0), dec!(0), dec!(1.0), false); "resolve")]
+    #[test_case("dispute, 1, 1,", "chargeback, 1, 1,", (dec!(0), dec!(0), dec!(0), true); "chargeback")]
+    fn applies_every_kind_of_transaction_to_an_account(
+        setup: &str,
+        row: &str,
+        (available, held, total, locked): (Decimal, Decimal, Decimal, bool),
+    ) {
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, 1.0
+{setup}"
+        );
+        let mut store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+        let tx = CsvSource::new(format!("type, client, tx, amount\n{row}").as_bytes())
+            .next()
+            .unwrap()
+            .expect("Something failed");
+
+        let mut account = store.accounts[&Client::from(1)];
+        account
+            .apply_transaction(&tx, &mut store)
+            .expect("Something failed");
+
+        let stored = store.accounts[&Client::from(1)];
+        for account in [account, stored] {
+            assert_eq!(
+                (
+                    account.available(),
+                    account.held(),
+                    account.total(),
+                    account.is_locked()
+                ),
+                (available, held, total, locked)
+            );
+        }
+    }
+
+    #[test]
+    fn csv_and_jsonl_sources_produce_identical_accounts() {
+        let csv = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+withdrawal, 1, 3, 0.5
+dispute, 2, 2,
+resolve, 2, 2,
+dispute, 1, 1,
+chargeback, 1, 1,";
+        let jsonl = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.0}
+{"type": "deposit", "client": 2, "tx": 2, "amount": 2.0}
+{"type": "withdrawal", "client": 1, "tx": 3, "amount": 0.5}
+
+{"type": "dispute", "client": 2, "tx": 2}
+{"type": "resolve", "client": 2, "tx": 2, "amount": null}
+{"type": "dispute", "client": 1, "tx": 1}
+{"type": "chargeback", "client": 1, "tx": 1}
+"#;
+        let options = Options::default();
+
+        let mut from_csv = Vec::new();
+        let store = process_transactions(CsvSource::new(csv.as_bytes()), Store::new(), &options)
+            .expect("Something failed");
+        write_accounts(&store, &mut from_csv, &OutputOptions::default()).expect("Something failed");
+        let mut from_jsonl = Vec::new();
+        let store = process_transactions(
+            JsonLinesSource::new(jsonl.as_bytes()),
+            Store::new(),
+            &options,
+        )
+        .expect("Something failed");
+        write_accounts(&store, &mut from_jsonl, &OutputOptions::default())
+            .expect("Something failed");
+
+        assert_eq!(from_csv, from_jsonl);
+        assert_eq!(
+            from_csv,
+            "client,available,held,total,locked
+1,-0.5,0.0000,-0.5,true
+2,2.0,0.0000,2.0,false
+"
+            .as_bytes()
+        );
+    }
+
+    #[test_case("deposit, 1, 2, 1.0", 1, 2; "deposit")]
+    #[test_case("dispute, 3, 4,", 3, 4; "dispute")]
+    #[test_case("deposit, 5, 4294967296, 1.0", 5, 4_294_967_296; "tx id above u32 max")]
+    #[test_case("deposit, 5, 18446744073709551615, 1.0", 5, u64::MAX; "largest tx id")]
+    fn reads_client_and_transaction_ids(row: &str, client: u16, transaction_id: u64) {
+        let input = format!("type, client, tx, amount\n{row}");
+        let transaction = CsvSource::new(input.as_bytes()).next().unwrap().unwrap();
+
+        assert_eq!(transaction.client_id(), Client::from(client));
+        assert_eq!(
+            transaction.transaction_id(),
+            TransactionId::from(transaction_id)
+        );
+    }
+
+    #[test]
+    fn reads_amounts_exactly_without_going_through_f64() {
+        let csv = "type, client, tx, amount
+deposit, 1, 1, 1234567.1234567890123456789";
+        let jsonl =
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1234567.1234567890123456789}"#;
+
+        let from_csv = CsvSource::new(csv.as_bytes()).next().unwrap().unwrap();
+        let from_jsonl = JsonLinesSource::new(jsonl.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            from_csv.get_amount(),
+            Some(dec!(1234567.1234567890123456789))
+        );
+        assert_eq!(
+            from_jsonl.get_amount(),
+            Some(dec!(1234567.1234567890123456789))
+        );
+    }
+
+    #[test]
+    fn writes_each_account_as_a_standalone_json_line() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 12345678901234.5678
+deposit, 1, 2, 0.0001
+deposit, 2, 3, 2.0
+dispute, 2, 3,";
+        let store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+        let output_options = OutputOptions {
+            format: OutputFormat::JsonLines,
+            ..OutputOptions::default()
+        };
+
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &output_options).expect("Something failed");
+        let result = String::from_utf8(result).unwrap();
+
+        let lines = result.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            let account: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert!(account.is_object());
+        }
+        // Amounts are numbers carrying the exact rounded decimal; an f64 would give ...234.568
+        assert_eq!(
+            lines[0],
+            r#"{"client":1,"available":12345678901234.5679,"held":0.0000,"total":12345678901234.5679,"locked":false}"#
+        );
+        assert_eq!(
+            lines[1],
+            r#"{"client":2,"available":0.0000,"held":2.0,"total":2.0,"locked":false}"#
+        );
+    }
+
+    #[test]
+    fn ignores_a_resolve_after_a_chargeback() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 2.0
+dispute, 1, 1,
+chargeback, 1, 1,
+resolve, 1, 1,";
+        let mut resolve = None;
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| {
+                if event.transaction.transaction_type() == TransactionType::Resolve {
+                    resolve = Some((event.before, event.after));
+                    assert!(event
+                        .result
+                        .as_ref()
+                        .unwrap_err()
+                        .to_string()
+                        .contains("while its dispute state is ChargedBack"));
+                }
+            },
+        )
+        .expect("Something failed");
+
+        let (before, after) = resolve.expect("resolve was not processed");
+        let (before, after) = (before.unwrap(), after.unwrap());
+        assert_eq!(
+            (before.available(), before.held(), before.total()),
+            (after.available(), after.held(), after.total())
+        );
+        assert_eq!(
+            (after.available(), after.held(), after.total()),
+            (dec!(2.0), dec!(0.0), dec!(2.0))
+        );
+        assert!(!store.transactions[&TransactionId::from(1)].get_is_under_dispute());
+    }
+
+    #[test_case("dispute", "AccountError(ErroneousDispute(TransactionId(99)))", "Erroneous dispute: Transaction id 99 does not exist"; "dispute")]
+    #[test_case("resolve", "AccountError(ErroneousResolve(TransactionId(99)))", "Erroneous resolve: Transaction id 99 does not exist"; "resolve")]
+    #[test_case("chargeback", "AccountError(ErroneousChargeback(TransactionId(99)))", "Erroneous charge back: Transaction id 99 does not exist"; "chargeback")]
+    fn categorizes_a_reference_to_a_transaction_that_does_not_exist(
+        action: &str,
+        variant: &str,
+        message: &str,
+    ) {
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, 1.0
+{action}, 1, 99,"
+        );
+        let mut error = None;
+        process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| {
+                if let Err(e) = event.result {
+                    error = Some((format!("{e:?}"), e.to_string()));
+                }
+            },
+        )
+        .expect("Something failed");
+
+        assert_eq!(
+            error.expect("reference was accepted"),
+            (
+                variant.to_string(),
+                format!("Invalid transaction - {message}")
+            )
+        );
+    }
+
+    #[test_case(DisputeState::Active, DisputeState::resolve; "resolve an active transaction")]
+    #[test_case(DisputeState::Active, DisputeState::charge_back; "charge back an active transaction")]
+    #[test_case(DisputeState::Disputed, DisputeState::dispute; "dispute a disputed transaction")]
+    #[test_case(DisputeState::Resolved, DisputeState::resolve; "resolve a resolved transaction")]
+    #[test_case(DisputeState::Resolved, DisputeState::charge_back; "charge back a resolved transaction")]
+    #[test_case(DisputeState::ChargedBack, DisputeState::dispute; "dispute a charged back transaction")]
+    #[test_case(DisputeState::ChargedBack, DisputeState::resolve; "resolve a charged back transaction")]
+    #[test_case(DisputeState::ChargedBack, DisputeState::charge_back; "charge back a charged back transaction")]
+    fn rejects_illegal_dispute_transitions(
+        state: DisputeState,
+        step: fn(DisputeState) -> Option<DisputeState>,
+    ) {
+        assert_eq!(step(state), None);
+    }
+
+    #[test_case("5.0", "You cannot withdraw 5.0. Only 1.0 is available because 10.0 is held for dispute"; "covered but for held funds")]
+    #[test_case("20.0", "You cannot withdraw 20.0. It is less than 1.0 available in your account"; "not covered at all")]
+    fn explains_why_a_withdrawal_was_refused(amount: &str, message: &str) {
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 2, 1.0
+dispute, 1, 1,
+withdrawal, 1, 3, {amount}"
+        );
+        let mut refusal = None;
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| {
+                if event.transaction.transaction_type() == TransactionType::Withdrawal {
+                    refusal = event.result.as_ref().err().map(ToString::to_string);
+                }
+            },
+        )
+        .expect("Something failed");
+
+        assert_eq!(
+            refusal.expect("withdrawal was applied"),
+            format!("Invalid transaction - {message}")
+        );
+        let account = store.accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held(), account.total()),
+            (dec!(1.0), dec!(10.0), dec!(11.0))
+        );
+    }
+
+    #[test]
+    fn writes_rejection_metrics_in_prometheus_format() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 2, 3, 9.0
+dispute, 1, 1,
+dispute, 1, 99,
+resolve, 2, 2,
+deposit, 1, 4, -1.0";
+        let mut metrics = Metrics::new();
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| metrics.record(event),
+        )
+        .expect("Something failed");
+
+        let mut result = Vec::new();
+        metrics
+            .write_prometheus(&store, &mut result)
+            .expect("Something failed");
+        let result = String::from_utf8(result).unwrap();
+
+        for line in [
+            "# TYPE payment_engine_rows_read_total counter",
+            "payment_engine_rows_read_total 7",
+            "payment_engine_rows_applied_total 3",
+            "# TYPE payment_engine_rows_rejected_total counter",
+            r#"payment_engine_rows_rejected_total{reason="insufficient_funds"} 1"#,
+            r#"payment_engine_rows_rejected_total{reason="unknown_transaction"} 1"#,
+            r#"payment_engine_rows_rejected_total{reason="illegal_dispute_transition"} 1"#,
+            r#"payment_engine_rows_rejected_total{reason="invalid_amount"} 1"#,
+            "# TYPE payment_engine_funds_available gauge",
+            "payment_engine_funds_available 5.0",
+            "payment_engine_funds_held 10.0",
+        ] {
+            assert!(
+                result.lines().any(|l| l == line),
+                "missing {line:?} in\n{result}"
+            );
+        }
+    }
+
+    #[test]
+    fn samples_the_first_rejected_tx_ids_per_reason() {
+        let mut input = String::from("type, client, tx, amount\ndeposit, 1, 1, 1.0\n");
+        for tx in 2..=9 {
+            input.push_str(&format!("withdrawal, 1, {tx}, 5.0\n"));
+        }
+        input.push_str("dispute, 1, 99,\nresolve, 1, ,");
+        let mut engine = Engine::new(Options {
+            fifo_disputes: true,
+            ..Options::default()
+        });
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+        let metrics = engine.metrics();
+
+        assert_eq!(metrics.rows_rejected["insufficient_funds"], 8);
+        assert_eq!(
+            metrics.rejection_samples["insufficient_funds"],
+            [2, 3, 4, 5, 6].map(|tx| RejectionSample::Tx(TransactionId::from(tx)))
+        );
+        assert_eq!(
+            metrics.rejection_samples["unknown_transaction"],
+            [RejectionSample::Tx(TransactionId::from(99))]
+        );
+        assert_eq!(
+            metrics.rejection_samples["no_open_dispute"],
+            [RejectionSample::Row(11)]
+        );
+
+        let mut result = Vec::new();
+        metrics
+            .write_prometheus(engine.store(), &mut result)
+            .expect("Something failed");
+        let result = String::from_utf8(result).unwrap();
+        assert!(!result.contains("tx="), "{result}");
+        for line in [
+            "payment_engine_rows_rejected_total{reason=\"insufficient_funds\"} 8",
+            "payment_engine_rows_rejected_total{reason=\"unknown_transaction\"} 1",
+            "# rejected insufficient_funds: tx 2, tx 3, tx 4, tx 5, tx 6",
+            "# rejected no_open_dispute: row 11",
+            "# rejected unknown_transaction: tx 99",
+        ] {
+            assert!(
+                result.lines().any(|l| l == line),
+                "missing {line:?} in\n{result}"
+            );
+        }
+    }
+
+    #[test_case("12.0", true; "matching control total")]
+    #[test_case("13.0", false; "row dropped from the feed")]
+    fn checks_net_deposits_against_a_control_total(declared: &str, matches: bool) {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 2, 3, 3.0
+withdrawal, 2, 4, 9.0
+deposit, 1, 5, 1.0
+dispute, 1, 5,
+chargeback, 1, 5,
+dispute, 1, 1,
+resolve, 1, 1,";
+        let mut metrics = Metrics::new();
+        process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| metrics.record(event),
+        )
+        .expect("Something failed");
+
+        let declared = Decimal::from_str(declared).unwrap();
+        let result = metrics.check_control_total(declared, dec!(0.0001));
+        assert_eq!(result.is_ok(), matches);
+        if let Err(e) = result {
+            assert_eq!(
+                e.to_string(),
+                "Net deposits of 12.0 differ from the control total of 13.0 by more than 0.0001"
+            );
+        }
+    }
+
+    #[test]
+    fn applies_transactions_through_an_engine() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 3.0
+deposit, 2, 2, 2.0
+withdrawal, 2, 3, 5.0
+dispute, 1, 1,";
+        let mut engine = Engine::new(Options::default());
+        apply_each(&mut engine, CsvSource::new(input.as_bytes()));
+
+        let metrics = engine.metrics();
+        assert_eq!((metrics.rows_read, metrics.rows_applied), (4, 3));
+        assert_eq!(metrics.rows_rejected["insufficient_funds"], 1);
+
+        let mut result = Vec::new();
+        engine
+            .write_accounts(&mut result, &OutputOptions::default())
+            .expect("Something failed");
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "client,available,held,total,locked
+1,0.0000,3.0,3.0,false
+2,2.0,0.0000,2.0,false
+"
+        );
+    }
+
+    /// Counts what is written to it, and the largest single write
+    #[derive(Default)]
+    struct WriteSizes {
+        total: usize,
+        largest: usize,
+    }
+
+    impl Write for WriteSizes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.total += buf.len();
+            self.largest = self.largest.max(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test_case(OutputFormat::Csv; "csv")]
+    #[test_case(OutputFormat::JsonLines; "json lines")]
+    fn streams_every_client_without_building_the_output_in_memory(format: OutputFormat) {
+        let mut engine = Engine::new(Options::default());
+        let input = (1..=u16::MAX).fold(
+            String::from("type, client, tx, amount\n"),
+            |input, client| input + &format!("deposit, {client}, {client}, 1.5\n"),
+        );
+        for result in apply_each(&mut engine, CsvSource::new(input.as_bytes())) {
+            result.expect("Something failed");
+        }
+
+        let mut sizes = WriteSizes::default();
+        let output_options = OutputOptions {
+            format,
+            ..OutputOptions::default()
+        };
+        engine
+            .write_accounts(&mut sizes, &output_options)
+            .expect("Something failed");
+
+        // Every client was written, a buffer at a time
+        assert!(sizes.total > 20 * usize::from(u16::MAX));
+        assert!(
+            sizes.largest <= output_options.write_buffer,
+            "wrote {} bytes at once",
+            sizes.largest
+        );
+    }
+
+    #[test_case(OutputFormat::Csv; "csv")]
+    #[test_case(OutputFormat::JsonLines; "json lines")]
+    fn writes_the_same_output_whatever_the_buffer_size(format: OutputFormat) {
+        let input = (1..=5_000).fold(String::from("type, client, tx, amount\n"), |input, tx| {
+            input + &format!("deposit, {}, {tx}, 1.{tx}\n", tx % 1_000)
+        });
+        let store = store_of(&input);
+
+        let outputs = [OutputOptions::DEFAULT_WRITE_BUFFER, 1, 100, 1 << 20].map(|write_buffer| {
+            let mut output = Vec::new();
+            let output_options = OutputOptions {
+                format,
+                write_buffer,
+                ..OutputOptions::default()
+            };
+            write_accounts(&store, &mut output, &output_options).expect("Something failed");
+            output
+        });
+
+        assert!(outputs[0].len() > 1_000 * 20);
+        assert!(outputs.iter().all(|output| *output == outputs[0]));
+    }
+
+    #[test]
+    fn disputes_a_deposit_a_portion_at_a_time() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1, 0.3
+dispute, 1, 1, 0.4
+dispute, 1, 1, 0.5";
+        let mut rejections = Vec::new();
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| {
+                if let Err(e) = event.result {
+                    rejections.push(e.to_string());
+                }
+            },
+        )
+        .expect("Something failed");
+
+        let account = store.accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held(), account.total()),
+            (dec!(0.3), dec!(0.7), dec!(1.0))
+        );
+        assert_eq!(
+            store.transactions[&TransactionId::from(1)].get_disputed_amount(),
+            dec!(0.7)
+        );
+        assert_eq!(
+            rejections,
+            ["Invalid transaction - Cannot dispute 0.5 of transaction 1, only 0.3 of it is undisputed"]
+        );
+    }
+
+    #[test_case("resolve", (dec!(1.0), dec!(0), dec!(1.0), false), dec!(0) ; "resolve releases the portion")]
+    #[test_case("chargeback", (dec!(0.6), dec!(0), dec!(0.6), true), dec!(0.4) ; "charge back takes the portion")]
+    fn settles_exactly_the_disputed_portion(
+        settlement: &str,
+        expected: (Decimal, Decimal, Decimal, bool),
+        still_disputed: Decimal,
+    ) {
+        let store = store_of(&format!(
+            "type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1, 0.4
+{settlement}, 1, 1,"
+        ));
+
+        let account = store.accounts[&Client::from(1)];
+        assert_eq!(
+            (
+                account.available(),
+                account.held(),
+                account.total(),
+                account.is_locked()
+            ),
+            expected
+        );
+        assert_eq!(
+            store.transactions[&TransactionId::from(1)].get_disputed_amount(),
+            still_disputed
+        );
+    }
+
+    #[test_case("client,available,held,total,locked"; "default columns")]
+    #[test_case("total,memo,client"; "selected columns with memo")]
+    fn describes_the_json_account_output_with_a_schema(columns: &str) {
+        let input = "type, client, tx, amount, memo
+deposit, 1, 1, 2.5, salary
+deposit, 2, 2, 1.0,
+dispute, 2, 2,";
+        let store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+
+        let output_options = OutputOptions {
+            format: OutputFormat::JsonLines,
+            columns: Column::parse_list(columns).expect("Something failed"),
+            ..OutputOptions::default()
+        };
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &output_options).expect("Something failed");
+        let schema = account_schema(&output_options.columns);
+
+        for line in result.lines() {
+            let account: serde_json::Value =
+                serde_json::from_str(&line.unwrap()).expect("Something failed");
+            assert!(
+                matches_schema(&schema, &account),
+                "{account} does not match {schema}"
+            );
+        }
+
+        assert!(!matches_schema(
+            &schema,
+            &serde_json::json!({ "client": "1" })
+        ));
+        assert!(!matches_schema(
+            &schema,
+            &serde_json::json!({ "unexpected": true })
+        ));
+    }
+
+    /// Checks an object against the subset of JSON Schema `account_schema` uses
+    fn matches_schema(schema: &serde_json::Value, value: &serde_json::Value) -> bool {
+        let has_type = |schema: &serde_json::Value, value: &serde_json::Value| {
+            let types = match &schema["type"] {
+                serde_json::Value::Array(types) => types.clone(),
+                single => vec![single.clone()],
+            };
+            types.iter().any(|t| match t.as_str() {
+                Some("object") => value.is_object(),
+                Some("integer") => value.is_u64() || value.is_i64(),
+                Some("number") => value.is_number(),
+                Some("boolean") => value.is_boolean(),
+                Some("string") => value.is_string(),
+                Some("null") => value.is_null(),
+                _ => false,
+            })
+        };
+        let properties = schema["properties"].as_object().unwrap();
+        let object = match value.as_object() {
+            Some(object) if has_type(schema, value) => object,
+            _ => return false,
+        };
+
+        schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|name| object.contains_key(name.as_str().unwrap()))
+            && object.iter().all(|(name, field)| {
+                properties
+                    .get(name)
+                    .is_some_and(|property| has_type(property, field))
+            })
+    }
+
+    #[test]
+    fn returns_to_the_original_balance_after_each_dispute_cycle() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 2.0
+deposit, 1, 2, 1.5
+dispute, 1, 1,
+resolve, 1, 1,
+dispute, 1, 1,
+resolve, 1, 1,";
+        let mut balances = Vec::new();
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| {
+                assert!(event.result.is_ok(), "{:?}", event.result);
+                let account = event.after.expect("Something failed");
+                balances.push((account.available(), account.held(), account.total()));
+            },
+        )
+        .expect("Something failed");
+
+        let original = (dec!(3.5), dec!(0), dec!(3.5));
+        let disputed = (dec!(1.5), dec!(2.0), dec!(3.5));
+        assert_eq!(balances[2..], [disputed, original, disputed, original]);
+        assert_eq!(
+            store.transactions[&TransactionId::from(1)].get_dispute_state(),
+            DisputeState::Resolved
+        );
+    }
+
+    #[test_case(DuplicateTxPolicy::First, Some(dec!(1.0)); "first row wins")]
+    #[test_case(DuplicateTxPolicy::Last, Some(dec!(1.5)); "last row corrects the first")]
+    #[test_case(DuplicateTxPolicy::Reject, None; "repeat stops processing")]
+    fn applies_the_duplicate_tx_policy_to_a_repeated_deposit(
+        policy: DuplicateTxPolicy,
+        total: Option<Decimal>,
+    ) {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 1, 1.5";
+        let options = Options {
+            duplicate_tx_policy: policy,
+            ..Options::default()
+        };
+        let result = process_transactions(CsvSource::new(input.as_bytes()), Store::new(), &options);
+
+        match total {
+            Some(total) => {
+                let store = result.expect("Something failed");
+                let account = store.accounts[&Client::from(1)];
+                assert_eq!((account.available(), account.total()), (total, total));
+            }
+            None => assert_eq!(
+                result.expect_err("duplicate was accepted").to_string(),
+                "Duplicate transaction id 1"
+            ),
+        }
+    }
+
+    #[test_case(DuplicateTxPolicy::First, "Duplicate transaction id 1"; "first row wins")]
+    #[test_case(DuplicateTxPolicy::Last, "Invalid transaction - Transaction 1 cannot be corrected, it was refused or disputed, or the correction's client or type differs"; "last row of another type")]
+    #[test_case(DuplicateTxPolicy::Reject, "Duplicate transaction id 1"; "repeat rejected")]
+    fn rejects_a_withdrawal_reusing_a_deposit_id(policy: DuplicateTxPolicy, error: &str) {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 3.0
+withdrawal, 1, 1, 1.0
+dispute, 1, 1,";
+        let mut engine = Engine::new(Options {
+            duplicate_tx_policy: policy,
+            ..Options::default()
+        });
+        let results = apply_each(&mut engine, CsvSource::new(input.as_bytes()));
+
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap_err().to_string(), error);
+        assert!(results[2].is_ok());
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held(), account.total()),
+            (dec!(0), dec!(3.0), dec!(3.0))
+        );
+    }
+
+    #[test_case("3/4", Ok(dec!(0.75)); "terminating fraction")]
+    #[test_case("-6/8", Ok(dec!(-0.75)); "unreduced negative fraction")]
+    #[test_case("1/3", Err("Malformed amount - 1/3 has no exact decimal value"); "non terminating fraction")]
+    #[test_case("1/0", Err("Malformed amount - 1/0 is not a fraction of two integers"); "zero denominator")]
+    fn reads_fraction_amounts_exactly(amount: &str, expected: Result<Decimal, &str>) {
+        let csv = format!("type, client, tx, amount\nwithdrawal, 1, 1, {amount}");
+        let json =
+            format!(r#"{{"type": "withdrawal", "client": 1, "tx": 1, "amount": "{amount}"}}"#);
+        let parsed = [
+            CsvSource::new(csv.as_bytes())
+                .amount_fractions(true)
+                .next()
+                .unwrap(),
+            JsonLinesSource::new(json.as_bytes())
+                .amount_fractions(true)
+                .next()
+                .unwrap(),
+        ];
+
+        for transaction in parsed {
+            let amount = transaction
+                .map(|transaction| transaction.get_amount().unwrap())
+                .map_err(|e| e.to_string());
+            assert_eq!(amount, expected.map_err(str::to_string));
+        }
+        assert!(CsvSource::new(csv.as_bytes()).next().unwrap().is_err());
+    }
+
+    #[test_case("1/3" ; "non terminating fraction")]
+    #[test_case("1/0" ; "zero denominator")]
+    #[test_case("1/x" ; "not a fraction")]
+    fn skips_only_the_row_of_an_inexact_fraction(amount: &str) {
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, 1/2
+deposit, 1, 2, {amount}
+deposit, 1, 3, 3/4"
+        );
+        let mut engine = Engine::new(Options::default());
+        let completion = engine
+            .process(
+                CsvSource::new(input.as_bytes()).amount_fractions(true),
+                |_| {},
+            )
+            .expect("Something failed");
+
+        assert_eq!(completion, Completion::Finished);
+        assert_eq!(
+            engine.store().accounts[&Client::from(1)].available(),
+            dec!(1.25)
+        );
+        assert_eq!(engine.metrics().rows_rejected["unparsable_amount"], 1);
+    }
+
+    #[test]
+    fn writes_a_ledger_row_for_every_applied_transaction() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 1.0
+withdrawal, 1, 3, 1.5
+withdrawal, 2, 4, 9.0
+dispute, 1, 1,
+chargeback, 1, 1,";
+        let mut result = Vec::new();
+        let mut ledger = Ledger::new(&mut result, true);
+        process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| ledger.record(event),
+        )
+        .expect("Something failed");
+        ledger.finish().expect("Something failed");
+
+        // The refused withdrawal has no row
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "client,tx,type,delta,available,held,total
+1,1,deposit,5.0,5.0,0,5.0
+2,2,deposit,1.0,1.0,0,1.0
+1,3,withdrawal,-1.5,3.5,0,3.5
+1,1,dispute,0.0,-1.5,5.0,3.5
+1,1,chargeback,-5.0,-1.5,0.0,-1.5
+"
+        );
+    }
+
+    #[test]
+    fn rebuilds_amounts_finer_than_the_output_from_the_ledger() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.00005
+deposit, 1, 2, 0.123456
+withdrawal, 1, 3, 0.00001";
+        let mut journal = Vec::new();
+        let mut ledger = Ledger::new(&mut journal, true);
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| ledger.record(event),
+        )
+        .expect("Something failed");
+        ledger.finish().expect("Something failed");
+
+        let replayed = replay_ledger(&journal[..]).expect("Something failed");
+        assert_eq!(
+            replayed.accounts[&Client::from(1)],
+            store.accounts[&Client::from(1)]
+        );
+        assert_eq!(
+            replayed.transactions[&TransactionId::from(1)].get_amount(),
+            Some(dec!(1.00005))
+        );
+
+        let (replayed, _) = process_transactions_with(
+            CsvSource::new("type, client, tx, amount\ndispute, 1, 1,".as_bytes()),
+            replayed,
+            &Options::default(),
+            |_| {},
+        )
+        .expect("Something failed");
+        let account = replayed.accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held()),
+            (dec!(0.123446), dec!(1.00005))
+        );
+    }
+
+    #[test]
+    fn rebuilds_the_final_state_from_the_ledger() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 1.0
+withdrawal, 1, 3, 1.5
+withdrawal, 2, 4, 9.0
+withdrawal, 1, 3, 2.0
+dispute, 1, 1, 2.0
+dispute, 1, 1, 1.0
+deposit, 3, 5, 4.0
+dispute, 3, 5,
+chargeback, 3, 5,
+deposit, 2, 6, 0.25
+dispute, 2, 6,
+resolve, 2, 6,";
+        let options = Options {
+            duplicate_tx_policy: DuplicateTxPolicy::Last,
+            ..Options::default()
+        };
+        let mut journal = Vec::new();
+        let mut ledger = Ledger::new(&mut journal, true);
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &options,
+            |event| ledger.record(event),
+        )
+        .expect("Something failed");
+        ledger.finish().expect("Something failed");
+
+        let replayed = replay_ledger(&journal[..]).expect("Something failed");
+
+        let accounts = |store: &Store| {
+            let mut result = Vec::new();
+            write_accounts(store, &mut result, &OutputOptions::default())
+                .expect("Something failed");
+            String::from_utf8(result).unwrap()
+        };
+        assert_eq!(accounts(&replayed), accounts(&store));
+        // The refused withdrawal was never applied, so it isn't journaled
+        for (id, tx) in store.transactions.iter().filter(|(_, tx)| tx.get_applied()) {
+            let replayed = &replayed.transactions[id];
+            assert_eq!(
+                (replayed.get_amount(), replayed.get_dispute_state()),
+                (tx.get_amount(), tx.get_dispute_state())
+            );
+            assert_eq!(replayed.get_disputed_amount(), tx.get_disputed_amount());
+        }
+        assert_eq!(
+            replayed.accounts[&Client::from(3)].locked_by(),
+            Some(5.into())
+        );
+
+        // Disputes carry on where the journal left off
+        let store = process_transactions(
+            CsvSource::new("type, client, tx\nresolve, 1, 1".as_bytes()),
+            replayed,
+            &options,
+        )
+        .expect("Something failed");
+        let account = store.accounts[&Client::from(1)];
+        assert_eq!((account.available(), account.held()), (dec!(3.0), dec!(0)));
+    }
+
+    #[test]
+    fn refuses_a_resolve_that_would_take_held_negative() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1,";
+        let mut store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+
+        // The account is replaced by a snapshot holding less than the open dispute
+        let seed = "client,available,held,total,locked
+1,0.7,0.3,1.0,false";
+        let seed = load_seed(seed.as_bytes()).expect("Something failed");
+        store.accounts.extend(seed.accounts);
+
+        let mut refusal = None;
+        let (store, _) = process_transactions_with(
+            CsvSource::new("type, client, tx, amount\nresolve, 1, 1,".as_bytes()),
+            store,
+            &Options::default(),
+            |event| refusal = event.result.as_ref().err().map(ToString::to_string),
+        )
+        .expect("Something failed");
+
+        assert_eq!(
+            refusal.expect("resolve was applied"),
+            "Invalid transaction - Cannot release 1.0 held for transaction 1, the account only holds 0.3"
+        );
+        let account = store.accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held(), account.total()),
+            (dec!(0.7), dec!(0.3), dec!(1.0))
+        );
+        assert!(store.transactions[&TransactionId::from(1)].get_is_under_dispute());
+    }
+
+    #[test]
+    fn skips_every_transaction_of_a_denied_client() {
+        let deny_list = "# sanctioned
+2
+
+";
+        let options = Options {
+            deny_list: load_deny_list(deny_list.as_bytes()).expect("Something failed"),
+            ..Options::default()
+        };
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 5.0
+withdrawal, 2, 3, 1.0
+dispute, 2, 2,
+dispute, 1, 2,
+deposit, 1, 4, 2.0";
+        let mut engine = Engine::new(options);
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        let mut result = Vec::new();
+        engine
+            .write_accounts(&mut result, &OutputOptions::default())
+            .expect("Something failed");
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "client,available,held,total,locked
+1,3.0,0.0000,3.0,false
+"
+        );
+        assert_eq!(engine.metrics().rows_rejected["denied_client"], 3);
+        assert_eq!(engine.metrics().rows_rejected["unknown_transaction"], 1);
+    }
+
+    #[test]
+    fn rejects_a_deny_list_entry_that_is_not_a_client_id() {
+        let error = load_deny_list("1\nclient 2\n".as_bytes()).expect_err("list was accepted");
+        assert_eq!(
+            error.to_string(),
+            r#"Malformed deny list - line 2: "client 2" is not a client id"#
+        );
+    }
+
+    #[test_case(OutputFormat::Csv, "client,available,held,total,locked
+1,2.13,0.123456789,2.25,false
+"; "csv")]
+    #[test_case(OutputFormat::JsonLines, r#"{"client":1,"available":2.13,"held":0.123456789,"total":2.25,"locked":false}
+"#; "json lines")]
+    fn writes_held_at_its_own_precision(format: OutputFormat, expected: &str) {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 2.251234567
+dispute, 1, 1, 0.123456789";
+        let store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+
+        let output_options = OutputOptions {
+            format,
+            balance_precision: 2,
+            held_precision: OutputOptions::MAX_PRECISION,
+            ..OutputOptions::default()
+        };
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &output_options).expect("Something failed");
+
+        assert_eq!(String::from_utf8(result).unwrap(), expected);
+    }
+
+    #[test_case("deposit, 1, 1,", Err("Invalid input - deposit 1 has no amount"); "deposit without amount")]
+    #[test_case("withdrawal, 1, 1,", Err("Invalid input - withdrawal 1 has no amount"); "withdrawal without amount")]
+    #[test_case("deposit, 1, 1, -1.0", Err("Invalid input - negative amount -1.0 on deposit"); "negative deposit")]
+    #[test_case("withdrawal, 1, 1, -1.0", Err("Invalid input - negative amount -1.0 on withdrawal"); "negative withdrawal")]
+    #[test_case("dispute, 1, 1, -0.5", Err("Invalid input - -0.5"); "negative dispute")]
+    #[test_case("deposit, 1, 1, 0", Ok(()); "zero deposit")]
+    #[test_case("dispute, 1, 1,", Ok(()); "dispute without amount")]
+    #[test_case("chargeback, 1, 1,", Ok(()); "chargeback without amount")]
+    fn validates_a_transaction_on_its_own(row: &str, expected: Result<(), &str>) {
+        let input = format!("type, client, tx, amount\n{row}");
+        let transaction = CsvSource::new(input.as_bytes())
+            .next()
+            .unwrap()
+            .expect("Something failed");
+
+        assert_eq!(
+            transaction.validate().map_err(|e| e.to_string()),
+            expected.map_err(str::to_string)
+        );
+    }
+
+    #[test_case(false, 0; "every rejection logged")]
+    #[test_case(true, 49; "first rejection per client logged")]
+    fn dedupes_locked_account_warnings(dedupe: bool, suppressed: u64) {
+        let options = Options {
+            dedupe_locked_warnings: dedupe,
+            ..Options::default()
+        };
+        let mut input = String::from(
+            "type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 1, 1,
+chargeback, 1, 1,
+",
+        );
+        for tx in 2..52 {
+            input.push_str(&format!("deposit, 1, {tx}, 1.0\n"));
+        }
+        let mut engine = Engine::new(options);
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        assert_eq!(engine.metrics().rows_rejected["account_locked"], 50);
+        assert_eq!(engine.metrics().locked_warnings_suppressed, suppressed);
+    }
+
+    #[test]
+    fn reports_the_time_spent_in_each_phase() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 0.5";
+        let mut engine = Engine::new(Options::default());
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        let mut report = Vec::new();
+        engine
+            .metrics()
+            .timing
+            .write_report(&mut report)
+            .expect("Something failed");
+        let report = String::from_utf8(report).unwrap();
+        let phases = report
+            .lines()
+            .map(|line| line.split_once(": ").expect("line has no phase").0)
+            .collect::<Vec<_>>();
+        assert_eq!(phases, ["parse", "apply", "write"]);
+    }
+
+    #[test]
+    fn reads_transaction_types_in_any_case() {
+        let input = "type, client, tx, amount
+Deposit, 1, 1, 3.0
+WITHDRAWAL, 1, 2, 1.0
+Dispute, 1, 1,
+resolve, 1, 1,";
+        let json = r#"{"type": "DEPOSIT", "client": 1, "tx": 1, "amount": 1.0}"#;
+
+        let mut source = CsvSource::new(input.as_bytes());
+        let mut types = Vec::new();
+        while let Some(transaction) = source.next() {
+            types.push(transaction.expect("Something failed").transaction_type());
+        }
+        types.push(
+            JsonLinesSource::new(json.as_bytes())
+                .next()
+                .unwrap()
+                .expect("Something failed")
+                .transaction_type(),
+        );
+        assert_eq!(
+            types,
+            [
+                TransactionType::Deposit,
+                TransactionType::Withdrawal,
+                TransactionType::Dispute,
+                TransactionType::Resolve,
+                TransactionType::Deposit
+            ]
+        );
+
+        let error = CsvSource::new("type, client, tx, amount\nrefund, 1, 1, 1.0".as_bytes())
+            .next()
+            .unwrap()
+            .expect_err("unknown type was accepted");
+        assert!(error.to_string().contains("unknown variant `refund`"));
+    }
+
+    #[test]
+    fn re_emits_a_messy_feed_in_canonical_form() {
+        let input = "type,   client,tx, amount, memo, idempotency_key
+  Deposit ,1,  1,   2 , refund 17
+WITHDRAWAL, 1, 2, 0.50, , w-2
+dispute, 1, 1
+deposit, 2, 3, 1.123456,";
+        let mut result = Vec::new();
+        canonicalize(CsvSource::new(input.as_bytes()), &mut result).expect("Something failed");
+
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "type,client,tx,amount,timestamp,memo,idempotency_key
+deposit,1,1,2.0000,,refund 17,
+withdrawal,1,2,0.5000,,,w-2
+dispute,1,1,,,,
+deposit,2,3,1.123456,,,
+"
+        );
+    }
+
+    #[test]
+    fn keeps_idempotency_keys_when_canonicalizing() {
+        let input = "type, client, tx, amount, timestamp, memo, idempotency_key
+deposit, 1, 1, 2.0, , , d-1
+deposit, 1, 2, 2.0, , , d-1";
+        let mut canonical = Vec::new();
+        canonicalize(CsvSource::new(input.as_bytes()), &mut canonical).expect("Something failed");
+
+        let options = Options {
+            idempotent: true,
+            ..Options::default()
+        };
+        let [original, canonical] = [input.as_bytes(), canonical.as_slice()].map(|feed| {
+            process_transactions(CsvSource::new(feed), Store::new(), &options)
+                .expect("Something failed")
+                .accounts[&Client::from(1)]
+                .total()
+        });
+        assert_eq!(original, dec!(2.0));
+        assert_eq!(canonical, original);
+    }
+
+    #[test]
+    fn rejects_amounts_over_the_cap() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 100.0
+deposit, 1, 2, 100.0001
+withdrawal, 1, 3, 250.0
+dispute, 1, 1,";
+        let mut engine = Engine::new(Options {
+            max_amount: Some(dec!(100)),
+            ..Options::default()
+        });
+        let errors = apply_each(&mut engine, CsvSource::new(input.as_bytes()))
+            .into_iter()
+            .filter_map(|result| result.err().map(|e| e.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            errors,
+            [
+                "Invalid input - amount 100.0001 on deposit 2 exceeds the limit of 100",
+                "Invalid input - amount 250.0 on withdrawal 3 exceeds the limit of 100"
+            ]
+        );
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held(), account.total()),
+            (dec!(0), dec!(100.0), dec!(100.0))
+        );
+        assert_eq!(engine.metrics().rows_rejected["amount_exceeds_limit"], 2);
+    }
+
+    #[test]
+    fn holds_the_exact_deposited_amount_after_balances_are_written_rounded() {
+        let amount = dec!(1.234567891234);
+        let mut engine = Engine::new(Options::default());
+        let input = format!("type, client, tx, amount\ndeposit, 7, 1, {amount}");
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        let output_options = OutputOptions {
+            balance_precision: 2,
+            ..OutputOptions::default()
+        };
+        engine
+            .write_accounts(io::sink(), &output_options)
+            .expect("Something failed");
+        engine
+            .normalize_client_ids(io::sink())
+            .expect("Something failed");
+        engine
+            .process(
+                CsvSource::new("type, client, tx\ndispute, 1, 1".as_bytes()),
+                |_| {},
+            )
+            .expect("Something failed");
+
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!(account.held(), amount);
+        assert_eq!(account.held().scale(), amount.scale());
+        assert_eq!(account.available(), dec!(0));
+    }
+
+    #[test]
+    fn rejects_transactions_a_custom_validator_refuses() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 100.0
+withdrawal, 1, 2, 60.0
+withdrawal, 1, 3, 20.0
+deposit, 1, 4, 75.0";
+        let mut engine = Engine::new(Options::default()).with_validator(|transaction| {
+            match (transaction.transaction_type(), transaction.get_amount()) {
+                (TransactionType::Withdrawal, Some(amount)) if amount > dec!(50) => {
+                    Err(format!("withdrawal of {amount} is over 50"))
+                }
+                _ => Ok(()),
+            }
+        });
+        let errors = apply_each(&mut engine, CsvSource::new(input.as_bytes()))
+            .into_iter()
+            .filter_map(|result| result.err().map(|e| e.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            errors,
+            ["Transaction 2 rejected by a validator - withdrawal of 60.0 is over 50"]
+        );
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!(account.total(), dec!(155.0));
+        assert_eq!(engine.metrics().rows_rejected["rejected_by_validator"], 1);
+    }
+
+    fn store_of(input: &str) -> Store {
+        process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed")
+    }
+
+    /// Applies the transactions of `source` one by one, as a caller driving the engine itself
+    /// would, with what each came to
+    fn apply_each(
+        engine: &mut Engine,
+        mut source: impl TransactionSource,
+    ) -> Vec<Result<(), TransactionError>> {
+        std::iter::from_fn(|| source.next())
+            .map(|transaction| engine.apply(transaction.expect("Something failed")))
+            .collect()
+    }
+
+    #[test]
+    fn merges_stores_of_disjoint_clients() {
+        let mut store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 3, 3, 3.0",
+        );
+        let other = store_of(
+            "type, client, tx, amount
+deposit, 2, 2, 2.0
+dispute, 2, 2,",
+        );
+
+        store.merge(other).expect("Something failed");
+
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &OutputOptions::default()).expect("Something failed");
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+2,0.0000,2.0,2.0,false
+3,3.0,0.0000,3.0,false
+"
+        );
+        assert_eq!(store.transactions.len(), 3);
+        assert!(store.transactions[&TransactionId::from(2)].get_is_under_dispute());
+    }
+
+    #[test_case("deposit, 1, 2, 5.0", "Cannot merge stores - client 1 has a different account in each"; "overlapping client")]
+    #[test_case("deposit, 2, 1, 5.0", "Cannot merge stores - transaction 1 is in both"; "overlapping transaction")]
+    fn refuses_to_merge_conflicting_stores(row: &str, error: &str) {
+        let mut store = store_of("type, client, tx, amount\ndeposit, 1, 1, 1.0");
+        let other = store_of(&format!("type, client, tx, amount\n{row}"));
+
+        assert_eq!(
+            store.merge(other).expect_err("stores merged").to_string(),
+            error
+        );
+        assert_eq!(store.clients().collect::<Vec<_>>(), [Client::from(1)]);
+    }
+
+    #[test_case("+1.5", Some(dec!(1.5)); "leading plus")]
+    #[test_case("+0", Some(dec!(0)); "plus zero")]
+    #[test_case("+-1.5", None; "two signs")]
+    #[test_case("++1.5", None; "two plus signs")]
+    fn reads_amounts_with_a_leading_plus(amount: &str, expected: Option<Decimal>) {
+        let input = format!("type, client, tx, amount\ndeposit, 1, 1, {amount}");
+        let parsed = CsvSource::new(input.as_bytes()).next().unwrap();
+
+        match expected {
+            Some(expected) => {
+                let transaction = parsed.expect("Something failed");
+                assert_eq!(transaction.get_amount(), Some(expected));
+                assert!(transaction.validate().is_ok());
+            }
+            None => assert!(parsed.is_err(), "{amount} was accepted"),
+        }
+    }
+
+    #[test]
+    fn reports_locked_accounts_with_the_charge_back_that_locked_them() {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 3.0
+deposit, 2, 3, 1.0
+deposit, 3, 4, 2.0
+dispute, 1, 2,
+chargeback, 1, 2,
+dispute, 3, 4,
+resolve, 3, 4,",
+        );
+
+        let mut result = Vec::new();
+        write_locked_report(&store, &mut result).expect("Something failed");
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "client,tx,total
+1,2,5.0
+"
+        );
+    }
+
+    #[test_case("2", Ok(dec!(0.5)); "whole amount within balance")]
+    #[test_case("3", Err("Invalid transaction - You cannot withdraw 3. It is less than 2.5 available in your account"); "whole amount over balance")]
+    fn withdraws_whole_amounts_from_a_fractional_balance(
+        amount: &str,
+        expected: Result<Decimal, &str>,
+    ) {
+        let input =
+            format!("type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdrawal, 1, 2, {amount}");
+        let mut engine = Engine::new(Options::default());
+        let results = apply_each(&mut engine, CsvSource::new(input.as_bytes()));
+
+        let account = engine.store().accounts[&Client::from(1)];
+        match expected {
+            Ok(available) => {
+                assert!(results[1].is_ok());
+                assert_eq!(account.available(), available);
+                assert_eq!(account.available().to_string(), "0.5");
+            }
+            Err(error) => {
+                assert_eq!(results[1].as_ref().unwrap_err().to_string(), error);
+                assert_eq!(account.available(), dec!(2.5));
+            }
+        }
+    }
+
+    #[test]
+    fn reads_a_latin1_feed() {
+        let input =
+            b"type, client, tx, amount, memo\ndeposit, 1, 1, 1.0, caf\xe9 \xa3\xff \x80 \x93ok\x94\n";
+
+        assert!(CsvSource::new(&input[..]).next().unwrap().is_err());
+        let transaction = CsvSource::new(Latin1Reader::new(&input[..]))
+            .next()
+            .unwrap()
+            .expect("Something failed");
+        assert_eq!(transaction.get_memo(), Some("café £ÿ € \u{201c}ok\u{201d}"));
+        assert_eq!(transaction.get_amount(), Some(dec!(1.0)));
+    }
+
+    #[test]
+    fn checks_the_accounts_add_up_to_the_transactions_applied() {
+        let mut store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 4.0
+withdrawal, 1, 3, 50.0
+deposit, 2, 4, 3.0
+withdrawal, 2, 5, 1.0
+dispute, 2, 4, 2.0
+chargeback, 2, 4,
+deposit, 3, 6, 2.0
+dispute, 3, 6,
+resolve, 3, 6,
+deposit, 2, 7, 5.0",
+        );
+        store.check_balanced().expect("Something failed");
+
+        store.transactions.remove(&TransactionId::from(2));
+        assert_eq!(
+            store
+                .check_balanced()
+                .expect_err("store balanced")
+                .to_string(),
+            "Unbalanced - accounts total 8.0, but the transactions applied add up to 12.0"
+        );
+    }
+
+    #[test]
+    fn reports_disputes_left_open_at_the_end_of_the_feed() {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 3.0
+deposit, 1, 3, 1.0
+deposit, 2, 4, 1.0
+deposit, 3, 5, 2.0
+dispute, 1, 1,
+dispute, 1, 3, 0.5
+dispute, 2, 4,
+resolve, 2, 4,
+dispute, 3, 5,
+chargeback, 3, 5,",
+        );
+
+        let mut result = Vec::new();
+        write_open_disputes(&store, &mut result).expect("Something failed");
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "client,held,open_disputes
+1,5.5,1 3
+"
+        );
+    }
+
+    #[test_case(dec!(1.5), Ok(dec!(1.5)); "positive")]
+    #[test_case(dec!(0), Ok(dec!(0)); "zero")]
+    #[test_case(dec!(-0.0), Ok(dec!(0)); "negative zero")]
+    #[test_case(dec!(-1.5), Err("-1.5 is negative"); "negative")]
+    fn constructs_amounts_only_from_non_negative_values(
+        value: Decimal,
+        expected: Result<Decimal, &str>,
+    ) {
+        assert_eq!(
+            Amount::new(value)
+                .map(Amount::value)
+                .map_err(|e| e.to_string()),
+            expected.map_err(str::to_string)
+        );
+    }
+
+    #[test_case(RedisputePolicy::Allow, None, (dec!(0), dec!(2.0)); "allow")]
+    #[test_case(RedisputePolicy::Reject, Some("Invalid transaction - Illegal dispute transition: cannot dispute transaction 1 while its dispute state is Resolved"), (dec!(2.0), dec!(0)); "reject")]
+    fn applies_the_redispute_policy_after_a_resolve(
+        policy: RedisputePolicy,
+        error: Option<&str>,
+        balances: (Decimal, Decimal),
+    ) {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 2.0
+dispute, 1, 1,
+resolve, 1, 1,
+dispute, 1, 1,";
+        let mut engine = Engine::new(Options {
+            redispute_policy: policy,
+            ..Options::default()
+        });
+        let results = apply_each(&mut engine, CsvSource::new(input.as_bytes()));
+
+        assert!(results[..3].iter().all(Result::is_ok));
+        assert_eq!(
+            results[3]
+                .as_ref()
+                .err()
+                .map(ToString::to_string)
+                .as_deref(),
+            error
+        );
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!((account.available(), account.held()), balances);
+    }
+
+    #[test_case("2.5", Ok((dec!(1203.7037), dec!(1203.7037))); "two and a half percent")]
+    #[test_case("100", Ok((dec!(0), dec!(0))); "everything")]
+    #[test_case("0", Ok((dec!(1234.5678), dec!(1234.5678))); "nothing")]
+    #[test_case("100.5", Err("Invalid input - fee of 100.5% is outside 0% to 100%"); "over a hundred percent")]
+    #[test_case("-1", Err("Invalid input - fee of -1% is outside 0% to 100%"); "negative percent")]
+    fn charges_a_percentage_fee_of_the_available_funds(
+        percent: &str,
+        expected: Result<(Decimal, Decimal), &str>,
+    ) {
+        let input = format!(
+            "type, client, tx, amount\ndeposit, 1, 1, 1234.5678\npercentage_fee, 1, 2, {percent}"
+        );
+        let mut engine = Engine::new(Options::default());
+        let results = apply_each(&mut engine, CsvSource::new(input.as_bytes()));
+
+        let account = engine.store().accounts[&Client::from(1)];
+        match expected {
+            Ok(balances) => {
+                assert!(results[1].is_ok());
+                assert_eq!((account.available(), account.total()), balances);
+            }
+            Err(error) => {
+                assert_eq!(results[1].as_ref().unwrap_err().to_string(), error);
+                assert_eq!(account.available(), dec!(1234.5678));
+            }
+        }
+        engine.store().check_balanced().expect("Something failed");
+    }
+
+    #[test_case(None, None, dec!(1203.7037) ; "output default of four places")]
+    #[test_case(Some(2), None, dec!(1203.7078) ; "configured precision")]
+    #[test_case(Some(2), Some(1), dec!(1203.8) ; "working scale")]
+    fn truncates_fees_to_the_configured_precision(
+        fee_precision: Option<u32>,
+        working_scale: Option<u32>,
+        available: Decimal,
+    ) {
+        let options = Options {
+            fee_precision,
+            working_scale,
+            ..Options::default()
+        };
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1234.5678\npercentage_fee, 1, 2, 2.5";
+        let store = process_transactions(CsvSource::new(input.as_bytes()), Store::new(), &options)
+            .expect("Something failed");
+
+        assert_eq!(store.accounts[&Client::from(1)].available(), available);
+    }
+
+    #[test]
+    fn refuses_to_dispute_a_fee() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+percentage_fee, 1, 2, 10
+dispute, 1, 2,";
+        let mut engine = Engine::new(Options::default());
+        let results = apply_each(&mut engine, CsvSource::new(input.as_bytes()));
+
+        assert_eq!(
+            results[2].as_ref().unwrap_err().to_string(),
+            "Invalid transaction - Transaction 2 is a fee, which cannot be disputed"
+        );
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!((account.available(), account.held()), (dec!(9.0), dec!(0)));
+    }
+
+    #[test_case(false, None; "ids may decrease")]
+    #[test_case(true, Some("Transaction id 2 is not greater than the earlier transaction id 5"); "ids must increase")]
+    fn applies_the_monotonic_tx_requirement(required: bool, error: Option<&str>) {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 5, 1.0
+dispute, 1, 1,
+resolve, 1, 1,
+withdrawal, 1, 2, 0.5
+deposit, 1, 6, 1.0";
+        let options = Options {
+            require_monotonic_tx: required,
+            ..Options::default()
+        };
+        let result = process_transactions(CsvSource::new(input.as_bytes()), Store::new(), &options);
+
+        match error {
+            None => {
+                let store = result.expect("Something failed");
+                assert_eq!(store.accounts[&Client::from(1)].total(), dec!(2.5));
+            }
+            Some(error) => assert_eq!(
+                result.expect_err("decreasing id was accepted").to_string(),
+                error
+            ),
+        }
+    }
+
+    #[test_case(DuplicateTxPolicy::First, Ok(dec!(2.0)); "first")]
+    #[test_case(DuplicateTxPolicy::Last, Ok(dec!(6.0)); "last")]
+    #[test_case(DuplicateTxPolicy::Reject, Err("Duplicate transaction id 1"); "reject")]
+    fn leaves_repeated_ids_to_the_duplicate_policy_when_ids_must_increase(
+        duplicate_tx_policy: DuplicateTxPolicy,
+        expected: Result<Decimal, &str>,
+    ) {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 1.0
+deposit, 1, 1, 5.0";
+        let options = Options {
+            require_monotonic_tx: true,
+            duplicate_tx_policy,
+            ..Options::default()
+        };
+        let result = process_transactions(CsvSource::new(input.as_bytes()), Store::new(), &options);
+
+        match expected {
+            Ok(total) => {
+                let store = result.expect("Something failed");
+                assert_eq!(store.accounts[&Client::from(1)].total(), total);
+            }
+            Err(error) => assert_eq!(
+                result.expect_err("repeated id was accepted").to_string(),
+                error
+            ),
+        }
+    }
+
+    #[test_case(false, "client,held
+5,9.0
+2,5.0
+1,1.0
+3,1.0
+4,0.0000
+" ; "held as kept")]
+    #[test_case(true, "client,held
+2,5.0
+1,1.0
+3,1.0
+4,0.0000
+5,0.0000
+" ; "held zeroed when locked")]
+    fn writes_the_accounts_holding_the_most_first(zero_held_when_locked: bool, expected: &str) {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 5.0
+deposit, 3, 3, 1.0
+deposit, 4, 4, 2.0
+dispute, 1, 1,
+dispute, 2, 2,
+dispute, 3, 3,
+deposit, 5, 5, 3.0
+deposit, 5, 6, 9.0
+dispute, 5, 6,
+dispute, 5, 5,
+chargeback, 5, 5,",
+        );
+        let output_options = OutputOptions {
+            sort: SortOrder::HeldDesc,
+            columns: vec![Column::Client, Column::Held],
+            zero_held_when_locked,
+            ..OutputOptions::default()
+        };
+
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &output_options).expect("Something failed");
+        assert_eq!(String::from_utf8(result).unwrap(), expected);
+    }
+
+    #[test_case("deposit, 1, 1, 1.0,\"first line\nsecond, line\""; "quote at field start")]
+    #[test_case("deposit, 1, 1, 1.0, \"first line\nsecond, line\""; "quote after padding")]
+    #[test_case("deposit, 1, 1, 1.0, \"first line\r\nsecond, line\" "; "crlf and trailing padding")]
+    fn reads_a_memo_spanning_lines_as_one_record(row: &str) {
+        let input = format!("type, client, tx, amount, memo\n{row}\nwithdrawal, 1, 2, 0.5");
+        let mut source = CsvSource::new(input.as_bytes());
+        let mut transactions = Vec::new();
+        while let Some(transaction) = source.next() {
+            transactions.push(transaction.expect("Something failed"));
+        }
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].get_amount(), Some(dec!(1.0)));
+        assert!(
+            transactions[0].get_memo().is_some_and(
+                |memo| memo.starts_with("first line") && memo.ends_with("\nsecond, line")
+            )
+        );
+        assert_eq!(transactions[1].transaction_id(), TransactionId::from(2));
+    }
+
+    #[test_case(AccountError::InsufficientFund { requested: dec!(2), available: dec!(1) }, ErrorCategory::ClientFault ; "insufficient fund")]
+    #[test_case(AccountError::InsufficientFundWhileHeld { requested: dec!(2), available: dec!(1), held: dec!(1) }, ErrorCategory::ClientFault ; "insufficient fund while held")]
+    #[test_case(AccountError::AccountLocked(Client::from(1)), ErrorCategory::ClientFault ; "account locked")]
+    #[test_case(AccountError::AccountClosed(Client::from(1)), ErrorCategory::ClientFault ; "account closed")]
+    #[test_case(AccountError::InvalidInput(anyhow::anyhow!("bad")), ErrorCategory::SystemError ; "invalid input")]
+    #[test_case(AccountError::ErroneousDispute(TransactionId::from(1)), ErrorCategory::PartnerError ; "erroneous dispute")]
+    #[test_case(AccountError::ErroneousResolve(TransactionId::from(1)), ErrorCategory::PartnerError ; "erroneous resolve")]
+    #[test_case(AccountError::ErroneousChargeback(TransactionId::from(1)), ErrorCategory::PartnerError ; "erroneous chargeback")]
+    #[test_case(AccountError::ForeignTransaction { transaction_id: TransactionId::from(1), client: Client::from(2) }, ErrorCategory::PartnerError ; "foreign transaction")]
+    #[test_case(AccountError::IllegalDisputeTransition { action: "resolve", transaction_id: TransactionId::from(1), state: DisputeState::Active }, ErrorCategory::PartnerError ; "illegal dispute transition")]
+    #[test_case(AccountError::UncorrectableTransaction(TransactionId::from(1)), ErrorCategory::PartnerError ; "uncorrectable transaction")]
+    #[test_case(AccountError::HeldUnderflow { transaction_id: TransactionId::from(1), requested: dec!(2), held: dec!(1) }, ErrorCategory::SystemError ; "held underflow")]
+    #[test_case(AccountError::UndisputableTransaction(TransactionId::from(1)), ErrorCategory::PartnerError ; "undisputable transaction")]
+    #[test_case(AccountError::UnappliedWithdrawal(TransactionId::from(1)), ErrorCategory::PartnerError ; "unapplied withdrawal")]
+    #[test_case(AccountError::DisputeExceedsAmount { transaction_id: TransactionId::from(1), requested: dec!(2), undisputed: dec!(1) }, ErrorCategory::PartnerError ; "dispute exceeds amount")]
+    #[test_case(AccountError::ChargebackExceedsHeld { transaction_id: TransactionId::from(1), disputed: dec!(2), held: dec!(1) }, ErrorCategory::PartnerError ; "chargeback exceeds held")]
+    fn categorizes_account_errors(error: AccountError, category: ErrorCategory) {
+        assert_eq!(error.category(), category);
+    }
+
+    #[test_case(TransactionError::AccountError(AccountError::AccountLocked(Client::from(1))), ErrorCategory::ClientFault ; "account error")]
+    #[test_case(TransactionError::InvalidAmount(dec!(-1)), ErrorCategory::PartnerError ; "invalid amount")]
+    #[test_case(TransactionError::NegativeDeposit(dec!(-1)), ErrorCategory::PartnerError ; "negative deposit")]
+    #[test_case(TransactionError::NegativeWithdrawal(dec!(-1)), ErrorCategory::PartnerError ; "negative withdrawal")]
+    #[test_case(TransactionError::InvalidPercentage(dec!(101)), ErrorCategory::PartnerError ; "invalid percentage")]
+    #[test_case(TransactionError::MissingAmount { transaction_type: TransactionType::Deposit, transaction_id: TransactionId::from(1) }, ErrorCategory::PartnerError ; "missing amount")]
+    #[test_case(TransactionError::AmountExceedsLimit { transaction_type: TransactionType::Deposit, transaction_id: TransactionId::from(1), amount: dec!(2), limit: dec!(1) }, ErrorCategory::ClientFault ; "amount exceeds limit")]
+    #[test_case(TransactionError::ClientLimitReached { client: Client::from(1), limit: 1 }, ErrorCategory::SystemError ; "client limit reached")]
+    #[test_case(TransactionError::DeniedClient(Client::from(1)), ErrorCategory::ClientFault ; "denied client")]
+    #[test_case(TransactionError::Rejected { transaction_id: TransactionId::from(1), reason: "flagged".to_string() }, ErrorCategory::ClientFault ; "rejected")]
+    #[test_case(TransactionError::NonMonotonicTransaction { transaction_id: TransactionId::from(1), previous: TransactionId::from(2) }, ErrorCategory::PartnerError ; "non monotonic transaction")]
+    #[test_case(TransactionError::DuplicateTransaction(TransactionId::from(1)), ErrorCategory::PartnerError ; "duplicate transaction")]
+    #[test_case(TransactionError::MissingTransactionId { transaction_type: TransactionType::Resolve, client: Client::from(1) }, ErrorCategory::PartnerError ; "missing transaction id")]
+    #[test_case(TransactionError::NoOpenDispute(Client::from(1)), ErrorCategory::PartnerError ; "no open dispute")]
+    #[test_case(TransactionError::Replayed(TransactionId::from(1)), ErrorCategory::PartnerError ; "replayed")]
+    #[test_case(TransactionError::Unknown(anyhow::anyhow!("bad")), ErrorCategory::SystemError ; "unknown")]
+    fn categorizes_transaction_errors(error: TransactionError, category: ErrorCategory) {
+        assert_eq!(error.category(), category);
+    }
+
+    #[test_case(4, 4, Column::DEFAULT.to_vec() ; "at four places")]
+    #[test_case(2, 28, Column::DEFAULT.to_vec() ; "at mixed places")]
+    #[test_case(4, 4, vec![Column::Total, Column::Memo, Column::Client] ; "with selected columns")]
+    fn round_trips_high_scale_amounts(
+        balance_precision: u32,
+        held_precision: u32,
+        columns: Vec<Column>,
+    ) {
+        let store = store_of(
+            "type, client, tx, amount, memo
+deposit, 1, 1, 1.123456789, first
+deposit, 1, 2, 0.00005, second
+deposit, 2, 3, 79228162514.264337593543950335,
+dispute, 2, 3,
+deposit, 3, 4, 2.99995, \"a, b\"
+withdrawal, 3, 5, 0.33333",
+        );
+        let options = OutputOptions {
+            balance_precision,
+            held_precision,
+            columns,
+            ..OutputOptions::default()
+        };
+
+        check_round_trip(&store, &options).expect("Something failed");
+    }
+
+    #[test_case("", dec!(1.0), 3 ; "skips the rows without the flag")]
+    #[test_case("$€", dec!(4.0), 1 ; "reads the amounts with the flag")]
+    fn strips_currency_symbols_from_amounts(symbols: &str, available: Decimal, unparsable: u64) {
+        let csv = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, $1.50
+deposit, 1, 3, €$1.50
+deposit, 1, 4, 1.5$";
+        let json = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.0}
+{"type": "deposit", "client": 1, "tx": 2, "amount": "$1.50"}
+{"type": "deposit", "client": 1, "tx": 3, "amount": "€$1.50"}
+{"type": "deposit", "client": 1, "tx": 4, "amount": "1.5$"}"#;
+
+        let mut csv_engine = Engine::new(Options::default());
+        csv_engine
+            .process(
+                CsvSource::new(csv.as_bytes()).strip_symbols(symbols),
+                |_| {},
+            )
+            .expect("Something failed");
+        let mut json_engine = Engine::new(Options::default());
+        json_engine
+            .process(
+                JsonLinesSource::new(json.as_bytes()).strip_symbols(symbols),
+                |_| {},
+            )
+            .expect("Something failed");
+
+        for engine in [csv_engine, json_engine] {
+            let account = engine.store().accounts[&Client::from(1)];
+            assert_eq!(account.available(), available);
+            assert_eq!(engine.metrics().rows_read, 4);
+            assert_eq!(
+                engine.metrics().rows_rejected["unparsable_amount"],
+                unparsable
+            );
+        }
+    }
+
+    #[test]
+    fn writes_no_header_when_disabled() {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 2, 1, 1.5
+deposit, 1, 2, 2.0",
+        );
+        let options = OutputOptions {
+            header: false,
+            ..OutputOptions::default()
+        };
+
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &options).expect("Something failed");
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "1,2.0,0.0000,2.0,false
+2,1.5,0.0000,1.5,false
+"
+        );
+    }
+
+    #[test]
+    fn settles_the_oldest_open_dispute_first_without_a_tx() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+deposit, 2, 3, 4.0
+dispute, 1, 2,
+dispute, 2, 3,
+dispute, 1, 1,
+resolve, 1, ,
+resolve, 1, 3,
+deposit, 1, , 1.0";
+        let options = Options {
+            fifo_disputes: true,
+            ..Options::default()
+        };
+        let mut engine = Engine::new(options);
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        let store = engine.store();
+        assert_eq!(
+            store.transactions[&TransactionId::from(2)].get_dispute_state(),
+            DisputeState::Resolved
+        );
+        assert!(store.transactions[&TransactionId::from(1)].get_is_under_dispute());
+        assert!(store.transactions[&TransactionId::from(3)].get_is_under_dispute());
+        assert_eq!(engine.metrics().rows_rejected["foreign_transaction"], 1);
+        assert_eq!(engine.metrics().rows_rejected["missing_transaction_id"], 1);
+
+        engine
+            .apply(
+                CsvSource::new("type, client, tx\nchargeback, 1,".as_bytes())
+                    .next()
+                    .unwrap()
+                    .unwrap(),
+            )
+            .expect("Something failed");
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held(), account.is_locked()),
+            (dec!(2.0), dec!(0), true)
+        );
+        let resolve = || {
+            CsvSource::new("type, client, tx\nresolve, 2,".as_bytes())
+                .next()
+                .unwrap()
+                .unwrap()
+        };
+        engine.apply(resolve()).expect("Something failed");
+        assert_eq!(
+            engine
+                .apply(resolve())
+                .expect_err("resolve was accepted")
+                .to_string(),
+            "Client 2 has no open dispute to settle"
+        );
+    }
+
+    #[test]
+    fn rejects_a_resolve_without_a_tx_by_default() {
+        let mut engine = Engine::new(Options::default());
+        engine
+            .process(
+                CsvSource::new(
+                    "type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1,
+resolve, 1, ,"
+                        .as_bytes(),
+                ),
+                |_| {},
+            )
+            .expect("Something failed");
+
+        assert!(engine.store().transactions[&TransactionId::from(1)].get_is_under_dispute());
+        assert_eq!(engine.metrics().rows_rejected["missing_transaction_id"], 1);
+    }
+
+    #[test_case(false, dec!(0.6) ; "applies a repeated partial dispute by default")]
+    #[test_case(true, dec!(0.3) ; "holds a redelivered dispute only once when idempotent")]
+    fn ignores_redelivered_disputes_when_idempotent(idempotent: bool, held: Decimal) {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1, 0.3
+dispute, 1, 1, 0.3";
+        let options = Options {
+            idempotent,
+            ..Options::default()
+        };
+        let mut engine = Engine::new(options);
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!((account.held(), account.total()), (held, dec!(1.0)));
+    }
+
+    #[test]
+    fn applies_a_dispute_raised_again_after_a_resolve_when_idempotent() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1,
+resolve, 1, 1,
+resolve, 1, 1,
+dispute, 1, 1,
+dispute, 1, 1,";
+        let options = Options {
+            idempotent: true,
+            ..Options::default()
+        };
+        let mut engine = Engine::new(options);
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!((account.held(), account.total()), (dec!(1.0), dec!(1.0)));
+        // Only the redelivered resolve and second dispute are taken for replays
+        assert_eq!(engine.metrics().rows_rejected["replayed"], 2);
+    }
+
+    #[test]
+    fn tells_deliveries_apart_by_idempotency_key() {
+        let input = "type, client, tx, amount, idempotency_key
+deposit, 1, 1, 1.0, a
+deposit, 1, 1, 1.0, a
+dispute, 1, 1, 0.3, b
+dispute, 1, 1, 0.3, c
+dispute, 1, 1, 0.3, c
+withdrawal, 1, 2, 0.1,
+withdrawal, 1, 2, 0.1,";
+        let options = Options {
+            idempotent: true,
+            ..Options::default()
+        };
+        let mut engine = Engine::new(options);
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held(), account.total()),
+            (dec!(0.3), dec!(0.6), dec!(0.9))
+        );
+        assert_eq!(engine.metrics().rows_rejected["replayed"], 3);
+    }
+
+    #[test_case(
+"client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,0.0000,2.0000,2.0000,false
+3,0.0000,0.0000,0.0000,true
+",
+0,
+"";
+
+"passes against a matching baseline"
+)]
+    #[test_case(
+"client,available,held,total,locked
+1,1.4,0,1.4,false
+3,0,0,0,false
+4,1,0,1,false
+",
+4,
+"client,field,baseline,actual
+1,available,1.4,1.5
+1,total,1.4,1.5
+2,account,missing,present
+2,available,,0.0000
+2,held,,2.0
+2,total,,2.0
+2,locked,,false
+3,locked,false,true
+4,account,present,missing
+4,available,1,
+4,held,0.0000,
+4,total,1,
+4,locked,false,
+";
+
+"reports every field differing from a wrong baseline"
+)]
+    fn compares_accounts_with_a_baseline(baseline: &str, differing: usize, diff: &str) {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 1.5
+deposit, 2, 2, 2.0
+dispute, 2, 2,
+deposit, 3, 3, 1.0
+dispute, 3, 3,
+chargeback, 3, 3,",
+        );
+        let baseline = load_seed(baseline.as_bytes()).expect("Something failed");
+
+        let mut result = Vec::new();
+        assert_eq!(
+            write_baseline_diff(&store, &baseline, &mut result, &OutputOptions::default())
+                .expect("Something failed"),
+            differing
+        );
+        assert_eq!(String::from_utf8(result).unwrap(), diff);
+    }
+
+    #[test_case(4, 4 ; "default precision")]
+    #[test_case(2, 4 ; "balance precision")]
+    #[test_case(2, 1 ; "held precision")]
+    #[test_case(0, 28 ; "full held precision")]
+    fn passes_against_a_baseline_of_its_own_output(balance_precision: u32, held_precision: u32) {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 1.005
+deposit, 2, 2, 2.12345
+dispute, 2, 2,
+deposit, 3, 3, 0.5",
+        );
+        let options = OutputOptions {
+            balance_precision,
+            held_precision,
+            ..OutputOptions::default()
+        };
+        let mut written = Vec::new();
+        write_accounts(&store, &mut written, &options).expect("Something failed");
+        let baseline = load_seed(written.as_slice()).expect("Something failed");
+
+        let mut result = Vec::new();
+        assert_eq!(
+            write_baseline_diff(&store, &baseline, &mut result, &options)
+                .expect("Something failed"),
+            0,
+            "{}",
+            String::from_utf8(result).unwrap()
+        );
+    }
+
+    #[test]
+    fn fails_the_batch_on_a_promoted_partner_error() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 5.0
+chargeback, 1, 1,
+dispute, 1, 9,
+deposit, 1, 3, 1.0";
+        let options = Options {
+            promoted_errors: [TransactionType::Dispute].into(),
+            ..Options::default()
+        };
+
+        let result = process_transactions(CsvSource::new(input.as_bytes()), Store::new(), &options);
+        assert_eq!(
+            result
+                .expect_err("erroneous dispute was accepted")
+                .to_string(),
+            "Invalid transaction - Erroneous dispute: Transaction id 9 does not exist"
+        );
+
+        let store = store_of(input);
+        assert_eq!(store.accounts[&Client::from(1)].total(), dec!(2.0));
+    }
+
+    #[test]
+    fn skips_a_header_repeated_mid_stream() {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 0.5
+type, client, tx, amount
+deposit, 1, 3, 2.0
+TYPE,Client,TX,Amount
+withdrawal, 1, 4, 0.25",
+        );
+
+        assert_eq!(store.accounts[&Client::from(1)].available(), dec!(2.25));
+        assert_eq!(store.transactions.len(), 4);
+    }
+
+    #[test_case(false, "client,available,held,total,locked
+1,0.0000,2.0,2.0,true
+2,1.0,0.0000,1.0,false
+" ; "raw held by default")]
+    #[test_case(true, "client,available,held,total,locked
+1,0.0000,0.0000,2.0,true
+2,1.0,0.0000,1.0,false
+" ; "zero held when locked")]
+    fn writes_held_of_locked_accounts(zero_held_when_locked: bool, expected: &str) {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 3.0
+deposit, 1, 2, 2.0
+dispute, 1, 2,
+dispute, 1, 1,
+chargeback, 1, 1,
+deposit, 2, 3, 1.0",
+        );
+        let options = OutputOptions {
+            zero_held_when_locked,
+            ..OutputOptions::default()
+        };
+
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &options).expect("Something failed");
+        assert_eq!(String::from_utf8(result).unwrap(), expected);
+        assert_eq!(store.accounts[&Client::from(1)].held(), dec!(2.0));
+        check_round_trip(&store, &options).expect("Something failed");
+    }
+
+    #[test]
+    fn snapshots_the_accounts_mid_stream() {
+        let transactions = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+dispute, 1, 1,
+withdrawal, 2, 3, 0.5
+deposit, 3, 4, 3.0";
+        let mut source = CsvSource::new(transactions.as_bytes());
+        let mut engine = Engine::new(Options::default());
+
+        for _ in 0..3 {
+            engine
+                .apply(source.next().unwrap().unwrap())
+                .expect("Something failed");
+        }
+        let snapshot = engine.snapshot();
+        for result in apply_each(&mut engine, source) {
+            result.expect("Something failed");
+        }
+
+        assert_eq!(
+            snapshot,
+            [
+                AccountSnapshot {
+                    client: Client::from(1),
+                    available: dec!(0),
+                    held: dec!(1.0),
+                    total: dec!(1.0),
+                    locked: false,
+                },
+                AccountSnapshot {
+                    client: Client::from(2),
+                    available: dec!(2.0),
+                    held: dec!(0),
+                    total: dec!(2.0),
+                    locked: false,
+                },
+            ]
+        );
+        assert_eq!(engine.snapshot().len(), 3);
+        assert_eq!(engine.snapshot()[1].available, dec!(1.5));
+    }
+
+    #[test_case("\"1,234.56\"", true, Some(dec!(1234.56)) ; "grouped thousands")]
+    #[test_case("\"-12,345,678\"", true, Some(dec!(-12345678)) ; "several groups")]
+    #[test_case("1234.56", true, Some(dec!(1234.56)) ; "ungrouped")]
+    #[test_case("\"1,5\"", true, None ; "decimal comma")]
+    #[test_case("\"1,2345.6\"", true, None ; "uneven group")]
+    #[test_case("\"1,234.56\"", false, None ; "grouped thousands when disabled")]
+    fn reads_amounts_with_thousands_separators(
+        amount: &str,
+        enabled: bool,
+        expected: Option<Decimal>,
+    ) {
+        let input = format!("type, client, tx, amount\ndeposit, 1, 1, {amount}");
+        let parsed = CsvSource::new(input.as_bytes())
+            .thousands_separators(enabled)
+            .next()
+            .unwrap();
+
+        match expected {
+            Some(expected) => assert_eq!(
+                parsed.expect("Something failed").get_amount(),
+                Some(expected)
+            ),
+            None => assert!(matches!(
+                parsed.expect_err("amount was accepted"),
+                ParseError::UnparsableAmount(_)
+            )),
+        }
+    }
+
+    #[test]
+    fn allocation_light_reading_matches_deserializing() {
+        let input = "type, client, tx, amount, timestamp, memo, idempotency_key, channel
+Deposit, 1, 1, \"$1,234.5\", 1700000000, \" first, of two \", key-1, web
+deposit, 2, 2, 3/4
+withdrawal, 1, 3, 0.25, , , , app
+type, client, tx, amount, timestamp, memo, idempotency_key, channel
+dispute, 1, 1,
+deposit, 2, 4, 1.x
+DISPUTE, 2, 2, 0.5
+resolve, 2, , , 1700000100, \"multi
+line\"
+chargeback, 1, 1,";
+        let source = |allocation_light| {
+            CsvSource::new(input.as_bytes())
+                .strip_symbols("$")
+                .thousands_separators(true)
+                .amount_fractions(true)
+                .allocation_light(allocation_light)
+        };
+
+        let read = |allocation_light| {
+            let mut source = source(allocation_light);
+            std::iter::from_fn(|| source.next())
+                .map(|parsed| match parsed {
+                    Ok(transaction) => format!("{transaction:?}"),
+                    Err(error) => format!("{error}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(read(true), read(false));
+        assert_eq!(read(true).len(), 8);
+
+        let written = |allocation_light| {
+            let options = Options {
+                fifo_disputes: true,
+                ..Options::default()
+            };
+            let store = process_transactions(source(allocation_light), Store::new(), &options)
+                .expect("Something failed");
+            let mut output = Vec::new();
+            write_accounts(&store, &mut output, &OutputOptions::default()).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(written(true), written(false));
+    }
+
+    #[test]
+    fn dumps_the_transactions_with_their_dispute_state() {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 1.25
+deposit, 2, 2, 2.0
+dispute, 1, 1,
+withdrawal, 2, 3, 5.0
+dispute, 2, 2,
+resolve, 2, 2,",
+        );
+
+        let mut dump = Vec::new();
+        write_transaction_dump(&store, &mut dump).expect("Something failed");
+
+        assert_eq!(
+            String::from_utf8(dump).unwrap(),
+            "client,tx,type,amount,applied,under_dispute,dispute_state,disputed_amount
+1,1,deposit,1.25,true,true,disputed,1.25
+2,2,deposit,2.0,true,false,resolved,0
+2,3,withdrawal,5.0,false,false,active,0
+"
+        );
+    }
+
+    #[test_case(true, (dec!(0), true, Some(&1)) ; "closed")]
+    #[test_case(false, (dec!(2.0), false, None) ; "left open")]
+    fn closes_accounts_drained_to_zero(
+        close_zero_accounts: bool,
+        expected: (Decimal, bool, Option<&u64>),
+    ) {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.5
+withdrawal, 1, 2, 1.0
+withdrawal, 1, 3, 0.5
+deposit, 1, 4, 2.0";
+        let options = Options {
+            close_zero_accounts,
+            ..Options::default()
+        };
+        let mut engine = Engine::new(options);
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        let account = engine.store().accounts[&Client::from(1)];
+        assert_eq!(
+            (
+                account.total(),
+                account.is_closed(),
+                engine.metrics().rows_rejected.get("account_closed")
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn writes_accounts_in_client_order_whatever_the_feed_order() {
+        let input = "type, client, tx, amount
+deposit, 3, 1, 3.0
+deposit, 1, 2, 1.0
+deposit, 200, 3, 2.0
+deposit, 2, 4, 2.0";
+        let reversed = "type, client, tx, amount
+deposit, 2, 4, 2.0
+deposit, 200, 3, 2.0
+deposit, 1, 2, 1.0
+deposit, 3, 1, 3.0";
+
+        let written = |input: &str| {
+            let mut output = Vec::new();
+            write_accounts(&store_of(input), &mut output, &OutputOptions::default()).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(written(input), written(reversed));
+        assert_eq!(
+            account_snapshots(written(input).as_bytes())
+                .iter()
+                .map(|account| account.client)
+                .collect::<Vec<_>>(),
+            [1, 2, 3, 200].map(Client::from)
+        );
+    }
+
+    #[test_case("deposit", None, (dec!(2.0), dec!(1.0), dec!(3.0), false) ; "deposit disputed")]
+    #[test_case("deposit", Some("resolve"), (dec!(3.0), dec!(0), dec!(3.0), false) ; "deposit resolved")]
+    #[test_case("deposit", Some("chargeback"), (dec!(2.0), dec!(0), dec!(2.0), true) ; "deposit charged back")]
+    #[test_case("withdrawal", None, (dec!(1.0), dec!(1.0), dec!(2.0), false) ; "withdrawal disputed")]
+    #[test_case("withdrawal", Some("resolve"), (dec!(1.0), dec!(0), dec!(1.0), false) ; "withdrawal resolved")]
+    #[test_case("withdrawal", Some("chargeback"), (dec!(2.0), dec!(0), dec!(2.0), true) ; "withdrawal charged back")]
+    fn settles_disputes_in_the_direction_of_the_disputed_transaction(
+        disputed: &str,
+        settlement: Option<&str>,
+        expected: (Decimal, Decimal, Decimal, bool),
+    ) {
+        let settlement = settlement.map_or(String::new(), |kind| format!("\n{kind}, 1, 2,"));
+        let store = store_of(&format!(
+            "type, client, tx, amount
+deposit, 1, 1, 2.0
+{disputed}, 1, 2, 1.0
+dispute, 1, 2,{settlement}"
+        ));
+
+        let account = store.accounts[&Client::from(1)];
+        assert_eq!(
+            (
+                account.available(),
+                account.held(),
+                account.total(),
+                account.is_locked()
+            ),
+            expected
+        );
+        assert!(store.check_balanced().is_ok());
+    }
+
+    #[test]
+    fn rejects_disputes_of_refused_withdrawals() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 5.0
+dispute, 1, 2,";
+        let mut engine = Engine::new(Options::default());
+        engine
+            .process(CsvSource::new(input.as_bytes()), |_| {})
+            .expect("Something failed");
+
+        assert_eq!(engine.metrics().rows_rejected["unapplied_withdrawal"], 1);
+        assert_eq!(engine.store().accounts[&Client::from(1)].held(), dec!(0));
+    }
+
+    #[test_case(Some(1_654_086_600), "# generated_at=2022-06-01T12:30:00Z, rows=2\n" ; "on")]
+    #[test_case(Some(951_827_696), "# generated_at=2000-02-29T12:34:56Z, rows=2\n" ; "on a leap day")]
+    #[test_case(None, "" ; "off")]
+    fn writes_a_metadata_line_before_the_header(generated_at: Option<u64>, metadata: &str) {
+        let store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0",
+        );
+        let options = OutputOptions {
+            generated_at,
+            ..OutputOptions::default()
+        };
+        let mut output = Vec::new();
+        write_accounts(&store, &mut output, &options).expect("Something failed");
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            format!(
+                "{metadata}client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+2,2.0,0.0000,2.0,false
+"
+            )
+        );
+        let skipping_comments = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .from_reader(output.as_bytes())
+            .records()
+            .count();
+        assert_eq!(skipping_comments, 2);
+    }
+
+    #[test_case(None, "", (dec!(0.005), dec!(0.335), dec!(0.34)) ; "output only while disputed")]
+    #[test_case(Some(2), "", (dec!(0.02), dec!(0.34), dec!(0.36)) ; "each operation while disputed")]
+    #[test_case(None, "\nresolve, 1, 1,", (dec!(0.34), dec!(0), dec!(0.34)) ; "output only once resolved")]
+    #[test_case(Some(2), "\nresolve, 1, 1,", (dec!(0.36), dec!(0), dec!(0.36)) ; "each operation once resolved")]
+    fn rounds_balances_at_the_working_scale(
+        working_scale: Option<u32>,
+        settlement: &str,
+        expected: (Decimal, Decimal, Decimal),
+    ) {
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, 0.335
+deposit, 1, 2, 0.335
+withdrawal, 1, 3, 0.33
+dispute, 1, 1,{settlement}"
+        );
+        let options = Options {
+            working_scale,
+            ..Options::default()
+        };
+        let store = process_transactions(CsvSource::new(input.as_bytes()), Store::new(), &options)
+            .expect("Something failed");
+
+        let account = store.accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held(), account.total()),
+            expected
+        );
+        assert_eq!(store.check_balanced().is_ok(), working_scale.is_none());
+    }
+
+    #[test_case("0.333", "resolve", (dec!(1.00), dec!(0), dec!(1.00)) ; "resolve")]
+    #[test_case("0.333", "chargeback", (dec!(0.67), dec!(0), dec!(0.67)) ; "charge back")]
+    #[test_case("0.334", "chargeback", (dec!(0.67), dec!(0), dec!(0.67)) ; "charge back rounding down")]
+    #[test_case("0.335", "resolve", (dec!(1.00), dec!(0), dec!(1.00)) ; "resolve rounding up")]
+    fn settles_disputes_finer_than_the_working_scale(
+        portion: &str,
+        settlement: &str,
+        expected: (Decimal, Decimal, Decimal),
+    ) {
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 1, {portion}
+{settlement}, 1, 1,"
+        );
+        let options = Options {
+            working_scale: Some(2),
+            ..Options::default()
+        };
+        let mut rejections = Vec::new();
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &options,
+            |event| rejections.extend(event.result.as_ref().err().map(ToString::to_string)),
+        )
+        .expect("Something failed");
+
+        assert!(rejections.is_empty(), "{rejections:?}");
+        let account = store.accounts[&Client::from(1)];
+        assert_eq!(
+            (account.available(), account.held(), account.total()),
+            expected
+        );
+        assert_eq!(account.is_locked(), settlement == "chargeback");
+    }
+
+    #[test]
+    fn paces_rows_at_the_replay_speed() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 1.0
+deposit, 1, 3, 1.0
+deposit, 1, 4, 1.0
+withdrawal, 1, 5, 0.5";
+        let options = Options {
+            replay_speed: NonZeroU32::new(50),
+            ..Options::default()
+        };
+        let started = Instant::now();
+        let store = process_transactions(CsvSource::new(input.as_bytes()), Store::new(), &options)
+            .expect("Something failed");
+
+        // The first row is due at once and each of the other four a fiftieth of a second later
+        assert!(started.elapsed() >= Duration::from_millis(80));
+        assert_eq!(
+            store.accounts[&Client::from(1)].total(),
+            store_of(input).accounts[&Client::from(1)].total()
+        );
+    }
+
+    #[test_case("1_000.50", true, Some(dec!(1000.50)) ; "grouped digits")]
+    #[test_case("0.000_1", true, Some(dec!(0.0001)) ; "grouped fraction")]
+    #[test_case("1000.50", true, Some(dec!(1000.50)) ; "ungrouped")]
+    #[test_case("1__000", true, None ; "doubled underscore")]
+    #[test_case("_1", true, None ; "leading underscore")]
+    #[test_case("1.5_", true, None ; "trailing underscore")]
+    #[test_case("1_000.50", false, None ; "grouped digits when disabled")]
+    fn reads_amounts_with_underscores(amount: &str, enabled: bool, expected: Option<Decimal>) {
+        let input = format!("type, client, tx, amount\ndeposit, 1, 1, {amount}");
+        for allocation_light in [false, true] {
+            let parsed = CsvSource::new(input.as_bytes())
+                .strip_underscores(enabled)
+                .allocation_light(allocation_light)
+                .next()
+                .unwrap();
+
+            match expected {
+                Some(expected) => assert_eq!(
+                    parsed.expect("Something failed").get_amount(),
+                    Some(expected)
+                ),
+                None => assert!(matches!(
+                    parsed.expect_err("amount was accepted"),
+                    ParseError::UnparsableAmount(_)
+                )),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_what_each_dispute_action_did() {
+        let mut store = store_of(
+            "type, client, tx, amount
+deposit, 1, 1, 1.5
+deposit, 1, 2, 2.0",
+        );
+        let mut account = store.account(Client::from(1)).expect("Something failed");
+
+        let disputed = account
+            .apply_dispute(TransactionId::from(1), &mut store)
+            .expect("Something failed");
+        assert_eq!(
+            disputed,
+            DisputeOutcome {
+                held_delta: dec!(1.5),
+                available_delta: dec!(-1.5),
+                total_delta: dec!(0),
+                new_state: DisputeState::Disputed,
+            }
+        );
+
+        let charged_back = account
+            .apply_chargeback(TransactionId::from(1), &mut store)
+            .expect("Something failed");
+        assert_eq!(
+            charged_back,
+            DisputeOutcome {
+                held_delta: dec!(-1.5),
+                available_delta: dec!(0),
+                total_delta: dec!(-1.5),
+                new_state: DisputeState::ChargedBack,
+            }
+        );
+        assert_eq!(store.account(Client::from(1)), Some(account));
+        assert!(account.is_locked());
+
+        assert!(account
+            .apply_resolve(TransactionId::from(2), &mut store)
+            .is_err());
+        assert_eq!(store.account(Client::from(1)), Some(account));
+    }
+
+    fn shard_snapshots(
+        input: &str,
+        range: ByteRange,
+    ) -> (Vec<AccountSnapshot>, Vec<ErrorCategory>) {
+        let reader =
+            read_byte_range(io::Cursor::new(input), range, true).expect("Something failed");
+        let mut rejections = Vec::new();
+        let (store, _) = process_transactions_with(
+            CsvSource::new(reader),
+            Store::new(),
+            &Options::default(),
+            |event| {
+                if let Err(e) = event.result {
+                    rejections.push(e.category());
+                }
+            },
+        )
+        .expect("Something failed");
+        let mut output = Vec::new();
+        write_accounts(&store, &mut output, &OutputOptions::default()).expect("Something failed");
+        (account_snapshots(&output), rejections)
+    }
+
+    #[test]
+    fn merges_byte_range_shards_into_the_full_run() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 1, 3, 2.0
+deposit, 2, 4, 1.0
+dispute, 2, 4,
+dispute, 1, 1,
+deposit, 3, 5, 3.0
+";
+        // Cut partway through the withdrawal, which stays with the first shard
+        let cut = input.find("withdrawal").unwrap() as u64 + 4;
+        let len = input.len() as u64;
+
+        let (full, _) = shard_snapshots(input, ByteRange { start: 0, end: len });
+        let (first, first_rejections) = shard_snapshots(input, ByteRange { start: 0, end: cut });
+        let (second, second_rejections) = shard_snapshots(
+            input,
+            ByteRange {
+                start: cut,
+                end: len,
+            },
+        );
+
+        let mut merged = BTreeMap::<Client, (Decimal, Decimal, Decimal)>::new();
+        for account in first.iter().chain(&second) {
+            let balances = merged.entry(account.client).or_default();
+            balances.0 += account.available;
+            balances.1 += account.held;
+            balances.2 += account.total;
+        }
+
+        assert!(first_rejections.is_empty());
+        // The dispute of tx 1 is in the second shard, which never saw the deposit
+        assert_eq!(second_rejections, [ErrorCategory::PartnerError]);
+        assert_eq!(merged[&Client::from(1)], (dec!(8), dec!(0), dec!(8)));
+        for account in &full {
+            let (available, held, total) = merged[&account.client];
+            assert_eq!(total, account.total);
+            if account.client != Client::from(1) {
+                assert_eq!((available, held), (account.available, account.held));
+            }
+        }
+        assert_eq!(merged.len(), full.len());
+    }
+
+    #[test_case("0:10", Ok(ByteRange { start: 0, end: 10 }); "range")]
+    #[test_case("10:10", Ok(ByteRange { start: 10, end: 10 }); "empty range")]
+    #[test_case("10:5", Err(ByteRangeError::Backwards { start: 10, end: 5 }); "backwards")]
+    #[test_case("10", Err(ByteRangeError::Malformed("10".to_owned())); "no end")]
+    #[test_case("a:10", Err(ByteRangeError::Malformed("a:10".to_owned())); "not a number")]
+    fn parses_byte_ranges(range: &str, expected: Result<ByteRange, ByteRangeError>) {
+        assert_eq!(range.parse::<ByteRange>(), expected);
+    }
+
+    #[test]
+    fn shares_no_record_between_adjacent_byte_ranges() {
+        let input =
+            "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\ndeposit, 1, 3, 4.0";
+        let len = input.len() as u64;
+        for cut in 0..=len {
+            let (first, _) = shard_snapshots(input, ByteRange { start: 0, end: cut });
+            let (second, _) = shard_snapshots(
+                input,
+                ByteRange {
+                    start: cut,
+                    end: len,
+                },
+            );
+            let total: Decimal = first
+                .iter()
+                .chain(&second)
+                .map(|account| account.total)
+                .sum();
+            assert_eq!(total, dec!(7), "cut at {cut}");
+        }
+    }
+
+    #[test]
+    fn traces_balances_at_the_output_precision() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.2345
+withdrawal, 1, 2, 0.0045";
+        let mut output = Vec::new();
+        let mut trace = ClientTrace::new(Client::from(1), 2, &mut output);
+        process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| trace.record(event),
+        )
+        .expect("Something failed");
+        trace.finish().expect("Something failed");
+
+        let available = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let event: serde_json::Value =
+                    serde_json::from_str(line).expect("Something failed");
+                event["available"].to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(available, ["1.23", "1.23"]);
+    }
+
+    #[test]
+    fn traces_one_clients_events_as_json_lines() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.5
+deposit, 2, 2, 2.0
+dispute, 1, 1,
+withdrawal, 1, 3, 1.0
+resolve, 1, 1,";
+        let mut output = Vec::new();
+        let mut trace = ClientTrace::new(Client::from(1), 4, &mut output);
+        process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| trace.record(event),
+        )
+        .expect("Something failed");
+        trace.finish().expect("Something failed");
+
+        let events = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("Something failed"))
+            .collect::<Vec<_>>();
+        let fields = |event: &serde_json::Value| {
+            let number = |field: &str| {
+                event[field]
+                    .as_str()
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| event[field].to_string())
+            };
+            (
+                event["type"].as_str().unwrap().to_owned(),
+                event["tx"].as_u64().unwrap(),
+                number("amount"),
+                event["applied"].as_bool().unwrap(),
+                number("available"),
+                number("held"),
+                number("total"),
+                event["locked"].as_bool().unwrap(),
+            )
+        };
+        let expected = [
+            ("deposit", 1, "1.5", true, "1.5", "0.0000", "1.5"),
+            ("dispute", 1, "null", true, "0.0000", "1.5", "1.5"),
+            ("withdrawal", 3, "1.0", false, "0.0000", "1.5", "1.5"),
+            ("resolve", 1, "null", true, "1.5", "0.0000", "1.5"),
+        ];
+
+        assert_eq!(events.len(), expected.len());
+        for (event, (kind, tx, amount, applied, available, held, total)) in
+            events.iter().zip(expected)
+        {
+            assert_eq!(
+                fields(event),
+                (
+                    kind.to_owned(),
+                    tx,
+                    amount.to_owned(),
+                    applied,
+                    available.to_owned(),
+                    held.to_owned(),
+                    total.to_owned(),
+                    false
+                )
+            );
+            assert_eq!(event.get("error").is_some(), !applied);
+        }
+    }
+
+    #[test_case("# re-keyed in March\n5,1\n", "resolve", "client,available,held,total,locked
+1,10.0,0.0000,10.0,false
+5,3.0,0.0000,3.0,false
+"; "resolve under the alias")]
+    #[test_case("5,1\n", "chargeback", "client,available,held,total,locked
+1,0.0000,0.0000,0.0000,true
+5,3.0,0.0000,3.0,false
+"; "charge back under the alias")]
+    #[test_case("", "resolve", "client,available,held,total,locked
+1,10.0,0.0000,10.0,false
+5,3.0,0.0000,3.0,false
+"; "no aliases")]
+    fn settles_disputes_under_an_aliased_client_id(
+        aliases: &str,
+        settlement: &str,
+        expected: &str,
+    ) {
+        let options = Options {
+            client_aliases: load_client_aliases(aliases.as_bytes()).expect("Something failed"),
+            ..Options::default()
+        };
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 5, 2, 3.0
+dispute, 5, 1,
+{settlement}, 5, 1,"
+        );
+        let mut rejections = Vec::new();
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &options,
+            |event| {
+                if let Err(e) = event.result {
+                    rejections.push(e.to_string());
+                }
+            },
+        )
+        .expect("Something failed");
+
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &OutputOptions::default()).expect("Something failed");
+        assert_eq!(String::from_utf8(result).unwrap(), expected);
+        assert_eq!(rejections.len(), if aliases.is_empty() { 2 } else { 0 });
+    }
+
+    #[test]
+    fn rejects_a_client_alias_that_is_not_a_pair_of_ids() {
+        let error = load_client_aliases("5,1\n6\n".as_bytes()).expect_err("aliases were accepted");
+        assert_eq!(
+            error.to_string(),
+            r#"Malformed client aliases - line 2: "6" is not an alias,client pair"#
+        );
+    }
+
+    #[test]
+    fn refuses_to_charge_back_a_resolved_dispute() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 2.0
+dispute, 1, 1,
+resolve, 1, 1,
+chargeback, 1, 1,";
+        let mut refusal = None;
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| {
+                if let Err(e) = event.result {
+                    refusal = Some(e.category());
+                }
+            },
+        )
+        .expect("Something failed");
+
+        assert_eq!(refusal, Some(ErrorCategory::PartnerError));
+        assert_eq!(written_balances(&store), (dec!(2.0), dec!(0), dec!(2.0)));
+        assert!(!store.accounts[&Client::from(1)].is_locked());
+        assert_eq!(
+            store.transactions[&TransactionId::from(1)].get_dispute_state(),
+            DisputeState::Resolved
+        );
+    }
+
+    #[test_case(false, None, "client,available,held,total,locked\n"; "header by default")]
+    #[test_case(true, None, ""; "nothing under the flag")]
+    #[test_case(true, Some(0), ""; "not even the metadata line")]
+    fn writes_nothing_for_an_all_rejected_feed_when_asked(
+        empty_when_no_accounts: bool,
+        generated_at: Option<u64>,
+        expected: &str,
+    ) {
+        let store = store_of(
+            "type, client, tx, amount
+withdrawal, 1, 1, 5.0
+dispute, 2, 7,
+deposit, 3, 2, -1.0",
+        );
+        let options = OutputOptions {
+            empty_when_no_accounts,
+            generated_at,
+            ..OutputOptions::default()
+        };
+
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &options).expect("Something failed");
+        assert_eq!(String::from_utf8(result).unwrap(), expected);
+    }
+
+    #[test_case(None, IngestRounding::Round, "1.00005", dec!(1.00005), false ; "output only rounding")]
+    #[test_case(Some(4), IngestRounding::Round, "1.00005", dec!(1.0000), true ; "ingest rounding")]
+    #[test_case(Some(4), IngestRounding::Round, "1.00007", dec!(1.0001), true ; "ingest rounding up")]
+    #[test_case(Some(4), IngestRounding::Truncate, "1.00007", dec!(1.0000), true ; "ingest truncation")]
+    fn reconciles_displayed_balances_when_rounding_at_ingest(
+        ingest_precision: Option<u32>,
+        ingest_rounding: IngestRounding,
+        amount: &str,
+        balance: Decimal,
+        reconciles: bool,
+    ) {
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, {amount}
+deposit, 2, 2, {amount}
+deposit, 3, 3, {amount}"
+        );
+        let options = Options {
+            ingest_precision,
+            ingest_rounding,
+            ..Options::default()
+        };
+        let store = process_transactions(CsvSource::new(input.as_bytes()), Store::new(), &options)
+            .expect("Something failed");
+
+        let mut output = Vec::new();
+        write_accounts(&store, &mut output, &OutputOptions::default()).expect("Something failed");
+        let displayed: Decimal = account_snapshots(&output)
+            .iter()
+            .map(|account| account.total)
+            .sum();
+        let grand_total: Decimal = store.accounts.values().map(Account::total).sum();
+
+        assert_eq!(store.accounts[&Client::from(1)].total(), balance);
+        assert_eq!(displayed == grand_total.round_dp(4), reconciles);
+    }
+
+    #[test_case(IngestRounding::Round, "100.004", Ok(dec!(100.00)) ; "rounded within the limit")]
+    #[test_case(IngestRounding::Round, "100.005", Ok(dec!(100.00)) ; "rounded to even within the limit")]
+    #[test_case(IngestRounding::Round, "100.006", Err("100.01") ; "rounded over the limit")]
+    #[test_case(IngestRounding::Truncate, "100.009", Ok(dec!(100.00)) ; "truncated within the limit")]
+    fn checks_the_amount_limit_after_rounding_at_ingest(
+        ingest_rounding: IngestRounding,
+        amount: &str,
+        expected: Result<Decimal, &str>,
+    ) {
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, {amount}"
+        );
+        let options = Options {
+            ingest_precision: Some(2),
+            ingest_rounding,
+            max_amount: Some(dec!(100)),
+            ..Options::default()
+        };
+        let mut rejections = Vec::new();
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &options,
+            |event| rejections.extend(event.result.as_ref().err().map(ToString::to_string)),
+        )
+        .expect("Something failed");
+
+        match expected {
+            Ok(total) => {
+                assert!(rejections.is_empty(), "{rejections:?}");
+                assert_eq!(store.accounts[&Client::from(1)].total(), total);
+            }
+            Err(rounded) => {
+                assert_eq!(rejections.len(), 1);
+                assert!(rejections[0].contains(rounded), "{rejections:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn replays_an_amount_equal_once_rounded_at_ingest() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.001
+deposit, 1, 1, 1.0";
+        let options = Options {
+            ingest_precision: Some(2),
+            idempotent: true,
+            ..Options::default()
+        };
+        let mut replayed = 0;
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &options,
+            |event| {
+                if let Err(TransactionError::Replayed(_)) = event.result {
+                    replayed += 1;
+                }
+            },
+        )
+        .expect("Something failed");
+
+        assert_eq!(replayed, 1);
+        assert_eq!(store.accounts[&Client::from(1)].total(), dec!(1.00));
+    }
+
+    #[test_case("dispute" ; "dispute")]
+    #[test_case("resolve" ; "resolve")]
+    #[test_case("chargeback" ; "chargeback")]
+    fn opens_no_account_for_a_dispute_of_an_unknown_transaction(action: &str) {
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, 1.0
+{action}, 2, 99,"
+        );
+        let options = Options {
+            max_clients: Some(1),
+            seed_balance: dec!(5),
+            ..Options::default()
+        };
+        let mut events = Vec::new();
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &options,
+            |event| {
+                if let Err(e) = event.result {
+                    let unknown = matches!(
+                        e,
+                        TransactionError::AccountError(
+                            AccountError::ErroneousDispute(_)
+                                | AccountError::ErroneousResolve(_)
+                                | AccountError::ErroneousChargeback(_)
+                        )
+                    );
+                    events.push((unknown, event.before, event.after));
+                }
+            },
+        )
+        .expect("Something failed");
+
+        // Past the client limit, it is still the unknown transaction that is reported
+        let (unknown, before, after) = events.pop().expect("reference was accepted");
+        assert!(unknown);
+        assert_eq!((before, after), (None, None));
+        assert_eq!(store.clients().collect::<Vec<_>>(), [Client::from(1)]);
+        assert!(!store.transactions.contains_key(&TransactionId::from(99)));
+    }
+
+    #[test_case("type, client, tx, amount\nnot, a, valid, row", None ; "valid header")]
+    #[test_case("type,client,tx,amount,memo\n", None ; "extra column")]
+    #[test_case("type, client, amount\ndeposit, 1, 1.0", Some("Header is missing the tx column(s)") ; "missing column")]
+    #[test_case("kind, customer, tx, amount\n", Some("Header is missing the type, client column(s)") ; "renamed columns")]
+    #[test_case("", Some("Header is missing the type, client, tx, amount column(s)") ; "empty feed")]
+    fn checks_only_the_header(input: &str, expected: Option<&str>) {
+        let result = CsvSource::new(input.as_bytes()).check_header();
+        assert_eq!(result.err().map(|e| e.to_string()).as_deref(), expected);
+    }
+
+    #[test]
+    fn renumbers_sparse_client_ids_densely() {
+        let input = "type, client, tx, amount
+deposit, 60000, 1, 3.0
+deposit, 40001, 2, 2.0
+deposit, 60000, 3, 1.0
+dispute, 60000, 1,
+withdrawal, 40001, 4, 0.5";
+        let store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+
+        let mut mapping = Vec::new();
+        let (store, _) = normalize_client_ids(store, &mut mapping).expect("Something failed");
+        let mut accounts = Vec::new();
+        write_accounts(&store, &mut accounts, &OutputOptions::default()).expect("Something failed");
+
+        assert_eq!(
+            String::from_utf8(mapping).unwrap(),
+            "old,new
+40001,1
+60000,2
+"
+        );
+        assert_eq!(
+            String::from_utf8(accounts).unwrap(),
+            "client,available,held,total,locked
+1,1.5,0.0000,1.5,false
+2,1.0,3.0,4.0,false
+"
+        );
+        let disputed = &store.transactions[&TransactionId::from(1)];
+        assert!(disputed.get_is_under_dispute());
+        assert_eq!(disputed.client_id(), Client::from(2));
+    }
+
+    #[test]
+    fn renumbers_clients_whose_every_row_was_refused() {
+        let input = "type, client, tx, amount
+withdrawal, 2, 1, 5.0
+deposit, 40001, 2, 2.0
+deposit, 60000, 3, 1.0";
+        let mut ledger_rows = Vec::new();
+        let mut ledger = Ledger::new(&mut ledger_rows, true).hold_rows();
+        let (store, _) = process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| ledger.record(event),
+        )
+        .expect("Something failed");
+
+        let mut mapping = Vec::new();
+        let (store, renumbered) =
+            normalize_client_ids(store, &mut mapping).expect("Something failed");
+        ledger.renumber(&renumbered);
+        ledger.finish().expect("Something failed");
+
+        assert_eq!(
+            String::from_utf8(mapping).unwrap(),
+            "old,new
+2,1
+40001,2
+60000,3
+"
+        );
+        let clients = store
+            .transactions
+            .values()
+            .map(Transaction::client_id)
+            .collect::<Vec<_>>();
+        assert_eq!(clients, [1, 2, 3].map(Client::from));
+        assert_eq!(
+            String::from_utf8(ledger_rows).unwrap(),
+            "client,tx,type,delta,available,held,total
+2,2,deposit,2.0,2.0,0,2.0
+3,3,deposit,1.0,1.0,0,1.0
+"
+        );
+    }
+
+    /// Hands over transactions no faster than one per `delay`
+    struct SlowSource<S> {
+        source: S,
+        delay: Duration,
+    }
+
+    impl<S: TransactionSource> TransactionSource for SlowSource<S> {
+        fn next(&mut self) -> Option<Result<Transaction, ParseError>> {
+            std::thread::sleep(self.delay);
+            self.source.next()
+        }
+    }
+
+    #[test]
+    fn stops_processing_once_the_timeout_has_passed() {
+        let input = (1..=20).fold("type, client, tx, amount".to_string(), |input, tx| {
+            input + &format!("\ndeposit, {tx}, {tx}, 1.0")
+        });
+        let source = SlowSource {
+            source: CsvSource::new(input.as_bytes()),
+            delay: Duration::from_millis(10),
+        };
+        let options = Options {
+            timeout: Some(Duration::from_millis(25)),
+            ..Options::default()
+        };
+
+        let (store, completion) = process_transactions_with(source, Store::new(), &options, |_| {})
+            .expect("Something failed");
+
+        assert_eq!(completion, Completion::TimedOut);
+        assert!(store.accounts.len() < 20);
+        let mut accounts = Vec::new();
+        write_accounts(&store, &mut accounts, &OutputOptions::default()).expect("Something failed");
+        assert_eq!(
+            String::from_utf8(accounts).unwrap().lines().count(),
+            store.accounts.len() + 1
+        );
+    }
+
+    #[test]
+    fn writes_each_account_to_its_own_file() {
+        let dir = std::env::temp_dir().join(format!("payment-engine-split-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 12, 2, 2.5
+withdrawal, 1, 3, 0.25";
+        let store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+
+        write_split_accounts(&store, &dir, &OutputOptions::default()).expect("Something failed");
+
+        let mut files = std::fs::read_dir(&dir)
+            .expect("Something failed")
+            .map(|entry| {
+                let path = entry.expect("Something failed").path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (
+                    name,
+                    std::fs::read_to_string(&path).expect("Something failed"),
+                )
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        std::fs::remove_dir_all(&dir).expect("Something failed");
+        assert_eq!(
+            files,
+            [
+                (
+                    "1.csv".to_string(),
+                    "client,available,held,total,locked\n1,0.75,0.0000,0.75,false\n".to_string()
+                ),
+                (
+                    "12.csv".to_string(),
+                    "client,available,held,total,locked\n12,2.5,0.0000,2.5,false\n".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn appends_a_second_run_without_repeating_the_header() {
+        let path =
+            std::env::temp_dir().join(format!("payment-engine-append-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        for input in [
+            "type, client, tx, amount
+deposit, 1, 1, 1.0",
+            "type, client, tx, amount
+deposit, 2, 1, 2.0",
+        ] {
+            let store = process_transactions(
+                CsvSource::new(input.as_bytes()),
+                Store::new(),
+                &Options::default(),
+            )
+            .expect("Something failed");
+            let (file, has_content) = open_append(&path).expect("Something failed");
+            let output_options = OutputOptions {
+                header: !has_content,
+                ..OutputOptions::default()
+            };
+            write_accounts(&store, file, &output_options).expect("Something failed");
+        }
+
+        let output = std::fs::read_to_string(&path).expect("Something failed");
+        std::fs::remove_file(&path).expect("Something failed");
+        assert_eq!(
+            output,
+            "client,available,held,total,locked
+1,1.0,0.0000,1.0,false
+2,2.0,0.0000,2.0,false
+"
+        );
+    }
+
+    #[test]
+    fn serves_snapshots_of_transactions_sent_over_tcp() {
+        use std::io::{BufRead, BufReader};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Something failed");
+        let addr = listener.local_addr().expect("Something failed");
+        std::thread::spawn(move || serve(listener, Store::new(), Options::default()));
+
+        let mut stream = TcpStream::connect(addr).expect("Something failed");
+        stream
+            .write_all(
+                b"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 0.5
+withdrawal, 2, 4, 5.0
+not a transaction
+SNAPSHOT
+",
+            )
+            .expect("Something failed");
+
+        let snapshot = BufReader::new(stream)
+            .lines()
+            .map(|line| line.expect("Something failed"))
+            .take_while(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            snapshot,
+            [
+                "client,available,held,total,locked",
+                "1,1.5,0.0000,1.5,false",
+                "2,2.0,0.0000,2.0,false",
+            ]
+        );
+    }
+
+    #[test]
+    fn answers_a_row_that_stops_processing_and_carries_on() {
+        use std::io::{BufRead, BufReader};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Something failed");
+        let addr = listener.local_addr().expect("Something failed");
+        std::thread::spawn(move || serve(listener, Store::new(), Options::default()));
+
+        // The repeated tx stops processing under the default duplicate policy, as in a file
+        let mut stream = TcpStream::connect(addr).expect("Something failed");
+        stream
+            .write_all(b"deposit, 1, 1, 1.0\ndeposit, 1, 1, 5.0\ndeposit, 1, 2, 2.0\nSNAPSHOT\n")
+            .expect("Something failed");
+        let reply = BufReader::new(stream)
+            .lines()
+            .map(|line| line.expect("Something failed"))
+            .take_while(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            reply,
+            [
+                "ERROR Duplicate transaction id 1",
+                "client,available,held,total,locked",
+                "1,3.0,0.0000,3.0,false"
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_only_the_selected_columns_in_order() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+dispute, 2, 2,
+chargeback, 2, 2,";
+        let store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+        let columns = Column::parse_list("locked, client,total").expect("Something failed");
+
+        let mut csv = Vec::new();
+        let output_options = OutputOptions {
+            columns: columns.clone(),
+            ..OutputOptions::default()
+        };
+        write_accounts(&store, &mut csv, &output_options).expect("Something failed");
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "locked,client,total
+false,1,1.0
+true,2,0.0000
+"
+        );
+
+        let mut jsonl = Vec::new();
+        let output_options = OutputOptions {
+            format: OutputFormat::JsonLines,
+            columns,
+            ..OutputOptions::default()
+        };
+        write_accounts(&store, &mut jsonl, &output_options).expect("Something failed");
+        assert_eq!(
+            String::from_utf8(jsonl).unwrap(),
+            r#"{"locked":false,"client":1,"total":1.0}
+{"locked":true,"client":2,"total":0.0000}
+"#
+        );
+    }
+
+    #[test_case("client,balance"; "unknown column")]
+    #[test_case("client,total,client"; "duplicate column")]
+    #[test_case(" , "; "no columns")]
+    fn rejects_an_invalid_column_list(list: &str) {
+        assert!(Column::parse_list(list).is_err());
+    }
+
+    #[test]
+    fn writes_each_clients_latest_memo() {
+        let input = r#"type, client, tx, amount, memo, channel
+deposit, 1, 1, 1.0, first deposit, web
+deposit, 2, 2, 2.0, salary, app
+withdrawal, 1, 3, 0.5,"rent, march", web
+deposit, 2, 4, 1.0, , app
+withdrawal, 2, 5, 9.0, too much, app
+dispute, 1, 1"#;
+        let store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+
+        let mut result = Vec::new();
+        let output_options = OutputOptions {
+            columns: Column::parse_list("client,total,memo").expect("Something failed"),
+            ..OutputOptions::default()
+        };
+        write_accounts(&store, &mut result, &output_options).expect("Something failed");
+
+        // Client 1's dispute carried no memo, client 2's last applied deposit had an empty one
+        // and its refused withdrawal doesn't count
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "client,total,memo
+1,0.5,
+2,3.0,
+"
+        );
+
+        let input = r#"type, client, tx, amount, memo, channel
+deposit, 1, 1, 1.0, first deposit, web
+withdrawal, 1, 3, 0.5,"rent, march", web
+deposit, 2, 2, 2.0, salary, app
+withdrawal, 2, 5, 9.0, too much, app"#;
+        let store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &output_options).expect("Something failed");
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "client,total,memo
+1,0.5,\"rent, march\"
+2,2.0,salary
+"
+        );
+    }
+
+    #[test]
+    fn processes_transactions_on_top_of_a_seed() {
+        let seed = "client,available,held,total,locked
+1,1.5,0.0000,1.5,false
+2,2.0,0.0000,2.0,true";
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 1.0";
+        let store = load_seed(seed.as_bytes()).expect("Something failed");
+        let store =
+            process_transactions(CsvSource::new(input.as_bytes()), store, &Options::default())
+                .expect("Something failed");
+
+        let mut result = Vec::new();
+        write_accounts(&store, &mut result, &OutputOptions::default()).expect("Something failed");
+        assert_eq!(
+            String::from_utf8(result).unwrap(),
+            "client,available,held,total,locked
+1,2.5,0.0000,2.5,false
+2,2.0,0.0000,2.0,true
+"
+        );
+    }
+
+    #[test]
+    fn rejects_a_seed_with_duplicate_accounts() {
+        let seed = "client,available,held,total,locked
+1,1.5,0.0000,1.5,false
+2,2.0,0.0000,2.0,false
+1,3.0,0.0000,3.0,false";
+
+        let error = load_seed(seed.as_bytes()).unwrap_err();
+        assert!(matches!(error, ProcessingError::DuplicateSeedAccount(_)));
+        assert_eq!(
+            error.to_string(),
+            "Seed contains more than one row for client 1"
+        );
+    }
+
+    #[test]
+    fn reports_how_long_held_funds_have_been_disputed() {
+        let input = "type, client, tx, amount, timestamp
+deposit, 1, 1, 1.0, 1000
+deposit, 1, 2, 2.0, 1000
+deposit, 2, 3, 3.0
+dispute, 1, 1, , 90000
+dispute, 1, 2, , 2000
+resolve, 1, 2, , 3000
+dispute, 2, 3,";
+        let store = process_transactions(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+        )
+        .expect("Something failed");
+
+        let mut report = Vec::new();
+        let now = 90000 + 3 * 24 * 60 * 60;
+        write_held_aging(&store, now, &mut report).expect("Something failed");
+
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client,tx,held,age_seconds,age_bucket
+1,1,1.0,259200,1d_to_7d
+2,3,3.0,,unknown
+"
+        );
+    }
+
+    #[test]
+    fn explains_a_deposit_that_was_disputed_and_charged_back() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 2.0
+resolve, 1, 1,
+dispute, 1, 1,
+withdrawal, 1, 3, 1.0
+chargeback, 1, 1,
+deposit, 1, 4, 1.0";
+        let mut explanation = Explanation::new(TransactionId::from(1));
+        process_transactions_with(
+            CsvSource::new(input.as_bytes()),
+            Store::new(),
+            &Options::default(),
+            |event| explanation.record(event),
+        )
+        .expect("Something failed");
+
+        let mut narrative = Vec::new();
+        explanation.write(&mut narrative).expect("Something failed");
+
+        assert_eq!(
+            String::from_utf8(narrative).unwrap(),
+            "row 1: deposit of 5.0 by client 1: applied
+  available 0.0000 -> 5.0, held 0.0000 -> 0.0000, total 0.0000 -> 5.0, locked false -> false
+row 3: resolve by client 1: rejected - Invalid transaction - Illegal dispute transition: cannot resolve transaction 1 while its dispute state is Active
+  available 7.0 -> 7.0, held 0.0000 -> 0.0000, total 7.0 -> 7.0, locked false -> false
+row 4: dispute by client 1: applied
+  available 7.0 -> 2.0, held 0.0000 -> 5.0, total 7.0 -> 7.0, locked false -> false
+row 6: chargeback by client 1: applied
+  available 1.0 -> 1.0, held 5.0 -> 0.0000, total 6.0 -> 1.0, locked false -> true
+"
+        );
+    }
+
+    /// A single client's transaction, with amounts in ten-thousandths and disputes referring to
+    /// the nth amount-bearing transaction
+    #[derive(Debug, Clone)]
+    enum Operation {
+        Deposit(u32),
+        Withdrawal(u32),
+        Dispute(u32),
+        Resolve(u32),
+    }
+
+    fn operation() -> impl Strategy<Value = Operation> {
+        prop_oneof![
+            (1..100_000u32).prop_map(Operation::Deposit),
+            (1..100_000u32).prop_map(Operation::Withdrawal),
+            (1..20u32).prop_map(Operation::Dispute),
+            (1..20u32).prop_map(Operation::Resolve),
+        ]
+    }
+
+    fn to_csv(operations: &[Operation]) -> String {
+        let mut csv = String::from("type, client, tx, amount\n");
+        let mut transaction_id = 0;
+        for operation in operations {
+            let row = match operation {
+                Operation::Deposit(amount) | Operation::Withdrawal(amount) => {
+                    transaction_id += 1;
+                    let kind = match operation {
+                        Operation::Deposit(_) => "deposit",
+                        _ => "withdrawal",
+                    };
+                    format!(
+                        "{kind}, 1, {transaction_id}, {}",
+                        Decimal::new(*amount as i64, 4)
+                    )
+                }
+                Operation::Dispute(tx) => format!("dispute, 1, {tx},"),
+                Operation::Resolve(tx) => format!("resolve, 1, {tx},"),
+            };
+            csv.push_str(&row);
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// The available, held and total balances of client 1 as written to the output
+    fn written_balances(store: &Store) -> (Decimal, Decimal, Decimal) {
+        let mut output = Vec::new();
+        write_accounts(store, &mut output, &OutputOptions::default()).unwrap();
+        match account_snapshots(&output).first() {
+            Some(account) => (account.available, account.held, account.total),
+            None => (dec!(0), dec!(0), dec!(0)),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn totals_are_conserved_across_non_chargeback_operations(
+            operations in prop::collection::vec(operation(), 1..50)
+        ) {
+            let csv = to_csv(&operations);
+            let mut source = CsvSource::new(csv.as_bytes());
+            let options = Options::default();
+            let mut store = Store::new();
+            let mut net_deposits = dec!(0);
+
+            for operation in &operations {
+                let transaction = source.next().unwrap().unwrap();
+                let applied = transaction.clone().save(&mut store, &options).is_ok();
+                // Disputing a withdrawal holds its reversal on top of the total until resolved
+                let reversal = |tx: &u32| {
+                    let disputed = &store.transactions[&TransactionId::from(u64::from(*tx))];
+                    match disputed.transaction_type() {
+                        TransactionType::Withdrawal => disputed.get_amount().unwrap(),
+                        _ => dec!(0),
+                    }
+                };
+                match (operation, applied) {
+                    (Operation::Deposit(_), true) => net_deposits += transaction.get_amount().unwrap(),
+                    (Operation::Withdrawal(_), true) => net_deposits -= transaction.get_amount().unwrap(),
+                    (Operation::Dispute(tx), true) => net_deposits += reversal(tx),
+                    (Operation::Resolve(tx), true) => net_deposits -= reversal(tx),
+                    _ => {}
+                }
+
+                let (available, held, total) = written_balances(&store);
+                prop_assert_eq!(available + held, total);
+                prop_assert_eq!(total, net_deposits);
+            }
+        }
+    }
+
+    fn assert_accounts(input_transaction: &str, options: &Options, output_account: &str) {
+        let mut result = Vec::new();
+
+        generate_accounts_from_transactions(input_transaction.as_bytes(), &mut result, options)
+            .expect("Something failed");
+        assert_eq!(result, output_account.as_bytes());
+    }
+
+    /// The accounts as written to the output. Amounts compare by value, so `1.0`, `1.0000` and
+    /// `1` are the same balance
+    fn account_snapshots(csv: &[u8]) -> Vec<AccountSnapshot> {
+        csv::Reader::from_reader(csv)
+            .records()
+            .map(|record| {
+                let record = record.expect("Something failed");
+                let amount = |i: usize| Decimal::from_str(&record[i]).expect("Something failed");
+                AccountSnapshot {
+                    client: Client::from(record[0].parse::<u16>().expect("Something failed")),
+                    available: amount(1),
+                    held: amount(2),
+                    total: amount(3),
+                    locked: record[4].parse().expect("Something failed"),
+                }
+            })
+            .collect()
+    }
+
+    /// Like `assert_accounts`, but independent of how the amounts are formatted
+    fn assert_account_values(input_transaction: &str, options: &Options, output_account: &str) {
+        let mut result = Vec::new();
+
+        generate_accounts_from_transactions(input_transaction.as_bytes(), &mut result, options)
+            .expect("Something failed");
+        assert_eq!(
+            account_snapshots(&result),
+            account_snapshots(output_account.as_bytes())
+        );
+    }
+}
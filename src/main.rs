@@ -1,203 +1,804 @@
 use std::{
-    env,
+    collections::{BTreeMap, BTreeSet},
     fs::File,
-    io::{self, BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::TcpListener,
+    num::NonZeroU32,
     process,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 #[macro_use]
 extern crate log;
 
-mod features;
-use csv::Writer;
-use features::{Store, Transaction};
+use clap::{Arg, ArgMatches, Command};
+use payment_engine::{
+    account_schema, canonicalize, check_round_trip, load_client_aliases, load_deny_list, load_seed,
+    open_append, read_byte_range, replay_ledger, serve, write_baseline_diff, write_held_aging,
+    write_locked_report, write_open_disputes, write_split_accounts, write_transaction_dump,
+    ByteRange, ClientTrace, Column, Completion, CsvSource, DuplicateTxPolicy, Engine, Event,
+    Explanation, IngestRounding, JsonLinesSource, Latin1Reader, Ledger, Options, OutputFormat,
+    OutputOptions, ProcessingError, RedisputePolicy, SortOrder, Store, TransactionType,
+};
+use rust_decimal::Decimal;
+
+/// Exit code for a run that hit `--timeout`. The accounts processed so far are still written.
+const TIMED_OUT_EXIT_CODE: i32 = 2;
 
 fn main() {
     env_logger::init();
 
-    let args = env::args().into_iter().collect::<Vec<_>>();
-    let transactions_file_name = &args[1];
+    let matches = cli().get_matches();
 
-    let f = File::open(transactions_file_name).expect("Unable to open file");
-    let reader = BufReader::new(f);
-
-    if generate_accounts_from_transactions(reader, io::stdout()).is_err() {
-        process::exit(1);
+    match run(&matches) {
+        Ok(Completion::Finished) => {}
+        Ok(Completion::TimedOut) => {
+            error!("Timed out before the whole feed was processed");
+            process::exit(TIMED_OUT_EXIT_CODE);
+        }
+        Err(e) => {
+            error!("{e}");
+            process::exit(1);
+        }
     }
 }
 
-fn generate_accounts_from_transactions(
-    reader: impl BufRead,
-    writer: impl Write,
-) -> anyhow::Result<()> {
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .delimiter(b',')
-        .trim(csv::Trim::All)
-        .flexible(true)
-        .from_reader(reader);
-
-    let mut store = Store::new();
-
-    for result in rdr.deserialize() {
-        let transaction: Transaction = result?;
-        if let Err(e) = transaction.save(&mut store) {
-            warn!("{e}");
+fn run(matches: &ArgMatches) -> anyhow::Result<Completion> {
+    let options = options_from_matches(matches)?;
+    let mut output_options = output_options_from_matches(matches);
+
+    let store = match (matches.value_of("seed"), matches.value_of("from-journal")) {
+        (Some(path), _) => load_seed(BufReader::new(File::open(path)?))?,
+        (_, Some(path)) => replay_ledger(BufReader::new(File::open(path)?))?,
+        (None, None) => Store::new(),
+    };
+
+    if matches.is_present("emit-schema") {
+        let schema = account_schema(&output_options.columns);
+        serde_json::to_writer_pretty(io::stdout(), &schema)?;
+        println!();
+        return Ok(Completion::Finished);
+    }
+
+    if let Some(addr) = matches.value_of("listen") {
+        serve(TcpListener::bind(addr)?, store, options)?;
+        return Ok(Completion::Finished);
+    }
+
+    // Only a run rebuilding from a journal may have no feed
+    let reader: Box<dyn BufRead> = match matches.value_of("transactions") {
+        Some(transactions_file_name) => {
+            let f = File::open(transactions_file_name).expect("Unable to open file");
+            let f: Box<dyn BufRead> = match matches.value_of_t::<ByteRange>("byte-range") {
+                Ok(range) => {
+                    let csv = matches.value_of("input-format") != Some("jsonl");
+                    Box::new(read_byte_range(BufReader::new(f), range, csv)?)
+                }
+                Err(_) => Box::new(BufReader::new(f)),
+            };
+            match matches.value_of("input-encoding") {
+                Some("latin1") => Box::new(Latin1Reader::new(f)),
+                _ => f,
+            }
+        }
+        None => Box::new(io::empty()),
+    };
+
+    // Checked before the output is opened, which would truncate it
+    if matches.is_present("validate-schema-only") {
+        CsvSource::new(reader).check_header()?;
+        return Ok(Completion::Finished);
+    }
+
+    let mut writer: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) if matches.is_present("append") => {
+            let (file, has_content) = open_append(path)?;
+            output_options.header &= !has_content;
+            Box::new(BufWriter::new(file))
+        }
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout()),
+    };
+
+    let amount_fractions = matches.is_present("amount-fractions");
+    let strip_symbols = matches.value_of("strip-symbols").unwrap_or_default();
+    let thousands_separators = matches.is_present("thousands-separators");
+    let strip_underscores = matches.is_present("strip-underscores");
+    let allocation_light = matches.is_present("allocation-light");
+    if matches.is_present("canonicalize") {
+        match matches.value_of("input-format") {
+            Some("jsonl") => canonicalize(
+                JsonLinesSource::new(reader)
+                    .amount_fractions(amount_fractions)
+                    .strip_symbols(strip_symbols)
+                    .thousands_separators(thousands_separators)
+                    .strip_underscores(strip_underscores),
+                writer,
+            )?,
+            _ => canonicalize(
+                CsvSource::new(reader)
+                    .amount_fractions(amount_fractions)
+                    .strip_symbols(strip_symbols)
+                    .thousands_separators(thousands_separators)
+                    .strip_underscores(strip_underscores)
+                    .allocation_light(allocation_light),
+                writer,
+            )?,
         }
+        return Ok(Completion::Finished);
     }
 
-    let mut wtr = Writer::from_writer(writer);
+    let mut explanation = matches
+        .value_of_t::<u64>("explain")
+        .ok()
+        .map(|tx| Explanation::new(tx.into()));
+    let mut ledger = None;
+    let mut trace = None;
+    if matches.is_present("ledger") {
+        // Renumbering only happens once the run is over, so the rows wait for it
+        ledger = Some(if matches.is_present("normalize-client-ids") {
+            Ledger::new(&mut writer, output_options.header).hold_rows()
+        } else {
+            Ledger::new(&mut writer, output_options.header)
+        });
+    } else if let Ok(client) = matches.value_of_t::<u16>("trace-client") {
+        trace = Some(ClientTrace::new(
+            client.into(),
+            output_options.balance_precision,
+            &mut writer,
+        ));
+    }
+    let observe = |event: &Event| {
+        if let Some(explanation) = explanation.as_mut() {
+            explanation.record(event);
+        }
+        if let Some(ledger) = ledger.as_mut() {
+            ledger.record(event);
+        }
+        if let Some(trace) = trace.as_mut() {
+            trace.record(event);
+        }
+    };
+
+    let mut engine = Engine::with_store(options, store);
+    let completion = match matches.value_of("input-format") {
+        Some("jsonl") => {
+            let source = JsonLinesSource::new(reader)
+                .amount_fractions(amount_fractions)
+                .strip_symbols(strip_symbols)
+                .thousands_separators(thousands_separators)
+                .strip_underscores(strip_underscores);
+            engine.process(source, observe)?
+        }
+        _ => {
+            let source = CsvSource::new(reader)
+                .amount_fractions(amount_fractions)
+                .strip_symbols(strip_symbols)
+                .thousands_separators(thousands_separators)
+                .strip_underscores(strip_underscores)
+                .allocation_light(allocation_light);
+            engine.process(source, observe)?
+        }
+    };
+
+    if let Some(path) = matches.value_of("normalize-client-ids") {
+        let mapping = engine.normalize_client_ids(File::create(path)?)?;
+        if let Some(ledger) = ledger.as_mut() {
+            ledger.renumber(&mapping);
+        }
+    }
+
+    let write_started = Instant::now();
+    let wrote_ledger = ledger.map(Ledger::finish).transpose()?.is_some();
+    let wrote_trace = trace.map(ClientTrace::finish).transpose()?.is_some();
+    match explanation {
+        _ if wrote_ledger || wrote_trace => {}
+        Some(explanation) => explanation.write(writer)?,
+        None => match matches.value_of("split-output") {
+            Some(dir) => write_split_accounts(engine.store(), dir, &output_options)?,
+            None => engine.write_accounts(writer, &output_options)?,
+        },
+    }
+
+    if matches.is_present("timing") {
+        let mut timing = engine.metrics().timing;
+        timing.write = write_started.elapsed();
+        timing.write_report(io::stderr())?;
+    }
 
-    for account in store.accounts.values() {
-        wtr.serialize(account)?;
+    if let Some(path) = matches.value_of("metrics-prometheus") {
+        engine
+            .metrics()
+            .write_prometheus(engine.store(), File::create(path)?)?;
     }
-    wtr.flush()?;
 
-    Ok(())
+    if let Some(path) = matches.value_of("report-open-disputes") {
+        write_open_disputes(engine.store(), File::create(path)?)?;
+    }
+
+    if let Some(path) = matches.value_of("report-locked") {
+        write_locked_report(engine.store(), File::create(path)?)?;
+    }
+
+    if let Some(path) = matches.value_of("dump-transactions") {
+        write_transaction_dump(engine.store(), File::create(path)?)?;
+    }
+
+    if let Some(path) = matches.value_of("held-aging") {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        write_held_aging(engine.store(), now, File::create(path)?)?;
+    }
+
+    if matches.is_present("assert-balanced") {
+        engine.store().check_balanced()?;
+    }
+
+    if let Some(path) = matches.value_of("compare") {
+        let baseline = load_seed(BufReader::new(File::open(path)?))?;
+        match write_baseline_diff(engine.store(), &baseline, io::stderr(), &output_options)? {
+            0 => {}
+            differing => return Err(ProcessingError::BaselineMismatch(differing).into()),
+        }
+    }
+
+    if matches.is_present("round-trip-check") {
+        check_round_trip(engine.store(), &output_options)?;
+    }
+
+    if matches.is_present("control-total") {
+        engine.metrics().check_control_total(
+            matches.value_of_t("control-total")?,
+            matches.value_of_t("control-total-epsilon")?,
+        )?;
+    }
+
+    Ok(completion)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use test_case::test_case;
-
-    #[test_case(
-"type, client, tx, amount 
-deposit, 1, 1, 1.0 
-withdrawal, 1, 4, 1.5",
-
-"client,available,held,total,locked
-1,1,0.0000,1,false
-";
-
-"cannot withdraw more than available"
-)]
-    #[test_case(
-"type, client, tx, amount
-deposit, 1, 1, 1.0 
-deposit, 2, 2, 2.0
-deposit, 1, 3, 2.0 
-withdrawal, 1, 4, 1.5 
-withdrawal, 2, 5, 3.0",
-
-"client,available,held,total,locked
-1,1.5,0.0000,1.5,false
-2,2,0.0000,2,false
-";
-
-"handles deposits and withdrawals properly"
-)]
-    #[test_case(
-"type, client, tx, amount
-deposit, 1, 1, 1.56787645323 
-deposit, 2, 2, 2.2345652
-deposit, 1, 3, 2.34354 
-withdrawal, 1, 4, 1.522454 
-withdrawal, 2, 5, 3.0014355",
-
-"client,available,held,total,locked
-1,2.3890,0.0000,2.3890,false
-2,2.2346,0.0000,2.2346,false
-";
-
-"handles at least 4 decimal places properly"
-)]
-    #[test_case(
-"type, client, tx, amount 
-deposit, 1, 1, 1.0
-deposit, 2, 2, 2.0 
-dispute, 1, 1,
-deposit, 1, 1, 1.5 
-withdrawal, 2, 5, 3.0", 
-
-"client,available,held,total,locked
-1,1.5,1,2.5,false
-2,2,0.0000,2,false
-";
-
-"handles client 1 dispute properly"
-)]
-    #[test_case(
-"type, client, tx, amount 
-deposit, 1, 1, 1.0
-deposit, 2, 2, 2.0 
-dispute, 1, 1, 2.0
-resolve, 1, 1,
-withdrawal, 2, 5, 3.0", 
-
-"client,available,held,total,locked
-1,1,0.0000,1,false
-2,2,0.0000,2,false
-";
-
-"can successfully resolve dispute"
-)]
-    #[test_case(
-"type, client, tx, amount 
-deposit, 1, 1, 1.0
-deposit, 2, 2, 2.0 
-dispute, 1, 1,
-chargeback, 1, 1,
-withdrawal, 2, 5, 3.0", 
-
-"client,available,held,total,locked
-1,0.0000,0.0000,0.0000,true
-2,2,0.0000,2,false
-";
-
-"locks account 1 when client 1 charges back"
-)]
-    #[test_case(
-"type, client, tx, amount 
-deposit, 1, 1, 1.0
-deposit, 2, 2, 2.0 
-dispute, 1, 1,
-chargeback, 1, 1,
-withdrawal, 2, 5, 3.0
-dispute, 2, 5,
-chargeback, 2, 5,", 
-
-// I'm assuming there can be a negative balance in case of chargeback.
-// Should this be prevented instead?
-"client,available,held,total,locked
-1,0.0000,0.0000,0.0000,true
-2,-1,0.0000,-1,true
-";
-
-"locks accounts 1 and 2 when clients 1 and 2 initiate chargebacks"
-)]
-    #[test_case(
-"type, client, tx, amount 
-deposit, 1, 1, 1.0
-deposit, 2, 2, 2.0 
-dispute, 1, 1
-chargeback, 1, 1,
-deposit, 1, 2, 5.6587878
-deposit, 1, 3, 11.05
-withdrawal, 1, 5, 3.0", 
-
-"client,available,held,total,locked
-1,0.0000,0.0000,0.0000,true
-2,2,0.0000,2,false
-";
-
-"cannot carry out a transaction after account is locked"
-)]
-    #[test_case(
-"type, client, tx, amount 
-deposit, 1, 1, 1.0
-deposit, 1, 2, -0.0001", 
-
-"client,available,held,total,locked
-1,1,0.0000,1,false
-";
-
-"does not accept negative amount"
-)]
-    fn transactions_to_accounts(input_transaction: &str, output_account: &str) {
-        let mut result = Vec::new();
-
-        generate_accounts_from_transactions(input_transaction.as_bytes(), &mut result)
-            .expect("Something failed");
-        assert_eq!(result, output_account.as_bytes());
+fn cli() -> Command<'static> {
+    Command::new("payment-engine")
+        .about("Toy payment engine")
+        .arg(
+            Arg::new("transactions")
+                .required_unless_present_any(["listen", "emit-schema", "from-journal"])
+                .help("File of transactions to process"),
+        )
+        .arg(
+            Arg::new("emit-schema")
+                .long("emit-schema")
+                .help("Print a JSON Schema of the JSON Lines account output for the selected columns and exit"),
+        )
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .takes_value(true)
+                .value_name("ADDR")
+                .help("Instead of a file, take transactions over TCP on ADDR, e.g. 127.0.0.1:7878"),
+        )
+        .arg(
+            Arg::new("validate-schema-only")
+                .long("validate-schema-only")
+                .conflicts_with_all(&["input-format", "output", "append"])
+                .help("Only check the CSV header names the type, client, tx and amount columns, exiting non-zero if not"),
+        )
+        .arg(
+            Arg::new("canonicalize")
+                .long("canonicalize")
+                .help("Instead of the accounts, print the transactions in a normalized CSV form without applying them"),
+        )
+        .arg(
+            Arg::new("input-format")
+                .long("input-format")
+                .takes_value(true)
+                .possible_values(["csv", "jsonl"])
+                .default_value("csv")
+                .help("Format of the transactions file"),
+        )
+        .arg(
+            Arg::new("input-encoding")
+                .long("input-encoding")
+                .takes_value(true)
+                .possible_values(["utf8", "latin1"])
+                .default_value("utf8")
+                .help("Character encoding of the transactions file"),
+        )
+        .arg(
+            Arg::new("byte-range")
+                .long("byte-range")
+                .takes_value(true)
+                .value_name("START:END")
+                .validator(|range| range.parse::<ByteRange>())
+                .help("Process only the records starting between byte offsets START and END of the file, so several runs can split one file"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(["csv", "jsonl"])
+                .default_value("csv")
+                .help("Format the accounts are written in"),
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write the accounts to PATH instead of stdout"),
+        )
+        .arg(
+            Arg::new("split-output")
+                .long("split-output")
+                .takes_value(true)
+                .value_name("DIR")
+                .conflicts_with_all(&["output", "explain", "ledger"])
+                .help("Write each account to its own file in DIR, named by client id"),
+        )
+        .arg(
+            Arg::new("append")
+                .long("append")
+                .requires("output")
+                .help("Add the accounts to the end of the output file, with no repeated header"),
+        )
+        .arg(
+            Arg::new("zero-held-when-locked")
+                .long("zero-held-when-locked")
+                .help("Write held as zero for locked accounts, whose funds are settled"),
+        )
+        .arg(
+            Arg::new("with-metadata")
+                .long("with-metadata")
+                .help("Start CSV output with a '# generated_at=<rfc3339>, rows=<n>' comment line"),
+        )
+        .arg(
+            Arg::new("emit-empty-when-no-accounts")
+                .long("emit-empty-when-no-accounts")
+                .help("Write nothing, not even the header, when no account has been opened"),
+        )
+        .arg(
+            Arg::new("output-header")
+                .long("output-header")
+                .takes_value(true)
+                .possible_values(["on", "off"])
+                .default_value("on")
+                .help("Whether CSV output starts with a header naming the columns"),
+        )
+        .arg(
+            Arg::new("sort-by")
+                .long("sort-by")
+                .takes_value(true)
+                .possible_values(["client", "held-desc"])
+                .default_value("client")
+                .help("Order of the accounts: by client id, or most funds held first"),
+        )
+        .arg(
+            Arg::new("columns")
+                .long("columns")
+                .takes_value(true)
+                .value_name("LIST")
+                .validator(Column::parse_list)
+                .help(
+                    "Comma separated account columns to write, in order, e.g. client,total,locked",
+                ),
+        )
+        .arg(
+            Arg::new("precision")
+                .long("precision")
+                .takes_value(true)
+                .value_name("PLACES")
+                .validator(precision)
+                .help("Decimal places available and total are rounded to [default: 4]"),
+        )
+        .arg(
+            Arg::new("round-at-ingest")
+                .long("round-at-ingest")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(["round", "truncate"])
+                .conflicts_with("working-scale")
+                .help("Round or truncate every amount to the output --precision as it is read, so displayed balances reconcile exactly"),
+        )
+        .arg(
+            Arg::new("working-scale")
+                .long("working-scale")
+                .takes_value(true)
+                .value_name("PLACES")
+                .validator(precision)
+                .help("Round available and held to PLACES after every transaction, not only in the output"),
+        )
+        .arg(
+            Arg::new("held-precision")
+                .long("held-precision")
+                .takes_value(true)
+                .value_name("PLACES")
+                .validator(precision)
+                .help("Decimal places held is rounded to, 28 for full precision [default: 4]"),
+        )
+        .arg(
+            Arg::new("write-buffer")
+                .long("write-buffer")
+                .takes_value(true)
+                .value_name("BYTES")
+                .validator(|bytes| match bytes.parse::<usize>() {
+                    Ok(0) | Err(_) => Err("expected a positive number of bytes"),
+                    Ok(_) => Ok(()),
+                })
+                .help("Bytes of output gathered before each write [default: 65536]"),
+        )
+        .arg(
+            Arg::new("with-memo")
+                .long("with-memo")
+                .help("Add a memo column with each client's latest transaction memo"),
+        )
+        .arg(
+            Arg::new("from-journal")
+                .long("from-journal")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with("seed")
+                .help("Start from the state rebuilt from PATH, a ledger written by --ledger"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Start from the account states in PATH, as written by a previous run"),
+        )
+        .arg(
+            Arg::new("negative-withdrawal-as-deposit")
+                .long("negative-withdrawal-as-deposit")
+                .help("Treat a withdrawal with a negative amount as a deposit"),
+        )
+        .arg(
+            Arg::new("duplicate-tx-policy")
+                .long("duplicate-tx-policy")
+                .takes_value(true)
+                .possible_values(["first", "last", "reject"])
+                .default_value("reject")
+                .help("Whether a repeated deposit or withdrawal id is ignored, corrects the first, or stops processing"),
+        )
+        .arg(
+            Arg::new("idempotent")
+                .long("idempotent")
+                .help("Ignore a transaction delivered again instead of applying it twice"),
+        )
+        .arg(
+            Arg::new("close-zero-accounts")
+                .long("close-zero-accounts")
+                .help("Close an unlocked account once a withdrawal drains it to zero, rejecting later transactions"),
+        )
+        .arg(
+            Arg::new("fifo-disputes")
+                .long("fifo-disputes")
+                .help("Apply a resolve or charge back without a tx to the client's oldest open dispute"),
+        )
+        .arg(
+            Arg::new("require-monotonic-tx")
+                .long("require-monotonic-tx")
+                .help("Stop processing at a deposit, withdrawal or fee id not greater than every one before it"),
+        )
+        .arg(
+            Arg::new("redispute-after-resolve")
+                .long("redispute-after-resolve")
+                .takes_value(true)
+                .possible_values(["allow", "reject"])
+                .default_value("allow")
+                .help("Whether a transaction can be disputed again once its dispute was resolved"),
+        )
+        .arg(
+            Arg::new("amount-fractions")
+                .long("amount-fractions")
+                .help("Also read amounts written as fractions, e.g. 3/4. Fractions without an exact decimal value are malformed"),
+        )
+        .arg(
+            Arg::new("thousands-separators")
+                .long("thousands-separators")
+                .help("Also read amounts grouped with commas, e.g. \"1,234.56\", quoted in CSV"),
+        )
+        .arg(
+            Arg::new("strip-underscores")
+                .long("strip-underscores")
+                .help("Also read amounts grouping digits with underscores, e.g. 1_000.50"),
+        )
+        .arg(
+            Arg::new("allocation-light")
+                .long("allocation-light")
+                .help("Read CSV rows into one reused buffer instead of deserializing each, for memory-constrained ingestion"),
+        )
+        .arg(
+            Arg::new("strip-symbols")
+                .long("strip-symbols")
+                .takes_value(true)
+                .value_name("SYMBOLS")
+                .help("Drop any of SYMBOLS, e.g. '$€', from the start of amounts before reading them"),
+        )
+        .arg(
+            Arg::new("deny-list")
+                .long("deny-list")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Skip every transaction of the client ids listed one per line in PATH"),
+        )
+        .arg(
+            Arg::new("client-alias")
+                .long("client-alias")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Treat the client ids paired as alias,client one per line in PATH as one client when disputing"),
+        )
+        .arg(
+            Arg::new("timing")
+                .long("timing")
+                .help("Report the time spent parsing, applying and writing to stderr"),
+        )
+        .arg(
+            Arg::new("promote-errors")
+                .long("promote-errors")
+                .takes_value(true)
+                .value_name("TYPES")
+                .validator(|list| {
+                    match list.split(',').find(|name| TransactionType::from_name(name).is_none()) {
+                        Some(name) => Err(format!("unknown transaction type {name:?}")),
+                        None => Ok(()),
+                    }
+                })
+                .help("Stop processing at a partner error on any of TYPES, e.g. dispute,chargeback"),
+        )
+        .arg(
+            Arg::new("dedupe-locked-warnings")
+                .long("dedupe-locked-warnings")
+                .help("Log only the first rejection for each locked account, counting the rest"),
+        )
+        .arg(
+            Arg::new("seed-balance")
+                .long("seed-balance")
+                .takes_value(true)
+                .value_name("AMOUNT")
+                .conflicts_with_all(&["assert-balanced", "ledger"])
+                .validator(|amount| match Decimal::from_str(amount) {
+                    Ok(amount) if amount.is_sign_negative() => Err("expected a non-negative amount"),
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("expected an amount"),
+                })
+                .help("Start every new account with AMOUNT available, for testing only"),
+        )
+        .arg(
+            Arg::new("max-amount")
+                .long("max-amount")
+                .takes_value(true)
+                .value_name("AMOUNT")
+                .validator(Decimal::from_str)
+                .help("Reject deposits and withdrawals of more than AMOUNT"),
+        )
+        .arg(
+            Arg::new("max-clients")
+                .long("max-clients")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|n| n.parse::<usize>())
+                .help("Reject transactions for new clients once N accounts exist"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .value_name("SECS")
+                .validator(|secs| secs.parse::<u64>())
+                .help(
+                    "Stop processing after SECS seconds, write what was processed and exit with 2",
+                ),
+        )
+        .arg(
+            Arg::new("replay-speed")
+                .long("replay-speed")
+                .takes_value(true)
+                .value_name("ROWS_PER_SEC")
+                .validator(|rate| rate.parse::<NonZeroU32>())
+                .help("Apply at most ROWS_PER_SEC rows a second, to replay a feed at a controlled rate"),
+        )
+        .arg(
+            Arg::new("metrics-prometheus")
+                .long("metrics-prometheus")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write processing metrics to PATH in the Prometheus text format"),
+        )
+        .arg(
+            Arg::new("assert-balanced")
+                .long("assert-balanced")
+                .conflicts_with_all(&["seed", "working-scale"])
+                .help("Fail the run if the accounts don't add up to the transactions applied"),
+        )
+        .arg(
+            Arg::new("compare")
+                .long("compare")
+                .takes_value(true)
+                .value_name("BASELINE")
+                .help("Fail the run if the accounts differ from the BASELINE accounts CSV, writing the differences to stderr"),
+        )
+        .arg(
+            Arg::new("round-trip-check")
+                .long("round-trip-check")
+                .help("Fail the run if the accounts written don't read back as the same values"),
+        )
+        .arg(
+            Arg::new("control-total")
+                .long("control-total")
+                .takes_value(true)
+                .value_name("AMOUNT")
+                .validator(Decimal::from_str)
+                .help("Fail the run if deposits minus withdrawals minus charge backs differ from AMOUNT"),
+        )
+        .arg(
+            Arg::new("control-total-epsilon")
+                .long("control-total-epsilon")
+                .takes_value(true)
+                .value_name("AMOUNT")
+                .default_value("0")
+                .validator(Decimal::from_str)
+                .help("How far the net deposits may differ from --control-total"),
+        )
+        .arg(
+            Arg::new("ledger")
+                .long("ledger")
+                .conflicts_with("explain")
+                .help("Write a running ledger of every applied transaction instead of the accounts"),
+        )
+        .arg(
+            Arg::new("trace-client")
+                .long("trace-client")
+                .takes_value(true)
+                .value_name("CLIENT")
+                .validator(|client| client.parse::<u16>())
+                .conflicts_with_all(&["explain", "ledger"])
+                .help("Instead of the accounts, print every row for CLIENT and the balances after it as JSON Lines"),
+        )
+        .arg(
+            Arg::new("report-open-disputes")
+                .long("report-open-disputes")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write every account still holding funds and its unresolved disputes to PATH"),
+        )
+        .arg(
+            Arg::new("report-locked")
+                .long("report-locked")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write every locked account and the charge back that locked it to PATH"),
+        )
+        .arg(
+            Arg::new("dump-transactions")
+                .long("dump-transactions")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write every transaction kept for disputes, with its dispute state, to PATH for debugging"),
+        )
+        .arg(
+            Arg::new("held-aging")
+                .long("held-aging")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write how long each currently held amount has been disputed to PATH"),
+        )
+        .arg(
+            Arg::new("normalize-client-ids")
+                .long("normalize-client-ids")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Renumber clients densely from 1 and write the old,new id mapping to PATH"),
+        )
+        .arg(
+            Arg::new("explain")
+                .long("explain")
+                .takes_value(true)
+                .value_name("TX")
+                .validator(|tx| tx.parse::<u64>())
+                .help("Instead of the accounts, print what happened to transaction TX and why"),
+        )
+}
+
+fn precision(places: &str) -> Result<(), String> {
+    match places.parse::<u32>() {
+        Ok(places) if places <= OutputOptions::MAX_PRECISION => Ok(()),
+        _ => Err(format!(
+            "expected a number of decimal places up to {}",
+            OutputOptions::MAX_PRECISION
+        )),
+    }
+}
+
+fn options_from_matches(matches: &ArgMatches) -> anyhow::Result<Options> {
+    Ok(Options {
+        negative_withdrawal_as_deposit: matches.is_present("negative-withdrawal-as-deposit"),
+        max_clients: matches.value_of_t("max-clients").ok(),
+        seed_balance: matches.value_of_t("seed-balance").unwrap_or_default(),
+        max_amount: matches.value_of_t("max-amount").ok(),
+        timeout: matches.value_of_t("timeout").ok().map(Duration::from_secs),
+        replay_speed: matches.value_of_t("replay-speed").ok(),
+        duplicate_tx_policy: match matches.value_of("duplicate-tx-policy") {
+            Some("first") => DuplicateTxPolicy::First,
+            Some("last") => DuplicateTxPolicy::Last,
+            _ => DuplicateTxPolicy::Reject,
+        },
+        redispute_policy: match matches.value_of("redispute-after-resolve") {
+            Some("reject") => RedisputePolicy::Reject,
+            _ => RedisputePolicy::Allow,
+        },
+        deny_list: match matches.value_of("deny-list") {
+            Some(path) => load_deny_list(BufReader::new(File::open(path)?))?,
+            None => BTreeSet::new(),
+        },
+        client_aliases: match matches.value_of("client-alias") {
+            Some(path) => load_client_aliases(BufReader::new(File::open(path)?))?,
+            None => BTreeMap::new(),
+        },
+        promoted_errors: matches
+            .value_of("promote-errors")
+            .map(|list| {
+                list.split(',')
+                    .filter_map(TransactionType::from_name)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        dedupe_locked_warnings: matches.is_present("dedupe-locked-warnings"),
+        require_monotonic_tx: matches.is_present("require-monotonic-tx"),
+        fifo_disputes: matches.is_present("fifo-disputes"),
+        idempotent: matches.is_present("idempotent"),
+        close_zero_accounts: matches.is_present("close-zero-accounts"),
+        working_scale: matches.value_of_t("working-scale").ok(),
+        fee_precision: matches.value_of_t("precision").ok(),
+        ingest_precision: matches.is_present("round-at-ingest").then(|| {
+            matches
+                .value_of_t("precision")
+                .unwrap_or(OutputOptions::default().balance_precision)
+        }),
+        ingest_rounding: match matches.value_of("round-at-ingest") {
+            Some("truncate") => IngestRounding::Truncate,
+            _ => IngestRounding::Round,
+        },
+    })
+}
+
+fn output_options_from_matches(matches: &ArgMatches) -> OutputOptions {
+    let format = match matches.value_of("format") {
+        Some("jsonl") => OutputFormat::JsonLines,
+        _ => OutputFormat::Csv,
+    };
+
+    let mut columns = match matches.value_of("columns") {
+        Some(list) => Column::parse_list(list).expect("columns are validated by clap"),
+        None => Column::DEFAULT.to_vec(),
+    };
+    if matches.is_present("with-memo") && !columns.contains(&Column::Memo) {
+        columns.push(Column::Memo);
+    }
+
+    let defaults = OutputOptions::default();
+    OutputOptions {
+        format,
+        columns,
+        balance_precision: matches
+            .value_of_t("precision")
+            .unwrap_or(defaults.balance_precision),
+        held_precision: matches
+            .value_of_t("held-precision")
+            .unwrap_or(defaults.held_precision),
+        write_buffer: matches
+            .value_of_t("write-buffer")
+            .unwrap_or(defaults.write_buffer),
+        header: matches.value_of("output-header") != Some("off"),
+        zero_held_when_locked: matches.is_present("zero-held-when-locked"),
+        empty_when_no_accounts: matches.is_present("emit-empty-when-no-accounts"),
+        generated_at: matches.is_present("with-metadata").then(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        }),
+        sort: match matches.value_of("sort-by") {
+            Some("held-desc") => SortOrder::HeldDesc,
+            _ => SortOrder::Client,
+        },
     }
 }